@@ -0,0 +1,7 @@
+ALTER TABLE trades
+  ADD COLUMN "buyer_fee" int NOT NULL DEFAULT 0,
+  ADD COLUMN "seller_fee" int NOT NULL DEFAULT 0;
+
+CREATE INDEX trades_buyorder_id_idx ON trades (buyorder_id);
+CREATE INDEX trades_sellorder_id_idx ON trades (sellorder_id);
+CREATE INDEX orders_trader_id_idx ON orders (trader_id);
@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN "client_order_id" text;
+ALTER TABLE orders ADD CONSTRAINT orders_trader_id_client_order_id_key UNIQUE (trader_id, client_order_id);
@@ -0,0 +1,17 @@
+ALTER TABLE orders
+  ADD COLUMN "quantity" int NOT NULL DEFAULT 1,
+  ADD COLUMN "filled_quantity" int NOT NULL DEFAULT 0;
+
+ALTER TABLE trades ADD COLUMN "quantity" int NOT NULL DEFAULT 1;
+
+CREATE TABLE order_events (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "order_id" bigint NOT NULL REFERENCES orders(id),
+  "kind" smallint NOT NULL,
+  "price" int NOT NULL,
+  "quantity" int NOT NULL,
+  "previous_price" int,
+  "previous_quantity" int,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX order_events_order_id_idx ON order_events (order_id);
@@ -0,0 +1,8 @@
+-- Per-trader overrides of the configured rate limits, NULL keeps the default
+CREATE TABLE trader_rate_limits (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "requests_per_second" int,
+  "request_burst" int,
+  "orders_per_second" int,
+  "order_burst" int
+);
@@ -0,0 +1,20 @@
+-- Sequenced inputs of the matching engine, replaying the entries of a card rebuilds its book
+CREATE TABLE engine_journal (
+  "sequence" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "card_id" int NOT NULL,
+  "kind" smallint NOT NULL,
+  "order_id" bigint NOT NULL REFERENCES orders(id),
+  "side" smallint NOT NULL,
+  "price" int NOT NULL,
+  "quantity" int NOT NULL,
+  "previous_price" int,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX engine_journal_card_id_idx ON engine_journal (card_id, sequence);
+
+-- The journal entry whose matching produced the trade, NULL for trades from before the journal
+ALTER TABLE trades ADD COLUMN "journal_sequence" bigint;
+
+-- Books from before the journal, in order of entry
+INSERT INTO engine_journal (card_id, kind, order_id, side, price, quantity)
+SELECT card_id, 0, id, side, price, quantity - filled_quantity FROM orders WHERE status = 0 ORDER BY id;
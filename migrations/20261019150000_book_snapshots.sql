@@ -0,0 +1,7 @@
+-- Latest snapshot of each card's book, the journal after the sequence is replayed on top of it
+CREATE TABLE book_snapshots (
+  "card_id" int PRIMARY KEY,
+  "sequence" bigint NOT NULL,
+  "orders" jsonb NOT NULL,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
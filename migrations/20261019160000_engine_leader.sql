@@ -0,0 +1,7 @@
+-- Fencing token of the matching engine, advanced by every newly elected leader
+CREATE TABLE engine_leader (
+  "id" int PRIMARY KEY CHECK (id = 1),
+  "epoch" bigint NOT NULL,
+  "elected_at" timestamp WITH time zone
+);
+INSERT INTO engine_leader (id, epoch) VALUES (1, 0);
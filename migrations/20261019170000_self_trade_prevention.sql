@@ -0,0 +1,12 @@
+-- 0: cancel newest, 1: cancel oldest, 2: cancel both, 3: decrement, 4: disabled
+-- Existing orders keep matching as before, so replaying their journal entries reproduces their trades
+ALTER TABLE orders ADD COLUMN "self_trade_prevention" smallint NOT NULL DEFAULT 4;
+ALTER TABLE orders ALTER COLUMN "self_trade_prevention" SET DEFAULT 0;
+ALTER TABLE engine_journal ADD COLUMN "trader_id" bigint REFERENCES traders(id);
+ALTER TABLE engine_journal ADD COLUMN "self_trade_prevention" smallint NOT NULL DEFAULT 4;
+ALTER TABLE engine_journal ALTER COLUMN "self_trade_prevention" SET DEFAULT 0;
+UPDATE engine_journal SET trader_id = orders.trader_id FROM orders WHERE orders.id = engine_journal.order_id;
+ALTER TABLE engine_journal ALTER COLUMN "trader_id" SET NOT NULL;
+ALTER TABLE order_events ADD COLUMN "counterparty_order_id" bigint REFERENCES orders(id);
+-- Snapshots don't have the traders of resting orders, books are rebuilt from the journal instead
+DELETE FROM book_snapshots;
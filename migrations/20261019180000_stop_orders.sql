@@ -0,0 +1,6 @@
+-- 0: limit, 1: stop market, 2: stop limit
+ALTER TABLE orders ADD COLUMN "order_type" smallint NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN "stop_price" int;
+ALTER TABLE orders ADD COLUMN "triggered_at" timestamp WITH time zone;
+ALTER TABLE engine_journal ADD COLUMN "order_type" smallint NOT NULL DEFAULT 0;
+ALTER TABLE engine_journal ADD COLUMN "stop_price" int;
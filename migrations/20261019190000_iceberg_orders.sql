@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN "display_quantity" int;
+ALTER TABLE engine_journal ADD COLUMN "display_quantity" int;
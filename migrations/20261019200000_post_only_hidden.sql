@@ -0,0 +1,5 @@
+-- 0: disabled, 1: reject, 2: reprice
+ALTER TABLE orders ADD COLUMN "post_only" smallint NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN "hidden" boolean NOT NULL DEFAULT false;
+ALTER TABLE engine_journal ADD COLUMN "post_only" smallint NOT NULL DEFAULT 0;
+ALTER TABLE engine_journal ADD COLUMN "hidden" boolean NOT NULL DEFAULT false;
@@ -0,0 +1,18 @@
+-- Auction entries of the journal belong to no order
+ALTER TABLE engine_journal ALTER COLUMN "order_id" DROP NOT NULL;
+ALTER TABLE engine_journal ALTER COLUMN "trader_id" DROP NOT NULL;
+ALTER TABLE book_snapshots ADD COLUMN "auction" boolean NOT NULL DEFAULT false;
+ALTER TABLE book_snapshots ADD COLUMN "last_price" int;
+-- Orders of the card are collected without matching from opens_at and uncrossed at closes_at
+CREATE TABLE call_auctions (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "card_id" int NOT NULL,
+  "opens_at" timestamp WITH time zone NOT NULL,
+  "closes_at" timestamp WITH time zone NOT NULL,
+  "opened_at" timestamp WITH time zone,
+  "uncrossed_at" timestamp WITH time zone,
+  "price" int,
+  "volume" int,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX call_auctions_card_id_idx ON call_auctions (card_id, opens_at);
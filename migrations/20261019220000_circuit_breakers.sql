@@ -0,0 +1,2 @@
+-- Auctions opened by the circuit breaker, the card is halted until they uncross
+ALTER TABLE call_auctions ADD COLUMN "halt" boolean NOT NULL DEFAULT false;
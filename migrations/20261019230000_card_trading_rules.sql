@@ -0,0 +1,2 @@
+-- Tick size of the card when the entry was appended, so replays reprice post-only orders the same way
+ALTER TABLE engine_journal ADD COLUMN "tick_size" int NOT NULL DEFAULT 1;
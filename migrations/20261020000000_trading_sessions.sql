@@ -0,0 +1,12 @@
+-- 0: good till cancelled, 1: day
+ALTER TABLE orders ADD COLUMN "time_in_force" smallint NOT NULL DEFAULT 0;
+-- Windows in which the market is closed regardless of the session hours, kind 0: holiday, 1: maintenance
+CREATE TABLE market_closures (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "kind" smallint NOT NULL,
+  "starts_at" timestamp WITH time zone NOT NULL,
+  "ends_at" timestamp WITH time zone NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX market_closures_ends_at_idx ON market_closures (ends_at);
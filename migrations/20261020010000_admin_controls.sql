@@ -0,0 +1,25 @@
+-- Cards halted by operations, a row without card_id halts the whole market
+CREATE TABLE trading_halts (
+  "card_id" int,
+  "operator" text NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
+  "halted_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE UNIQUE INDEX trading_halts_card_id_idx ON trading_halts (COALESCE(card_id, -1));
+-- Traders whose orders are rejected until they are reinstated
+CREATE TABLE trader_suspensions (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "operator" text NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
+  "suspended_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+-- Every admin action with the operator who took it
+CREATE TABLE admin_audit_log (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "operator" text NOT NULL,
+  "action" text NOT NULL,
+  "card_id" int,
+  "trader_id" bigint,
+  "details" jsonb NOT NULL DEFAULT '{}',
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,9 @@
+-- Per-trader overrides of the configured risk limits, NULL keeps the default and 0 disables the limit
+CREATE TABLE trader_risk_limits (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "max_order_value" bigint,
+  "max_open_orders" int,
+  "max_card_notional" bigint,
+  "max_position" int,
+  "max_daily_volume" int
+);
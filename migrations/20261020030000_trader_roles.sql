@@ -0,0 +1,2 @@
+-- 0 trader, 1 market maker, 2 support (read-only), 3 admin
+ALTER TABLE traders ADD COLUMN "role" smallint NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+-- Every step of an order is appended as an event with the trade of a fill and who caused it,
+-- lifecycle events start with this migration, earlier orders only have their detail events
+ALTER TABLE order_events
+  ADD COLUMN "trade_id" bigint REFERENCES trades(id),
+  ADD COLUMN "actor" text NOT NULL DEFAULT 'system';
+UPDATE order_events SET actor = 'trader:' || orders.trader_id FROM orders WHERE orders.id = order_events.order_id AND order_events.kind = 0;
+ALTER TABLE order_events ALTER COLUMN "actor" DROP DEFAULT;
+CREATE FUNCTION reject_order_event_change() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'order_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER order_events_append_only BEFORE UPDATE OR DELETE ON order_events FOR EACH ROW EXECUTE FUNCTION reject_order_event_change();
+CREATE TRIGGER order_events_no_truncate BEFORE TRUNCATE ON order_events FOR EACH STATEMENT EXECUTE FUNCTION reject_order_event_change();
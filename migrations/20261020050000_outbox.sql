@@ -0,0 +1,14 @@
+-- Trades and order events for other services, written in the transaction which produced them
+-- and published by the relay at least once, in order of id
+CREATE TABLE outbox (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "topic" text NOT NULL,
+  "payload" jsonb NOT NULL,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  -- A relay holds the message until then, after a failure it is retried then
+  "next_attempt_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "attempts" int NOT NULL DEFAULT 0,
+  "last_error" text,
+  "published_at" timestamp WITH time zone
+);
+CREATE INDEX outbox_unpublished_idx ON outbox (id) WHERE published_at IS NULL;
@@ -0,0 +1,2 @@
+-- SHA-256 of the API key of the trader, the key itself is only shown when it's issued, NULL until one is
+ALTER TABLE traders ADD COLUMN "api_key_hash" bytea UNIQUE;
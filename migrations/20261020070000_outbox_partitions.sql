@@ -0,0 +1,6 @@
+-- A message waiting for its retry holds back the later messages of its card only, and one which failed
+-- OUTBOX_MAX_ATTEMPTS times is parked until operations publish it again by clearing parked_at
+ALTER TABLE outbox ADD COLUMN "card_id" int;
+ALTER TABLE outbox ADD COLUMN "parked_at" timestamp WITH time zone;
+UPDATE outbox SET card_id = (payload->>'card_id')::int;
+CREATE INDEX outbox_card_unpublished_idx ON outbox (card_id, id) WHERE published_at IS NULL;
@@ -2,7 +2,9 @@ CREATE DATABASE pokemon;
 \connect pokemon;
 CREATE TABLE traders (
   "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
-  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "role" smallint NOT NULL DEFAULT 0,
+  "api_key_hash" bytea UNIQUE
 );
 CREATE TABLE orders (
   "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
@@ -11,7 +13,19 @@ CREATE TABLE orders (
   "side" smallint NOT NULL,
   "status" smallint NOT NULL,
   "trader_id" bigint NOT NULL REFERENCES traders(id),
-  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "client_order_id" text,
+  "quantity" int NOT NULL DEFAULT 1,
+  "filled_quantity" int NOT NULL DEFAULT 0,
+  "self_trade_prevention" smallint NOT NULL DEFAULT 0,
+  "order_type" smallint NOT NULL DEFAULT 0,
+  "stop_price" int,
+  "triggered_at" timestamp WITH time zone,
+  "display_quantity" int,
+  "post_only" smallint NOT NULL DEFAULT 0,
+  "hidden" boolean NOT NULL DEFAULT false,
+  "time_in_force" smallint NOT NULL DEFAULT 0,
+  CONSTRAINT orders_trader_id_client_order_id_key UNIQUE (trader_id, client_order_id)
 );
 CREATE TABLE trades (
   "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
@@ -19,8 +33,143 @@ CREATE TABLE trades (
   "price" int NOT NULL,
   "buyorder_id" bigint NOT NULL REFERENCES orders(id),
   "sellorder_id" bigint NOT NULL REFERENCES orders(id),
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "buyer_fee" int NOT NULL DEFAULT 0,
+  "seller_fee" int NOT NULL DEFAULT 0,
+  "quantity" int NOT NULL DEFAULT 1,
+  "journal_sequence" bigint
+);
+CREATE INDEX trades_buyorder_id_idx ON trades (buyorder_id);
+CREATE INDEX trades_sellorder_id_idx ON trades (sellorder_id);
+CREATE INDEX orders_trader_id_idx ON orders (trader_id);
+CREATE TABLE order_events (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "order_id" bigint NOT NULL REFERENCES orders(id),
+  "kind" smallint NOT NULL,
+  "price" int NOT NULL,
+  "quantity" int NOT NULL,
+  "previous_price" int,
+  "previous_quantity" int,
+  "counterparty_order_id" bigint REFERENCES orders(id),
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "trade_id" bigint REFERENCES trades(id),
+  "actor" text NOT NULL
+);
+CREATE INDEX order_events_order_id_idx ON order_events (order_id);
+CREATE FUNCTION reject_order_event_change() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'order_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER order_events_append_only BEFORE UPDATE OR DELETE ON order_events FOR EACH ROW EXECUTE FUNCTION reject_order_event_change();
+CREATE TRIGGER order_events_no_truncate BEFORE TRUNCATE ON order_events FOR EACH STATEMENT EXECUTE FUNCTION reject_order_event_change();
+CREATE TABLE outbox (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "topic" text NOT NULL,
+  "payload" jsonb NOT NULL,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "next_attempt_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "attempts" int NOT NULL DEFAULT 0,
+  "last_error" text,
+  "published_at" timestamp WITH time zone,
+  "card_id" int,
+  "parked_at" timestamp WITH time zone
+);
+CREATE INDEX outbox_unpublished_idx ON outbox (id) WHERE published_at IS NULL;
+CREATE INDEX outbox_card_unpublished_idx ON outbox (card_id, id) WHERE published_at IS NULL;
+CREATE TABLE trader_rate_limits (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "requests_per_second" int,
+  "request_burst" int,
+  "orders_per_second" int,
+  "order_burst" int
+);
+CREATE TABLE trader_risk_limits (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "max_order_value" bigint,
+  "max_open_orders" int,
+  "max_card_notional" bigint,
+  "max_position" int,
+  "max_daily_volume" int
+);
+CREATE TABLE engine_journal (
+  "sequence" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "card_id" int NOT NULL,
+  "kind" smallint NOT NULL,
+  "order_id" bigint REFERENCES orders(id),
+  "side" smallint NOT NULL,
+  "price" int NOT NULL,
+  "quantity" int NOT NULL,
+  "previous_price" int,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "trader_id" bigint REFERENCES traders(id),
+  "self_trade_prevention" smallint NOT NULL DEFAULT 0,
+  "order_type" smallint NOT NULL DEFAULT 0,
+  "stop_price" int,
+  "display_quantity" int,
+  "post_only" smallint NOT NULL DEFAULT 0,
+  "hidden" boolean NOT NULL DEFAULT false,
+  "tick_size" int NOT NULL DEFAULT 1
+);
+CREATE INDEX engine_journal_card_id_idx ON engine_journal (card_id, sequence);
+CREATE TABLE book_snapshots (
+  "card_id" int PRIMARY KEY,
+  "sequence" bigint NOT NULL,
+  "orders" jsonb NOT NULL,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "auction" boolean NOT NULL DEFAULT false,
+  "last_price" int
+);
+CREATE TABLE call_auctions (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "card_id" int NOT NULL,
+  "opens_at" timestamp WITH time zone NOT NULL,
+  "closes_at" timestamp WITH time zone NOT NULL,
+  "opened_at" timestamp WITH time zone,
+  "uncrossed_at" timestamp WITH time zone,
+  "price" int,
+  "volume" int,
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  "halt" boolean NOT NULL DEFAULT false
+);
+CREATE INDEX call_auctions_card_id_idx ON call_auctions (card_id, opens_at);
+CREATE TABLE market_closures (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "kind" smallint NOT NULL,
+  "starts_at" timestamp WITH time zone NOT NULL,
+  "ends_at" timestamp WITH time zone NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
   "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
+CREATE INDEX market_closures_ends_at_idx ON market_closures (ends_at);
+CREATE TABLE trading_halts (
+  "card_id" int,
+  "operator" text NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
+  "halted_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE UNIQUE INDEX trading_halts_card_id_idx ON trading_halts (COALESCE(card_id, -1));
+CREATE TABLE trader_suspensions (
+  "trader_id" bigint PRIMARY KEY REFERENCES traders(id),
+  "operator" text NOT NULL,
+  "reason" text NOT NULL DEFAULT '',
+  "suspended_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE admin_audit_log (
+  "id" bigint GENERATED always AS IDENTITY PRIMARY KEY,
+  "operator" text NOT NULL,
+  "action" text NOT NULL,
+  "card_id" int,
+  "trader_id" bigint,
+  "details" jsonb NOT NULL DEFAULT '{}',
+  "created_at" timestamp WITH time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE engine_leader (
+  "id" int PRIMARY KEY CHECK (id = 1),
+  "epoch" bigint NOT NULL,
+  "elected_at" timestamp WITH time zone
+);
+INSERT INTO engine_leader (id, epoch) VALUES (1, 0);
 
 do $$
 BEGIN
@@ -0,0 +1,145 @@
+use actix_web::{web, FromRequest, HttpRequest, dev::Payload, http::header};
+use anyhow::{anyhow, Context};
+use futures::future::LocalBoxFuture;
+
+use crate::error::Error;
+use crate::ports::{Role, TraderStore};
+use crate::rate_limit::RateLimits;
+use crate::trader_store::PostgresTraderStoreImpl;
+
+// What a route requires of its caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    // Orders and trades of the trader
+    ReadTrader(i64),
+    // Placing, amending and cancelling the orders of the trader
+    Trade(i64),
+    // Halts, suspensions, risk limits, roles and the audit log
+    ReadAdmin,
+    Admin,
+}
+
+// Trader making the request, authenticated by its API key in the Authorization header,
+// the role is read on every request so a change applies right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub trader_id: i64,
+    pub role: Role,
+}
+
+impl Caller {
+    pub fn permits(&self, permission: Permission) -> bool {
+        let staff = matches!(self.role, Role::Support | Role::Admin);
+        match permission {
+            Permission::ReadTrader(trader_id) => self.trader_id == trader_id || staff,
+            // Staff accounts don't trade, admins cancel the orders of a trader through the admin routes
+            Permission::Trade(trader_id) => self.trader_id == trader_id && !staff,
+            Permission::ReadAdmin => staff,
+            Permission::Admin => self.role == Role::Admin,
+        }
+    }
+
+    pub fn authorize(&self, permission: Permission) -> Result<(), Error> {
+        if self.permits(permission) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+}
+
+// Key of an `Authorization: Bearer <key>` header
+fn api_key(req: &HttpRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, api_key) = value.trim().split_once(' ')?;
+    let api_key = api_key.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !api_key.is_empty()).then_some(api_key)
+}
+
+// Requests of the caller are charged to its quota only once it's authenticated, so nobody can use up
+// the quota of another trader, unauthenticated requests are only limited by IP
+async fn authenticate(trader_store: &impl TraderStore, rate_limits: Option<&RateLimits>, api_key: Option<String>) -> Result<Caller, Error> {
+    let api_key = api_key.ok_or(Error::Unauthenticated)?;
+    let (trader_id, role) = trader_store.authenticate(&api_key).await.context("Failed to authenticate")?
+        .ok_or(Error::Unauthenticated)?;
+    if let Some(rate_limits) = rate_limits {
+        rate_limits.check_trader(trader_id).await?;
+    }
+    Ok(Caller { trader_id, role })
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let api_key = api_key(req).map(str::to_string);
+        let trader_store = req.app_data::<web::Data<PostgresTraderStoreImpl>>().cloned();
+        let rate_limits = req.app_data::<web::Data<RateLimits>>().cloned();
+        Box::pin(async move {
+            let trader_store = trader_store.ok_or_else(|| anyhow!("Trader store is not configured"))?;
+            authenticate(&**trader_store, rate_limits.as_ref().map(web::Data::get_ref), api_key).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use actix_web::test::TestRequest;
+    use crate::ports::MockTraderStore;
+    use crate::rate_limit::{Limit, RateLimitConfig};
+    use super::*;
+
+    #[test]
+    fn test_permissions_of_roles() {
+        let caller = |role| Caller { trader_id: 1, role };
+        for role in [Role::Trader, Role::MarketMaker] {
+            assert!(caller(role).permits(Permission::ReadTrader(1)));
+            assert!(caller(role).permits(Permission::Trade(1)));
+            assert!(!caller(role).permits(Permission::ReadTrader(2)));
+            assert!(!caller(role).permits(Permission::Trade(2)));
+            assert!(!caller(role).permits(Permission::ReadAdmin));
+            assert!(!caller(role).permits(Permission::Admin));
+        }
+        assert!(caller(Role::Support).permits(Permission::ReadTrader(2)));
+        assert!(caller(Role::Support).permits(Permission::ReadAdmin));
+        assert!(!caller(Role::Support).permits(Permission::Trade(1)));
+        assert!(!caller(Role::Support).permits(Permission::Admin));
+        assert!(caller(Role::Admin).permits(Permission::ReadTrader(2)));
+        assert!(caller(Role::Admin).permits(Permission::Admin));
+        assert!(!caller(Role::Admin).permits(Permission::Trade(2)));
+        assert!(matches!(caller(Role::Trader).authorize(Permission::Admin), Err(Error::Forbidden)));
+    }
+
+    #[test]
+    fn test_api_key_of_authorization_header() {
+        let api_key = |value: &str| {
+            let req = TestRequest::default().insert_header((header::AUTHORIZATION, value)).to_http_request();
+            api_key(&req).map(str::to_string)
+        };
+        assert_eq!(Some("abc123".to_string()), api_key("Bearer abc123"));
+        assert_eq!(Some("abc123".to_string()), api_key("bearer  abc123 "));
+        assert_eq!(None, api_key("Bearer "));
+        assert_eq!(None, api_key("Basic YWJjOjEyMw=="));
+        assert_eq!(None, api_key("abc123"));
+        assert_eq!(None, super::api_key(&TestRequest::default().to_http_request()));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_quota_is_charged_to_the_authenticated_caller() {
+        let mut trader_store = MockTraderStore::new();
+        trader_store.expect_authenticate().returning(|api_key| Ok((api_key == "key-1").then_some((1, Role::Trader))));
+        let mut limits_store = MockTraderStore::new();
+        limits_store.expect_query_rate_limits().returning(|_| Ok(None));
+        let limit = Limit { per_second: 0, burst: 1 };
+        let rate_limits = RateLimits::new(RateLimitConfig { trader: limit, ip: limit, order: limit, trust_forwarded_for: false }, Arc::new(limits_store));
+
+        // Unknown and missing keys are rejected before anything is charged
+        assert!(matches!(authenticate(&trader_store, Some(&rate_limits), Some("key-2".to_string())).await, Err(Error::Unauthenticated)));
+        assert!(matches!(authenticate(&trader_store, Some(&rate_limits), None).await, Err(Error::Unauthenticated)));
+        assert_eq!(Caller { trader_id: 1, role: Role::Trader }, authenticate(&trader_store, Some(&rate_limits), Some("key-1".to_string())).await.unwrap());
+        assert!(matches!(authenticate(&trader_store, Some(&rate_limits), Some("key-1".to_string())).await, Err(Error::RateLimited(_))));
+    }
+}
@@ -1,5 +1,43 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
 pub const NUM_CARDS: usize = 4;
+pub const MAX_QUANTITY: i32 = 1_000_000;
+
+// Trading rules of a card, prices in cents
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct Card {
+    pub id: i32,
+    pub min_price: i32,
+    pub max_price: i32,
+    /// Prices are multiples of the tick size
+    pub tick_size: i32,
+    pub min_quantity: i32,
+    /// Quantities are multiples of the lot size
+    pub lot_size: i32,
+}
+
+pub const CATALOG: [Card; NUM_CARDS] = [
+    Card { id: 0, min_price: 100, max_price: 1000, tick_size: 1, min_quantity: 1, lot_size: 1 },
+    Card { id: 1, min_price: 100, max_price: 1000, tick_size: 1, min_quantity: 1, lot_size: 1 },
+    Card { id: 2, min_price: 100, max_price: 1000, tick_size: 5, min_quantity: 1, lot_size: 1 },
+    Card { id: 3, min_price: 500, max_price: 5000, tick_size: 10, min_quantity: 10, lot_size: 10 },
+];
+
+impl Card {
+    pub fn is_valid_price(&self, price: i32) -> bool {
+        (self.min_price..=self.max_price).contains(&price) && price % self.tick_size == 0
+    }
+
+    pub fn is_valid_quantity(&self, quantity: i32) -> bool {
+        (self.min_quantity..=MAX_QUANTITY).contains(&quantity) && quantity % self.lot_size == 0
+    }
+}
+
+pub fn find(id: i32) -> Option<&'static Card> {
+    usize::try_from(id).ok().and_then(|id| CATALOG.get(id))
+}
 
 pub fn is_valid(id: i32) -> bool {
-    id >= 0 && id < NUM_CARDS.try_into().unwrap()
+    find(id).is_some()
 }
@@ -1,5 +1,9 @@
+use anyhow::{anyhow, bail, Context};
+use chrono::NaiveTime;
 use envconfig::Envconfig;
 
+use crate::session::SessionHours;
+
 #[derive(Envconfig)]
 pub struct Config {
   #[envconfig(from = "HOST", default = "127.0.0.1")]
@@ -10,4 +14,153 @@ pub struct Config {
 
   #[envconfig(from = "DATABASE_URL")]
   pub database_url: String,
+
+  // Basis points charged on the trade price, maker is the resting order and taker is the incoming one
+  #[envconfig(from = "MAKER_FEE_BPS", default = "0")]
+  pub maker_fee_bps: i32,
+
+  #[envconfig(from = "TAKER_FEE_BPS", default = "0")]
+  pub taker_fee_bps: i32,
+
+  // Secret mixed into counterparty pseudonyms of trade history, required since trader ids are small enough
+  // to try every one against an unsalted pseudonym
+  #[envconfig(from = "COUNTERPARTY_SALT")]
+  pub counterparty_salt: String,
+
+  // HTTP date after which the unversioned /api routes may be removed
+  #[envconfig(from = "API_V1_SUNSET", default = "Sat, 01 May 2027 00:00:00 GMT")]
+  pub api_v1_sunset: String,
+
+  // Token buckets per trader and per client IP, the order limit counts order entry messages of a trader,
+  // per-trader overrides are in the trader_rate_limits table
+  #[envconfig(from = "RATE_LIMIT_TRADER_PER_SECOND", default = "20")]
+  pub rate_limit_trader_per_second: u32,
+
+  #[envconfig(from = "RATE_LIMIT_TRADER_BURST", default = "40")]
+  pub rate_limit_trader_burst: u32,
+
+  #[envconfig(from = "RATE_LIMIT_IP_PER_SECOND", default = "50")]
+  pub rate_limit_ip_per_second: u32,
+
+  #[envconfig(from = "RATE_LIMIT_IP_BURST", default = "100")]
+  pub rate_limit_ip_burst: u32,
+
+  #[envconfig(from = "RATE_LIMIT_ORDER_PER_SECOND", default = "10")]
+  pub rate_limit_order_per_second: u32,
+
+  #[envconfig(from = "RATE_LIMIT_ORDER_BURST", default = "20")]
+  pub rate_limit_order_burst: u32,
+
+  // Take the client IP from X-Forwarded-For, only behind a proxy which sets it
+  #[envconfig(from = "RATE_LIMIT_TRUST_FORWARDED_FOR", default = "false")]
+  pub rate_limit_trust_forwarded_for: bool,
+
+  // Orders must be priced within these basis points around the last trade or auction price of the card, 0 disables the band
+  #[envconfig(from = "PRICE_BAND_BPS", default = "2000")]
+  pub price_band_bps: i32,
+
+  // A card halts if its price moves more than these basis points within the window, 0 disables the circuit breaker
+  #[envconfig(from = "CIRCUIT_BREAKER_BPS", default = "1000")]
+  pub circuit_breaker_bps: i32,
+
+  #[envconfig(from = "CIRCUIT_BREAKER_WINDOW_SECS", default = "300")]
+  pub circuit_breaker_window_secs: u64,
+
+  // Length of the call auction a halted card re-opens through
+  #[envconfig(from = "HALT_AUCTION_SECS", default = "300")]
+  pub halt_auction_secs: u64,
+
+  // Seconds between snapshots of the order books, 0 disables them
+  #[envconfig(from = "SNAPSHOT_INTERVAL_SECS", default = "300")]
+  pub snapshot_interval_secs: u64,
+
+  // Daily trading hours as HH:MM in UTC, the market trades around the clock if they are empty.
+  // Orders are collected for the opening auction from the pre-open and for the closing auction until the close
+  #[envconfig(from = "SESSION_PRE_OPEN", default = "")]
+  pub session_pre_open: String,
+
+  #[envconfig(from = "SESSION_OPEN", default = "")]
+  pub session_open: String,
+
+  #[envconfig(from = "SESSION_CLOSING_AUCTION", default = "")]
+  pub session_closing_auction: String,
+
+  #[envconfig(from = "SESSION_CLOSE", default = "")]
+  pub session_close: String,
+
+  // Cancel orders with the day time in force when the market closes
+  #[envconfig(from = "CANCEL_DAY_ORDERS_AT_CLOSE", default = "true")]
+  pub cancel_day_orders_at_close: bool,
+
+  // Pre-trade risk limits of every trader, 0 disables a limit, per-trader overrides are in the trader_risk_limits table.
+  // Values are in cents, positions and volumes in cards
+  #[envconfig(from = "RISK_MAX_ORDER_VALUE", default = "0")]
+  pub risk_max_order_value: i64,
+
+  #[envconfig(from = "RISK_MAX_OPEN_ORDERS", default = "0")]
+  pub risk_max_open_orders: i64,
+
+  #[envconfig(from = "RISK_MAX_CARD_NOTIONAL", default = "0")]
+  pub risk_max_card_notional: i64,
+
+  #[envconfig(from = "RISK_MAX_POSITION", default = "0")]
+  pub risk_max_position: i64,
+
+  #[envconfig(from = "RISK_MAX_DAILY_VOLUME", default = "0")]
+  pub risk_max_daily_volume: i64,
+
+  // Sinks the outbox relay publishes trades and order events to, each one is off while empty.
+  // The file gets a JSON line per message, the webhook a POST and NATS a message on <prefix>.<topic>
+  #[envconfig(from = "OUTBOX_FILE", default = "")]
+  pub outbox_file: String,
+
+  #[envconfig(from = "OUTBOX_WEBHOOK_URL", default = "")]
+  pub outbox_webhook_url: String,
+
+  #[envconfig(from = "OUTBOX_NATS_URL", default = "")]
+  pub outbox_nats_url: String,
+
+  #[envconfig(from = "OUTBOX_NATS_SUBJECT_PREFIX", default = "pokemon")]
+  pub outbox_nats_subject_prefix: String,
+
+  #[envconfig(from = "OUTBOX_BATCH_SIZE", default = "100")]
+  pub outbox_batch_size: i64,
+
+  #[envconfig(from = "OUTBOX_POLL_INTERVAL_MS", default = "500")]
+  pub outbox_poll_interval_ms: u64,
+
+  // A failed message is retried after the base delay, doubled per failed attempt up to the maximum
+  #[envconfig(from = "OUTBOX_RETRY_BASE_MS", default = "1000")]
+  pub outbox_retry_base_ms: u64,
+
+  #[envconfig(from = "OUTBOX_RETRY_MAX_SECS", default = "300")]
+  pub outbox_retry_max_secs: u64,
+
+  // A message failing this often is parked, so the later messages of its card aren't held back for good
+  #[envconfig(from = "OUTBOX_MAX_ATTEMPTS", default = "20")]
+  pub outbox_max_attempts: i32,
+}
+
+// Shorter secrets can be guessed along with the trader ids
+const MIN_COUNTERPARTY_SALT_LEN: usize = 16;
+
+impl Config {
+  pub fn counterparty_salt(&self) -> anyhow::Result<&str> {
+    if self.counterparty_salt.len() < MIN_COUNTERPARTY_SALT_LEN {
+      bail!("COUNTERPARTY_SALT must be a random secret of at least {} characters", MIN_COUNTERPARTY_SALT_LEN);
+    }
+    Ok(&self.counterparty_salt)
+  }
+
+  pub fn session_hours(&self) -> anyhow::Result<Option<SessionHours>> {
+    let times = [&self.session_pre_open, &self.session_open, &self.session_closing_auction, &self.session_close];
+    if times.iter().all(|time| time.is_empty()) {
+      return Ok(None);
+    }
+    let times = times.iter()
+      .map(|time| NaiveTime::parse_from_str(time, "%H:%M").with_context(|| format!("Invalid session time: {:?}", time)))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    SessionHours::new(times[0], times[1], times[2], times[3]).map(Some)
+      .ok_or_else(|| anyhow!("Session times must be in the order of pre-open, open, closing auction and close"))
+  }
 }
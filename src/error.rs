@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+// Errors returned by the order service, each kind has a stable code for API clients,
+// cloned when one failure answers several orders of a batch
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Invalid order side")]
+    InvalidSide,
+    #[error("Price must be within the price range of the card and a multiple of its tick size, stop market orders have no price")]
+    InvalidPrice,
+    #[error("Quantity must be at least the minimum quantity of the card, a multiple of its lot size and at most 1000000")]
+    InvalidQuantity,
+    #[error("Invalid card id")]
+    InvalidCardId,
+    #[error("Client order id must be 1 to 64 characters")]
+    InvalidClientOrderId,
+    #[error("Self-trade prevention must be cancel_newest, cancel_oldest, cancel_both or decrement")]
+    InvalidSelfTradePrevention,
+    #[error("Order type must be limit, stop_market or stop_limit")]
+    InvalidOrderType,
+    #[error("Stop price within the price range of the card and a multiple of its tick size is required by stop orders and not allowed otherwise")]
+    InvalidStopPrice,
+    #[error("Display quantity must be a valid quantity of the card up to the order quantity and is not allowed for stop market and hidden orders")]
+    InvalidDisplayQuantity,
+    #[error("Post only must be reject or reprice and is not allowed for stop market orders")]
+    InvalidPostOnly,
+    #[error("Price must be within the band of {0} to {1} cents around the last trade")]
+    PriceOutsideBand(i32, i32),
+    #[error("Order value must be at most {0} cents")]
+    OrderValueLimitExceeded(i64),
+    #[error("Trader may have at most {0} open orders")]
+    OpenOrdersLimitExceeded(i64),
+    #[error("Open orders of the card may be worth at most {0} cents")]
+    CardNotionalLimitExceeded(i64),
+    #[error("Position in the card including the open orders of the side may be at most {0}")]
+    PositionLimitExceeded(i64),
+    #[error("Trader may trade at most {0} cards a day")]
+    DailyVolumeLimitExceeded(i64),
+    #[error("Call auction must close after it opens and in the future")]
+    InvalidAuctionWindow,
+    #[error("Market closure must end after it starts and in the future")]
+    InvalidClosureWindow,
+    #[error("Time in force must be gtc or day")]
+    InvalidTimeInForce,
+    #[error("X-Operator header with the operator identity is required by admin actions")]
+    OperatorRequired,
+    #[error("Batch must have 1 to 100 orders")]
+    InvalidBatchSize,
+    #[error("Price or quantity is required")]
+    EmptyAmendment,
+    #[error("Quantity must be greater than filled quantity")]
+    QuantityNotAboveFilled,
+    #[error("Authorization header with the API key of a trader is required")]
+    Unauthenticated,
+    #[error("Role of the caller does not allow this request")]
+    Forbidden,
+    #[error("Trader does not exist")]
+    TraderNotFound,
+    #[error("Order not found")]
+    OrderNotFound,
+    #[error("Card not found")]
+    CardNotFound,
+    #[error("Order is not pending")]
+    OrderNotPending,
+    #[error("Another call auction of the card is scheduled in the window")]
+    AuctionOverlaps,
+    #[error("The market is closed, orders are accepted from the pre-open until the close")]
+    MarketClosed,
+    #[error("Trading is halted by operations, orders can only be cancelled")]
+    TradingHalted,
+    #[error("Trader is suspended, orders can only be cancelled")]
+    TraderSuspended,
+    // Seconds until the request would be admitted
+    #[error("Rate limit exceeded, retry after {0} seconds")]
+    RateLimited(u64),
+    #[error("Order entry is handled by the leader instance, retry later")]
+    NotLeader,
+    #[error("{0}")]
+    Internal(Arc<anyhow::Error>),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::Internal(Arc::new(e))
+    }
+}
+
+impl Error {
+    // Part of the API, never rename an existing code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::InvalidSide => "invalid_side",
+            Error::InvalidPrice => "invalid_price",
+            Error::InvalidQuantity => "invalid_quantity",
+            Error::InvalidCardId => "invalid_card_id",
+            Error::InvalidClientOrderId => "invalid_client_order_id",
+            Error::InvalidSelfTradePrevention => "invalid_self_trade_prevention",
+            Error::InvalidOrderType => "invalid_order_type",
+            Error::InvalidStopPrice => "invalid_stop_price",
+            Error::InvalidDisplayQuantity => "invalid_display_quantity",
+            Error::InvalidPostOnly => "invalid_post_only",
+            Error::PriceOutsideBand(..) => "price_outside_band",
+            Error::OrderValueLimitExceeded(_) => "order_value_limit_exceeded",
+            Error::OpenOrdersLimitExceeded(_) => "open_orders_limit_exceeded",
+            Error::CardNotionalLimitExceeded(_) => "card_notional_limit_exceeded",
+            Error::PositionLimitExceeded(_) => "position_limit_exceeded",
+            Error::DailyVolumeLimitExceeded(_) => "daily_volume_limit_exceeded",
+            Error::InvalidAuctionWindow => "invalid_auction_window",
+            Error::InvalidClosureWindow => "invalid_closure_window",
+            Error::InvalidTimeInForce => "invalid_time_in_force",
+            Error::OperatorRequired => "operator_required",
+            Error::InvalidBatchSize => "invalid_batch_size",
+            Error::EmptyAmendment => "empty_amendment",
+            Error::QuantityNotAboveFilled => "quantity_not_above_filled",
+            Error::Unauthenticated => "unauthenticated",
+            Error::Forbidden => "forbidden",
+            Error::TraderNotFound => "trader_not_found",
+            Error::OrderNotFound => "order_not_found",
+            Error::CardNotFound => "card_not_found",
+            Error::OrderNotPending => "order_not_pending",
+            Error::AuctionOverlaps => "auction_overlaps",
+            Error::MarketClosed => "market_closed",
+            Error::TradingHalted => "trading_halted",
+            Error::TraderSuspended => "trader_suspended",
+            Error::RateLimited(_) => "rate_limited",
+            Error::NotLeader => "not_leader",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    // Message for clients, internal errors are only logged
+    pub fn detail(&self) -> String {
+        match self {
+            Error::Internal(_) => "Internal server error".to_string(),
+            e => e.to_string(),
+        }
+    }
+}
@@ -3,9 +3,14 @@ use actix_web::{web, get, HttpResponse, Responder, route};
 use actix_web_lab::respond::Html;
 use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
 
-use crate::graphql::schema::{Schema, create_schema};
+use crate::auth::Caller;
+use crate::error::Error;
+use crate::graphql::schema::{Context, Schema, create_schema};
 use crate::order_store::PostgresOrderStoreImpl;
 use crate::trade_store::PostgresTradeStoreImpl;
+use crate::trader_store::PostgresTraderStoreImpl;
+use crate::rate_limit::RateLimits;
+use crate::OrderServiceImpl;
 
 #[get("/graphiql")]
 async fn graphql_playground() -> impl Responder {
@@ -13,13 +18,20 @@ async fn graphql_playground() -> impl Responder {
 }
 
 #[route("/graphql", method = "GET", method = "POST")]
-async fn graphql(st: web::Data<Schema>, data: web::Json<GraphQLRequest>) -> impl Responder {
-    let user = data.execute(&st, &()).await;
-    HttpResponse::Ok().json(user)
+async fn graphql(st: web::Data<Schema>, caller: Result<Caller, Error>, data: web::Json<GraphQLRequest>) -> Result<HttpResponse, Error> {
+    // Anonymous requests may query the public fields
+    let context = match caller {
+        Ok(caller) => Context { caller: Some(caller) },
+        Err(Error::Unauthenticated) => Context { caller: None },
+        Err(e) => return Err(e),
+    };
+    let user = data.execute(&st, &context).await;
+    Ok(HttpResponse::Ok().json(user))
 }
 
-pub fn configure(cfg: &mut web::ServiceConfig, order_store: PostgresOrderStoreImpl, trade_store: PostgresTradeStoreImpl) {
-  let schema = Arc::new(create_schema(order_store, trade_store));
+pub fn configure(cfg: &mut web::ServiceConfig, trader_store: PostgresTraderStoreImpl, order_store: PostgresOrderStoreImpl, trade_store: PostgresTradeStoreImpl,
+    order_service: OrderServiceImpl, rate_limits: Arc<RateLimits>) {
+  let schema = Arc::new(create_schema(trader_store, order_store, trade_store, order_service, rate_limits));
   cfg.app_data(web::Data::from(schema.clone()))
     .service(graphql)
     .service(graphql_playground);
@@ -1,8 +1,16 @@
-use juniper::{FieldResult, EmptySubscription, RootNode, GraphQLObject};
+use std::sync::Arc;
+use juniper::{EmptySubscription, RootNode, GraphQLObject, GraphQLEnum, FieldError, IntoFieldError, ScalarValue, graphql_value};
+use anyhow::anyhow;
+use log::error;
 
-use crate::ports::{self, TradeStore, OrderStore};
+use crate::auth::{Caller, Permission};
+use crate::ports::{self, TradeStore, OrderStore, TraderStore, OrderService};
+use crate::error::Error;
+use crate::rate_limit::RateLimits;
 use crate::order_store::PostgresOrderStoreImpl;
 use crate::trade_store::PostgresTradeStoreImpl;
+use crate::trader_store::PostgresTraderStoreImpl;
+use crate::OrderServiceImpl;
 
 #[derive(GraphQLObject)]
 #[graphql(description = "Order")]
@@ -13,6 +21,9 @@ struct Order {
     pub side: i32,
     pub status: i32,
     pub trader_id: String,
+    pub quantity: i32,
+    pub filled_quantity: i32,
+    pub client_order_id: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>
 }
 impl From<ports::Order> for Order{
@@ -24,6 +35,9 @@ impl From<ports::Order> for Order{
             side: order.side.into(),
             status: order.status.into(),
             trader_id: order.trader_id.to_string(),
+            quantity: order.quantity,
+            filled_quantity: order.filled_quantity,
+            client_order_id: order.client_order_id,
             created_at: order.created_at
         }
     }
@@ -35,8 +49,11 @@ struct Trade {
     pub id: String,
     pub card_id: i32,
     pub price: i32,
+    pub quantity: i32,
     pub buyorder_id: String,
     pub sellorder_id: String,
+    pub buyer_fee: i32,
+    pub seller_fee: i32,
     pub created_at: chrono::DateTime<chrono::Utc>
 }
 impl From<ports::Trade> for Trade{
@@ -45,40 +62,209 @@ impl From<ports::Trade> for Trade{
             id: trade.id.to_string(),
             card_id: trade.card_id,
             price: trade.price,
+            quantity: trade.quantity,
             buyorder_id: trade.buyorder_id.to_string(),
             sellorder_id: trade.sellorder_id.to_string(),
+            buyer_fee: trade.buyer_fee,
+            seller_fee: trade.seller_fee,
             created_at: trade.created_at
         }
     }
 }
 
+#[derive(GraphQLObject)]
+#[graphql(description = "Trade record from the trader's side, counterparty is anonymized")]
+struct TraderTrade {
+    pub trade_id: String,
+    pub order_id: String,
+    pub card_id: i32,
+    pub side: i32,
+    pub price: i32,
+    pub quantity: i32,
+    pub fee: i32,
+    pub counterparty: String,
+    pub created_at: chrono::DateTime<chrono::Utc>
+}
+impl From<ports::TraderTrade> for TraderTrade{
+    fn from(trade: ports::TraderTrade) -> Self {
+        TraderTrade {
+            trade_id: trade.trade_id.to_string(),
+            order_id: trade.order_id.to_string(),
+            card_id: trade.card_id,
+            side: trade.side.into(),
+            price: trade.price,
+            quantity: trade.quantity,
+            fee: trade.fee,
+            counterparty: trade.counterparty,
+            created_at: trade.created_at
+        }
+    }
+}
+
+#[derive(GraphQLEnum)]
+enum SessionPhase {
+    PreOpen,
+    Continuous,
+    ClosingAuction,
+    Closed,
+}
+
+#[derive(GraphQLEnum)]
+enum TradingState {
+    Continuous,
+    Auction,
+    #[graphql(description = "In the call auction the circuit breaker opened")]
+    Halted,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Trading state and price band of a card")]
+struct CardStatus {
+    pub card_id: i32,
+    pub phase: SessionPhase,
+    pub state: TradingState,
+    #[graphql(description = "Last trade or auction price")]
+    pub reference_price: Option<i32>,
+    pub band_low: Option<i32>,
+    pub band_high: Option<i32>,
+    #[graphql(description = "When the running auction uncrosses, a halted card re-opens then")]
+    pub auction_closes_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl From<ports::CardStatus> for CardStatus {
+    fn from(status: ports::CardStatus) -> Self {
+        CardStatus {
+            card_id: status.card_id,
+            phase: match status.phase {
+                ports::SessionPhase::PreOpen => SessionPhase::PreOpen,
+                ports::SessionPhase::Continuous => SessionPhase::Continuous,
+                ports::SessionPhase::ClosingAuction => SessionPhase::ClosingAuction,
+                ports::SessionPhase::Closed => SessionPhase::Closed,
+            },
+            state: match status.state {
+                ports::TradingState::Continuous => TradingState::Continuous,
+                ports::TradingState::Auction => TradingState::Auction,
+                ports::TradingState::Halted => TradingState::Halted,
+            },
+            reference_price: status.reference_price,
+            band_low: status.band_low,
+            band_high: status.band_high,
+            auction_closes_at: status.auction_closes_at,
+        }
+    }
+}
+
+// Same codes as the REST problem responses, in the extensions of the error
+impl<S: ScalarValue> IntoFieldError<S> for Error {
+    fn into_field_error(self) -> FieldError<S> {
+        if let Error::Internal(e) = &self {
+            error!("{:#}", e);
+        }
+        let code = self.code();
+        FieldError::new(self.detail(), graphql_value!({ "code": code }))
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64, Error> {
+    id.parse::<i64>().map_err(|_| Error::InvalidRequest(format!("Invalid id: {}", id)))
+}
+
+// Caller of the request, None when it isn't identified, fields of a trader are authorized against it
+pub struct Context {
+    pub caller: Option<Caller>,
+}
+impl juniper::Context for Context {}
+impl Context {
+    fn authorize(&self, permission: Permission) -> Result<(), Error> {
+        self.caller.ok_or(Error::Unauthenticated)?.authorize(permission)
+    }
+    async fn charge(&self, rate_limits: &RateLimits) -> Result<(), Error> {
+        rate_limits.check_trader(self.caller.ok_or(Error::Unauthenticated)?.trader_id).await
+    }
+}
+
+struct Trader {
+    id: i64,
+    order_store: PostgresOrderStoreImpl,
+    trade_store: PostgresTradeStoreImpl,
+}
+
+#[juniper::graphql_object(description = "Trader", context = Context)]
+impl Trader {
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
+    async fn orders(&self) -> Result<Vec<Order>, Error> {
+        Ok(self.order_store.query_orders(self.id, None).await?.into_iter().map(|order| order.into()).collect())
+    }
+    async fn trades(&self) -> Result<Vec<TraderTrade>, Error> {
+        Ok(self.trade_store.query_trader_trades(self.id, None).await?.into_iter().map(|trade| trade.into()).collect())
+    }
+}
+
 pub struct QueryRoot{
+    trader_store: PostgresTraderStoreImpl,
     order_store: PostgresOrderStoreImpl,
     trade_store: PostgresTradeStoreImpl,
+    order_service: OrderServiceImpl,
+    // Every query of a trader costs the caller one more request on top of the GraphQL request
+    rate_limits: Arc<RateLimits>,
 }
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(context = Context)]
 impl QueryRoot {
-    async fn trades(&self, card_id: i32) -> FieldResult<Vec<Trade>> {
+    async fn card_status(&self, card_id: i32) -> Result<CardStatus, Error> {
+        Ok(self.order_service.card_status(card_id).await?.into())
+    }
+    async fn trades(&self, card_id: i32) -> Result<Vec<Trade>, Error> {
         Ok(self.trade_store.query_trades(card_id, None).await?.into_iter().map(|trade| trade.into()).collect())
     }
-    async fn orders(&self, trader_id: String) -> FieldResult<Vec<Order>> {
-        Ok(self.order_store.query_orders(trader_id.parse::<i64>()?, None).await?.into_iter().map(|order| order.into()).collect())
+    async fn orders(&self, context: &Context, trader_id: String) -> Result<Vec<Order>, Error> {
+        let trader_id = parse_id(&trader_id)?;
+        context.authorize(Permission::ReadTrader(trader_id))?;
+        context.charge(&self.rate_limits).await?;
+        Ok(self.order_store.query_orders(trader_id, None).await?.into_iter().map(|order| order.into()).collect())
+    }
+    async fn trader(&self, context: &Context, id: String) -> Result<Option<Trader>, Error> {
+        let id = parse_id(&id)?;
+        context.authorize(Permission::ReadTrader(id))?;
+        context.charge(&self.rate_limits).await?;
+        match self.trader_store.is_exist(id).await {
+            Some(true) => Ok(Some(Trader {id, order_store: self.order_store.clone(), trade_store: self.trade_store.clone()})),
+            Some(false) => Ok(None),
+            None => Err(anyhow!("Failed to query trader: {}", id).into()),
+        }
     }
 }
 
 pub struct MutationRoot;
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(context = Context)]
 impl MutationRoot {
-    fn add_order() -> FieldResult<bool> {
+    fn add_order() -> Result<bool, Error> {
         todo!();
-        Ok(true)
     }
 }
 
-pub type Schema = RootNode<'static, QueryRoot, MutationRoot, EmptySubscription>;
+pub type Schema = RootNode<'static, QueryRoot, MutationRoot, EmptySubscription<Context>>;
+
+pub fn create_schema(trader_store: PostgresTraderStoreImpl, order_store: PostgresOrderStoreImpl, trade_store: PostgresTradeStoreImpl,
+    order_service: OrderServiceImpl, rate_limits: Arc<RateLimits>) -> Schema {
+    Schema::new(QueryRoot {trader_store, order_store, trade_store, order_service, rate_limits}, MutationRoot {}, EmptySubscription::new())
+}
+#[cfg(test)]
+mod test {
+    use juniper::DefaultScalarValue;
+    use super::*;
 
-pub fn create_schema(order_store: PostgresOrderStoreImpl, trade_store: PostgresTradeStoreImpl) -> Schema {
-    Schema::new(QueryRoot {order_store, trade_store}, MutationRoot {}, EmptySubscription::new())
-}
\ No newline at end of file
+    #[test]
+    fn test_errors_have_the_code_in_extensions() {
+        let e: FieldError<DefaultScalarValue> = Error::OrderNotFound.into_field_error();
+        assert_eq!("Order not found", e.message());
+        assert_eq!(&graphql_value!({ "code": "order_not_found" }), e.extensions());
+        let e: FieldError<DefaultScalarValue> = Error::Forbidden.into_field_error();
+        assert_eq!(&graphql_value!({ "code": "forbidden" }), e.extensions());
+        let e: FieldError<DefaultScalarValue> = Error::from(anyhow!("Connection refused")).into_field_error();
+        assert_eq!("Internal server error", e.message());
+        assert_eq!(&graphql_value!({ "code": "internal_error" }), e.extensions());
+    }
+}
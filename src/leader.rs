@@ -0,0 +1,50 @@
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use anyhow::{Context, Result};
+use log::{info, error};
+use sqlx::{Connection, PgConnection};
+
+use crate::OrderServiceImpl;
+
+// Key of the session advisory lock, only its holder runs the matching engine
+const ENGINE_LOCK_KEY: i64 = 0x504f4b454d4f4e;
+// How often followers try to take the lock and the leader checks its connection
+const ELECTION_INTERVAL: Duration = Duration::from_secs(5);
+
+// Runs forever, the instance leads while its own connection holds the lock,
+// Postgres releases the lock once that connection is gone and a follower takes over
+pub async fn run(database_url: String, order_service: OrderServiceImpl) {
+    let mut conn = None;
+    loop {
+        if let Err(e) = campaign(&database_url, &mut conn, &order_service).await {
+            error!("{:#}", e);
+            order_service.stop();
+            // Closing the connection releases the lock if it is still held
+            conn = None;
+        }
+        sleep(ELECTION_INTERVAL).await;
+    }
+}
+
+// Returns Ok if another instance holds the lock, otherwise leads until the connection fails
+async fn campaign(database_url: &str, conn: &mut Option<PgConnection>, order_service: &OrderServiceImpl) -> Result<()> {
+    let conn = match conn {
+        Some(conn) => conn,
+        None => conn.insert(PgConnection::connect(database_url).await.context("Failed to connect for leader election")?),
+    };
+    let is_locked = sqlx::query_scalar!(r#"SELECT pg_try_advisory_lock($1) AS "is_locked!""#, ENGINE_LOCK_KEY)
+        .fetch_one(&mut *conn).await.context("Failed to try the leader lock")?;
+    if !is_locked {
+        return Ok(());
+    }
+    // Transactions of the previous leader are fenced from now on
+    let epoch = sqlx::query_scalar!("UPDATE engine_leader SET epoch = epoch + 1, elected_at = CURRENT_TIMESTAMP WHERE id = 1 RETURNING epoch")
+        .fetch_one(&mut *conn).await.context("Failed to advance the leader epoch")?;
+    info!("Elected as leader with epoch {}, rebuilding order books", epoch);
+    order_service.start(epoch).await?;
+    info!("Matching engine is running");
+    loop {
+        sleep(ELECTION_INTERVAL).await;
+        conn.ping().await.context("Lost the connection holding the leader lock, stepping down")?;
+    }
+}
@@ -1,12 +1,17 @@
 use std::sync::{Arc};
-use actix_web::{web, get, post, delete, App, HttpResponse, HttpServer, Responder, middleware};
+use actix_web::{web, get, post, put, delete, patch, App, HttpRequest, HttpResponse, HttpServer, Responder, FromRequest, middleware, dev::Payload};
 use log::{info, error};
+use anyhow::{anyhow, Context};
 use envconfig::Envconfig;
 use dotenv::dotenv;
 use sqlx::{postgres::PgPoolOptions};
-use serde::Deserialize;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
 mod ports;
+mod error;
+mod problem;
 mod order_service;
 mod card;
 mod order_manager;
@@ -15,101 +20,1197 @@ mod trader_store;
 mod order_store;
 mod trade_store;
 mod graphql;
+mod openapi;
+mod v1;
+mod rate_limit;
+mod replay;
+mod leader;
+mod session;
+mod risk;
+mod auth;
+mod outbox;
+mod outbox_store;
 
+use auth::{Caller, Permission};
 use config::Config;
+use error::Error;
+use problem::Problem;
+use ports::Order;
 use ports::{TraderStore, OrderStore, TradeStore, OrderService};
 use trader_store::PostgresTraderStoreImpl;
 use order_store::PostgresOrderStoreImpl;
 use trade_store::PostgresTradeStoreImpl;
 
-#[get("/api/traders/{id}/orders")]
-async fn get_orders(order_store: web::Data<PostgresOrderStoreImpl>, trader_store: web::Data<PostgresTraderStoreImpl>, path: web::Path<i64>) -> impl Responder {
-    let trader_id = path.into_inner();
-    let is_trader_exist = trader_store.is_exist(trader_id).await;
-    if is_trader_exist.is_none() {
-        return HttpResponse::InternalServerError().body("Failed to query trader");
+async fn check_trader(trader_store: &PostgresTraderStoreImpl, trader_id: i64) -> Result<(), Error> {
+    match trader_store.is_exist(trader_id).await {
+        Some(true) => Ok(()),
+        Some(false) => Err(Error::TraderNotFound),
+        None => Err(anyhow!("Failed to query trader: {}", trader_id).into()),
     }
-    if !is_trader_exist.unwrap() {
-        return HttpResponse::BadRequest().body("Trader does not exist");
+}
+
+// Identity of whoever takes an admin action, from the X-Operator header, written to the audit log,
+// the caller must be an admin
+struct Operator(String);
+
+impl FromRequest for Operator {
+    type Error = Error;
+    type Future = futures::future::LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let caller = Caller::from_request(req, payload);
+        let operator = req.headers().get("X-Operator")
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|operator| !operator.is_empty() && operator.len() <= 64)
+            .map(str::to_string);
+        Box::pin(async move {
+            caller.await?.authorize(Permission::Admin)?;
+            operator.map(Operator).ok_or(Error::OperatorRequired)
+        })
     }
-    let r = order_store.query_orders(trader_id, Some(50)).await;
-    match r {
-        Ok(orders) => HttpResponse::Ok().json(orders),
-        Err(e) => {
-            error!("Failed to query orders: {}", e);
-            HttpResponse::InternalServerError().body("Failed to query orders")
-        },
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Latest 50 orders of the trader", body = [Order]),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is neither the trader nor support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/traders/{id}/orders")]
+async fn get_orders(order_store: web::Data<PostgresOrderStoreImpl>, trader_store: web::Data<PostgresTraderStoreImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    caller.authorize(Permission::ReadTrader(trader_id))?;
+    check_trader(&trader_store, trader_id).await?;
+    let orders = order_store.query_orders(trader_id, Some(50)).await.context("Failed to query orders")?;
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "trades",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Latest 50 trades of the trader", body = [TraderTrade]),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is neither the trader nor support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/traders/{id}/trades")]
+async fn get_trader_trades(trade_store: web::Data<PostgresTradeStoreImpl>, trader_store: web::Data<PostgresTraderStoreImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    caller.authorize(Permission::ReadTrader(trader_id))?;
+    check_trader(&trader_store, trader_id).await?;
+    let trades = trade_store.query_trader_trades(trader_id, Some(50)).await.context("Failed to query trader trades")?;
+    Ok(HttpResponse::Ok().json(trades))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "trades",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "All trades of the trader", body = String, content_type = "text/csv"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is neither the trader nor support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/traders/{id}/trades.csv")]
+async fn export_trader_trades(trade_store: web::Data<PostgresTradeStoreImpl>, trader_store: web::Data<PostgresTraderStoreImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    caller.authorize(Permission::ReadTrader(trader_id))?;
+    check_trader(&trader_store, trader_id).await?;
+    // Bookkeeping wants the full history instead of the latest page
+    let trades = trade_store.query_trader_trades(trader_id, Some(i64::MAX)).await.context("Failed to query trader trades")?;
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for trade in &trades {
+        writer.serialize(trade).context("Failed to write trades csv")?;
     }
+    let body = writer.into_inner().map_err(|e| anyhow!("Failed to write trades csv: {}", e))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"trader-{}-trades.csv\"", trader_id)))
+        .body(body))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct OrderRequest {
+    /// buy or sell
+    #[schema(example = "buy")]
     side: String,
-    price: i32,
+    /// Limit price in cents within the price range of the card and a multiple of its tick size, not allowed for stop_market
+    price: Option<i32>,
     card_id: i32,
+    /// At least the minimum quantity of the card, a multiple of its lot size and at most 1000000, defaults to 1
+    quantity: Option<i32>,
+    /// Unique per trader, resubmission returns the original order
+    client_order_id: Option<String>,
+    /// What happens when the order would trade with an order of the same trader:
+    /// cancel_newest (default), cancel_oldest, cancel_both or decrement
+    #[schema(example = "cancel_newest")]
+    self_trade_prevention: Option<String>,
+    /// limit (default), stop_market or stop_limit
+    #[schema(example = "limit")]
+    order_type: Option<String>,
+    /// Stop orders enter the book once a trade is at or above it for buy, at or below it for sell, same rules as the price
+    stop_price: Option<i32>,
+    /// Makes an iceberg order showing at most this much in the book, 1 to quantity, not allowed for stop_market and hidden orders
+    display_quantity: Option<i32>,
+    /// The order never takes liquidity, when it would it's cancelled (reject) or priced one tick behind the best opposite price (reprice)
+    #[schema(example = "reject")]
+    post_only: Option<String>,
+    /// Rests without showing in the depth, behind the displayed orders of the same price, defaults to false
+    hidden: Option<bool>,
+    /// gtc (default) or day, day orders are cancelled when the market closes
+    #[schema(example = "gtc")]
+    time_in_force: Option<String>,
 }
 
 type OrderServiceImpl = order_service::OrderServiceImpl<
     trader_store::PostgresTraderStoreImpl,
-    order_store::PostgresOrderStoreImpl,
-    trade_store::PostgresTradeStoreImpl>;
+    order_store::PostgresOrderStoreImpl>;
+
+const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+
+impl OrderRequest {
+    fn validate(self) -> Result<ports::PlaceOrder, Error> {
+        let side = ports::Action::from_str(&self.side).ok_or(Error::InvalidSide)?;
+        let order_type = match &self.order_type {
+            Some(order_type) => ports::OrderType::from_str(order_type).ok_or(Error::InvalidOrderType)?,
+            None => ports::OrderType::default(),
+        };
+        let stop_price = match (order_type, self.stop_price) {
+            (ports::OrderType::Limit, None) => None,
+            (ports::OrderType::StopMarket | ports::OrderType::StopLimit, Some(stop_price)) => Some(stop_price),
+            _ => return Err(Error::InvalidStopPrice),
+        };
+        // A stop market order has no limit, it's stored with its stop price
+        let price = match (order_type, self.price, stop_price) {
+            (ports::OrderType::StopMarket, None, Some(stop_price)) => stop_price,
+            (ports::OrderType::Limit | ports::OrderType::StopLimit, Some(price), _) => price,
+            _ => return Err(Error::InvalidPrice),
+        };
+        // Quantities are checked against the card by the order service
+        let quantity = self.quantity.unwrap_or(1);
+        let hidden = self.hidden.unwrap_or(false);
+        let post_only = match &self.post_only {
+            Some(post_only) if order_type != ports::OrderType::StopMarket => ports::PostOnly::from_str(post_only).ok_or(Error::InvalidPostOnly)?,
+            Some(_) => return Err(Error::InvalidPostOnly),
+            None => ports::PostOnly::default(),
+        };
+        if let Some(client_order_id) = &self.client_order_id {
+            if client_order_id.is_empty() || client_order_id.len() > MAX_CLIENT_ORDER_ID_LEN {
+                return Err(Error::InvalidClientOrderId);
+            }
+        }
+        let self_trade_prevention = match &self.self_trade_prevention {
+            Some(self_trade_prevention) => ports::SelfTradePrevention::from_str(self_trade_prevention).ok_or(Error::InvalidSelfTradePrevention)?,
+            None => ports::SelfTradePrevention::default(),
+        };
+        let time_in_force = match &self.time_in_force {
+            Some(time_in_force) => ports::TimeInForce::from_str(time_in_force).ok_or(Error::InvalidTimeInForce)?,
+            None => ports::TimeInForce::default(),
+        };
+        Ok(ports::PlaceOrder {
+            card_id: self.card_id,
+            side,
+            price,
+            quantity,
+            client_order_id: self.client_order_id,
+            self_trade_prevention,
+            order_type,
+            stop_price,
+            display_quantity: self.display_quantity,
+            post_only,
+            hidden,
+            time_in_force,
+        })
+    }
+}
 
-#[post("/api/traders/{id}/orders")]
-async fn add_order(order_service: web::Data<OrderServiceImpl>, path: web::Path<i64>, req_body: web::Json<OrderRequest>) -> impl Responder {
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    request_body = OrderRequest,
+    responses(
+        (status = 200, description = "The placed order, or the order placed first when client_order_id was already used", body = Order),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Trader is suspended or caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/traders/{id}/orders")]
+async fn add_order(order_service: web::Data<OrderServiceImpl>, caller: Caller, path: web::Path<i64>, req_body: web::Json<OrderRequest>) -> Result<HttpResponse, Error> {
     let trader_id = path.into_inner();
-    let side = ports::Action::from_str(&req_body.side);
-    if side.is_none() {
-        return HttpResponse::BadRequest().body("Invalid order side");
+    caller.authorize(Permission::Trade(trader_id))?;
+    let order = req_body.into_inner().validate()?;
+    info!("Received order request: {:?} card={} price={} quantity={} client_order_id={:?}", &order.side, &order.card_id, order.price, order.quantity, &order.client_order_id);
+
+    let order = order_service.add_order(trader_id, order).await?;
+    Ok(HttpResponse::Ok().json(order))
+}
+
+const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+struct BatchOrderRequest {
+    /// 1 to 100 orders
+    orders: Vec<OrderRequest>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchOrderResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<Order>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    request_body = BatchOrderRequest,
+    responses(
+        (status = 200, description = "Results in the same order as the request, an order of a failed card has its own error while the other cards are placed", body = [BatchOrderResult]),
+        (status = 400, description = "Empty or too large batch", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Trader is suspended or caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/traders/{id}/orders/batch")]
+async fn add_orders(order_service: web::Data<OrderServiceImpl>, caller: Caller, path: web::Path<i64>, req_body: web::Json<BatchOrderRequest>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    caller.authorize(Permission::Trade(trader_id))?;
+    let req_body = req_body.into_inner();
+    if req_body.orders.is_empty() || req_body.orders.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidBatchSize);
+    }
+    // Invalid items are answered one by one, the rest are placed
+    let mut results = Vec::with_capacity(req_body.orders.len());
+    let mut orders = Vec::new();
+    for order in req_body.orders {
+        match order.validate() {
+            Ok(order) => {
+                orders.push(order);
+                results.push(BatchOrderResult { order: None, error: None });
+            },
+            Err(e) => results.push(BatchOrderResult { order: None, error: Some(Problem::from(&e)) }),
+        }
+    }
+    info!("Received batch order request: trader={} orders={}", trader_id, orders.len());
+
+    if !orders.is_empty() {
+        let placed_orders = order_service.add_orders(trader_id, orders).await?;
+        let pending_results = results.iter_mut().filter(|result| result.error.is_none());
+        for (result, placed_order) in pending_results.zip(placed_orders) {
+            match placed_order {
+                Ok(order) => result.order = Some(order),
+                Err(e) => result.error = Some(Problem::from(&e)),
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CancelOrdersQuery {
+    card_id: Option<i32>,
+    /// buy or sell
+    side: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CancelOrdersResponse {
+    cancelled_order_ids: Vec<i64>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    params(CancelOrdersQuery),
+    responses(
+        (status = 200, description = "Cancelled orders", body = CancelOrdersResponse),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/traders/{id}/orders")]
+async fn delete_orders(order_service: web::Data<OrderServiceImpl>, caller: Caller, path: web::Path<i64>, query: web::Query<CancelOrdersQuery>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    caller.authorize(Permission::Trade(trader_id))?;
+    let side = match &query.side {
+        Some(side) => Some(ports::Action::from_str(side).ok_or(Error::InvalidSide)?),
+        None => None,
+    };
+    if matches!(query.card_id, Some(card_id) if !card::is_valid(card_id)) {
+        return Err(Error::InvalidCardId);
+    }
+    info!("Received cancel all request: trader={} card={:?} side={:?}", trader_id, query.card_id, &side);
+
+    let cancelled_order_ids = order_service.cancel_orders(trader_id, query.card_id, side).await?;
+    Ok(HttpResponse::Ok().json(CancelOrdersResponse { cancelled_order_ids }))
+}
+
+// Orders of other traders are reported as not found
+async fn query_trader_order(order_store: &PostgresOrderStoreImpl, trader_id: i64, order_id: i64) -> Result<Order, Error> {
+    match order_store.query_order(order_id).await.context("Failed to query order")? {
+        Some(order) if order.trader_id == trader_id => Ok(order),
+        _ => Err(Error::OrderNotFound),
     }
-    if req_body.price < 100 || req_body.price > 1000 {
-        return HttpResponse::BadRequest().body("Price must be in the range of 100 to 1000 cents");
+}
+
+async fn query_order_by_client_id(order_store: &PostgresOrderStoreImpl, trader_id: i64, client_order_id: &str) -> Result<Order, Error> {
+    order_store.query_order_by_client_id(trader_id, client_order_id).await.context("Failed to query order")?
+        .ok_or(Error::OrderNotFound)
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    responses(
+        (status = 204, description = "Cancelled"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Order not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Order is not pending", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/traders/{id}/orders/{order_id}")]
+async fn delete_order(order_service: web::Data<OrderServiceImpl>, order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, path: web::Path<(i64, i64)>) -> Result<HttpResponse, Error> {
+    let (trader_id, order_id) = path.into_inner();
+    caller.authorize(Permission::Trade(trader_id))?;
+    let order = query_trader_order(&order_store, trader_id, order_id).await?;
+    order_service.cancel_order(&order).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AmendOrderRequest {
+    price: Option<i32>,
+    /// New total quantity including the filled part
+    quantity: Option<i32>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    request_body = AmendOrderRequest,
+    responses(
+        (status = 200, description = "The amended order", body = Order),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Trader is suspended or caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Order not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Order is not pending", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[patch("/traders/{id}/orders/{order_id}")]
+async fn amend_order(order_service: web::Data<OrderServiceImpl>, order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, path: web::Path<(i64, i64)>, req_body: web::Json<AmendOrderRequest>) -> Result<HttpResponse, Error> {
+    let (trader_id, order_id) = path.into_inner();
+    caller.authorize(Permission::Trade(trader_id))?;
+    if req_body.price.is_none() && req_body.quantity.is_none() {
+        return Err(Error::EmptyAmendment);
     }
-    if !card::is_valid(req_body.card_id) {
-        return HttpResponse::BadRequest().body("Invalid card id");
+    let order = query_trader_order(&order_store, trader_id, order_id).await?;
+    if matches!(req_body.quantity, Some(quantity) if quantity <= order.filled_quantity) {
+        return Err(Error::QuantityNotAboveFilled);
     }
-    let side = side.unwrap();
-    info!("Received order request: {:?} card={} price={}", &side, &req_body.card_id, req_body.price);
+    info!("Received amend request: order={} price={:?} quantity={:?}", order_id, req_body.price, req_body.quantity);
+
+    let order = order_service.amend_order(&order, req_body.price, req_body.quantity).await?;
+    Ok(HttpResponse::Ok().json(order))
+}
 
-    let r = order_service.add_order(trader_id, side, req_body.price, req_body.card_id).await;
-    match r {
-        Ok(_) => {
-            HttpResponse::Ok().body("")
-        },
-        Err(e) => {
-            error!("Failed to add order: {}", e);
-            HttpResponse::InternalServerError().body("Failed to add order")
-        },
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "The order", body = Order),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is neither the trader nor support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Order not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/traders/{id}/orders/by-client-id/{client_order_id}")]
+async fn get_order_by_client_id(order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, path: web::Path<(i64, String)>) -> Result<HttpResponse, Error> {
+    let (trader_id, client_order_id) = path.into_inner();
+    caller.authorize(Permission::ReadTrader(trader_id))?;
+    let order = query_order_by_client_id(&order_store, trader_id, &client_order_id).await?;
+    Ok(HttpResponse::Ok().json(order))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    responses(
+        (status = 204, description = "Cancelled"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not the trader", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Order not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Order is not pending", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/traders/{id}/orders/by-client-id/{client_order_id}")]
+async fn delete_order_by_client_id(order_service: web::Data<OrderServiceImpl>, order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, path: web::Path<(i64, String)>) -> Result<HttpResponse, Error> {
+    let (trader_id, client_order_id) = path.into_inner();
+    caller.authorize(Permission::Trade(trader_id))?;
+    let order = query_order_by_client_id(&order_store, trader_id, &client_order_id).await?;
+    order_service.cancel_order(&order).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "orders",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "What happened to the order, oldest first", body = [OrderEvent]),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Order not found, also for orders of other traders unless the caller is support or admin", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/orders/{id}/events")]
+async fn get_order_events(order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let order_id = path.into_inner();
+    let order = order_store.query_order(order_id).await.context("Failed to query order")?;
+    match order {
+        Some(order) if caller.permits(Permission::ReadTrader(order.trader_id)) => {},
+        _ => return Err(Error::OrderNotFound),
     }
+    let events = order_store.query_order_events(order_id).await.context("Failed to query order events")?;
+    Ok(HttpResponse::Ok().json(events))
 }
 
-#[delete("/api/traders/{id}/orders/{order_id}")]
-async fn delete_order() -> impl Responder {
-    // TODO: delete order
-    // TODO: can only delete unfilled order
-    HttpResponse::NoContent().body("")
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Cards and their trading rules", body = [Card]),
+    )
+)]
+#[get("/cards")]
+async fn get_cards() -> impl Responder {
+    HttpResponse::Ok().json(card::CATALOG)
 }
 
-#[get("/api/cards/{id}/trades")]
-async fn get_trades(trade_store: web::Data<PostgresTradeStoreImpl>, path: web::Path<i32>) -> impl Responder {
-    let card_id = path.into_inner();    
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "trades",
+    responses(
+        (status = 200, description = "Latest 50 trades of the card", body = [Trade]),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/cards/{id}/trades")]
+async fn get_trades(trade_store: web::Data<PostgresTradeStoreImpl>, path: web::Path<i32>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
     if !card::is_valid(card_id) {
-        return HttpResponse::NotFound().body("Card not found");
+        return Err(Error::CardNotFound);
+    }
+    let trades = trade_store.query_trades(card_id, Some(50)).await.context("Failed to query trades")?;
+    Ok(HttpResponse::Ok().json(trades))
+}
+
+const MAX_DEPTH_LEVELS: usize = 50;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DepthQuery {
+    /// Price levels per side, 1 to 50, defaults to 10
+    levels: Option<usize>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    params(DepthQuery),
+    responses(
+        (status = 200, description = "Visible quantity of the best price levels, hidden reserves of iceberg orders and stop orders are not included", body = Depth),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/cards/{id}/depth")]
+async fn get_depth(order_service: web::Data<OrderServiceImpl>, path: web::Path<i32>, query: web::Query<DepthQuery>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
+    if !card::is_valid(card_id) {
+        return Err(Error::CardNotFound);
+    }
+    let levels = query.levels.unwrap_or(10);
+    if !(1..=MAX_DEPTH_LEVELS).contains(&levels) {
+        return Err(Error::InvalidRequest(format!("levels must be in the range of 1 to {}", MAX_DEPTH_LEVELS)));
+    }
+    Ok(HttpResponse::Ok().json(order_service.depth(card_id, levels).await?))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Trading state and price band of the card", body = CardStatus),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/cards/{id}/status")]
+async fn get_card_status(order_service: web::Data<OrderServiceImpl>, path: web::Path<i32>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(order_service.card_status(path.into_inner()).await?))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Call auctions of the card, latest opening first", body = [CallAuction]),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/cards/{id}/auctions")]
+async fn get_call_auctions(order_store: web::Data<PostgresOrderStoreImpl>, path: web::Path<i32>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
+    if !card::is_valid(card_id) {
+        return Err(Error::CardNotFound);
+    }
+    let auctions = order_store.query_call_auctions(card_id).await.context("Failed to query call auctions")?;
+    Ok(HttpResponse::Ok().json(auctions))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CallAuctionRequest {
+    /// Defaults to now
+    opens_at: Option<chrono::DateTime<chrono::Utc>>,
+    closes_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    request_body = CallAuctionRequest,
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 200, description = "The scheduled call auction, orders of the card are collected without matching from opens_at and uncrossed at closes_at", body = CallAuction),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Another call auction of the card is scheduled in the window", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[post("/admin/cards/{id}/auctions")]
+async fn create_call_auction(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i32>, req_body: web::Json<CallAuctionRequest>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
+    let opens_at = req_body.opens_at.unwrap_or_else(chrono::Utc::now);
+    info!("Received call auction request: operator={} card={} opens_at={} closes_at={}", operator.0, card_id, opens_at, req_body.closes_at);
+    let auction = order_service.schedule_call_auction(&operator.0, card_id, opens_at, req_body.closes_at).await?;
+    Ok(HttpResponse::Ok().json(auction))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Session phase and best prices of the card", body = Ticker),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/cards/{id}/ticker")]
+async fn get_ticker(order_service: web::Data<OrderServiceImpl>, path: web::Path<i32>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(order_service.ticker(path.into_inner()).await?))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Phase of the trading session", body = SessionStatus),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/session")]
+async fn get_session(order_service: web::Data<OrderServiceImpl>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(order_service.session_status().await?))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Server-sent events starting with the current session, then session events when the phase changes \
+            and ticker events when a book or the phase changes, the data is JSON with a type field", content_type = "text/event-stream", body = String),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/market-data")]
+async fn get_market_data(order_service: web::Data<OrderServiceImpl>) -> Result<HttpResponse, Error> {
+    let receiver = order_service.subscribe();
+    let session = ports::MarketDataEvent::Session(order_service.session_status().await?);
+    let event = |event: ports::MarketDataEvent| -> Result<web::Bytes, actix_web::Error> {
+        let data = serde_json::to_string(&event).map_err(actix_web::error::ErrorInternalServerError)?;
+        Ok(web::Bytes::from(format!("data: {}\n\n", data)))
+    };
+    // Events a slow subscriber missed are skipped
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = futures::stream::once(async { session }).chain(events).map(event);
+    Ok(HttpResponse::Ok().content_type("text/event-stream").streaming(events))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "market data",
+    responses(
+        (status = 200, description = "Market closures which haven't ended, in order of their start", body = [MarketClosure]),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[get("/closures")]
+async fn get_closures(order_store: web::Data<PostgresOrderStoreImpl>) -> Result<HttpResponse, Error> {
+    let closures = order_store.query_market_closures(chrono::Utc::now()).await.context("Failed to query market closures")?;
+    Ok(HttpResponse::Ok().json(closures))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ClosureRequest {
+    /// holiday or maintenance
+    #[schema(example = "holiday")]
+    kind: String,
+    /// Defaults to now
+    starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    ends_at: chrono::DateTime<chrono::Utc>,
+    reason: Option<String>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    request_body = ClosureRequest,
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 200, description = "The scheduled closure, orders are rejected from starts_at until ends_at", body = MarketClosure),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+#[post("/admin/closures")]
+async fn create_closure(order_service: web::Data<OrderServiceImpl>, operator: Operator, req_body: web::Json<ClosureRequest>) -> Result<HttpResponse, Error> {
+    let kind = ports::ClosureKind::from_str(&req_body.kind).ok_or_else(|| Error::InvalidRequest("kind must be holiday or maintenance".to_string()))?;
+    let starts_at = req_body.starts_at.unwrap_or_else(chrono::Utc::now);
+    info!("Received closure request: operator={} kind={:?} starts_at={} ends_at={}", operator.0, kind, starts_at, req_body.ends_at);
+    let closure = order_service.schedule_closure(&operator.0, kind, starts_at, req_body.ends_at, req_body.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::Ok().json(closure))
+}
+
+#[derive(Serialize, ToSchema)]
+struct CardSnapshot {
+    card_id: i32,
+    /// Journal sequence included in the snapshot
+    sequence: i64,
+    /// Number of resting orders
+    orders: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SnapshotResponse {
+    snapshots: Vec<CardSnapshot>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 200, description = "Books are snapshotted, cards unchanged since their last snapshot are not written again", body = SnapshotResponse),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded, see Retry-After", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds to wait"))),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/snapshots")]
+async fn create_snapshots(order_service: web::Data<OrderServiceImpl>, operator: Operator) -> Result<HttpResponse, Error> {
+    info!("Received snapshot request: operator={}", operator.0);
+    let snapshots: Vec<CardSnapshot> = order_service.snapshot().await?.into_iter()
+        .map(|snapshot| CardSnapshot { card_id: snapshot.card_id, sequence: snapshot.sequence, orders: snapshot.orders.len() })
+        .collect();
+    order_service.audit(ports::NewAuditEntry {
+        operator: operator.0,
+        action: ports::AdminAction::Snapshot,
+        card_id: None,
+        trader_id: None,
+        details: serde_json::json!({ "snapshots": snapshots }),
+    }).await?;
+    Ok(HttpResponse::Ok().json(SnapshotResponse { snapshots }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdminActionQuery {
+    /// Written to the audit log
+    reason: Option<String>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Orders of every card are rejected until the market is resumed, cancellations are accepted"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/halt")]
+async fn halt_market(order_service: web::Data<OrderServiceImpl>, operator: Operator, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    info!("Received market halt request: operator={}", operator.0);
+    order_service.set_trading_halt(&operator.0, None, true, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Market is resumed, cards halted on their own stay halted"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/admin/halt")]
+async fn resume_market(order_service: web::Data<OrderServiceImpl>, operator: Operator, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    info!("Received market resume request: operator={}", operator.0);
+    order_service.set_trading_halt(&operator.0, None, false, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Orders of the card are rejected until it's resumed, cancellations are accepted and call auctions wait"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/cards/{id}/halt")]
+async fn halt_card(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i32>, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
+    info!("Received card halt request: operator={} card={}", operator.0, card_id);
+    order_service.set_trading_halt(&operator.0, Some(card_id), true, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Card is resumed unless the whole market is halted"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Card not found", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/admin/cards/{id}/halt")]
+async fn resume_card(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i32>, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    let card_id = path.into_inner();
+    info!("Received card resume request: operator={} card={}", operator.0, card_id);
+    order_service.set_trading_halt(&operator.0, Some(card_id), false, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Halts by operations, a halt without card_id is of the whole market", body = [TradingHalt]),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not support or admin", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/halts")]
+async fn get_halts(order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller) -> Result<HttpResponse, Error> {
+    caller.authorize(Permission::ReadAdmin)?;
+    let halts = order_store.query_trading_halts().await.context("Failed to query trading halts")?;
+    Ok(HttpResponse::Ok().json(halts))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Orders of the trader are rejected until it's reinstated, its open orders stay"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/traders/{id}/suspension")]
+async fn suspend_trader(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    info!("Received suspension request: operator={} trader={}", operator.0, trader_id);
+    order_service.set_trader_suspension(&operator.0, trader_id, true, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Trader is reinstated"),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/admin/traders/{id}/suspension")]
+async fn reinstate_trader(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    info!("Received reinstatement request: operator={} trader={}", operator.0, trader_id);
+    order_service.set_trader_suspension(&operator.0, trader_id, false, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Suspended traders", body = [TraderSuspension]),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not support or admin", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/suspensions")]
+async fn get_suspensions(order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller) -> Result<HttpResponse, Error> {
+    caller.authorize(Permission::ReadAdmin)?;
+    let suspensions = order_store.query_trader_suspensions().await.context("Failed to query trader suspensions")?;
+    Ok(HttpResponse::Ok().json(suspensions))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AdminActionQuery, ("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 200, description = "Cancelled orders of the trader across every card, regardless of its rate limit", body = CancelOrdersResponse),
+        (status = 400, description = "X-Operator header is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Not the leader instance, retry later", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/admin/traders/{id}/orders")]
+async fn cancel_trader_orders(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>, query: web::Query<AdminActionQuery>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    info!("Received mass cancel request: operator={} trader={}", operator.0, trader_id);
+    let cancelled_order_ids = order_service.cancel_trader_orders(&operator.0, trader_id, query.reason.as_deref().unwrap_or("")).await?;
+    Ok(HttpResponse::Ok().json(CancelOrdersResponse { cancelled_order_ids }))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Overrides of the configured risk limits, null keeps the default and 0 disables the limit", body = TraderRiskLimits),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/traders/{id}/risk-limits")]
+async fn get_risk_limits(order_service: web::Data<OrderServiceImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    caller.authorize(Permission::ReadAdmin)?;
+    let limits = order_service.risk_limits(path.into_inner()).await?.unwrap_or_default();
+    Ok(HttpResponse::Ok().json(limits))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    request_body = TraderRiskLimits,
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Overrides are replaced, every instance checks orders against them within a second"),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/admin/traders/{id}/risk-limits")]
+async fn put_risk_limits(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>, req_body: web::Json<ports::TraderRiskLimits>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    let limits = req_body.into_inner();
+    let values = [limits.max_order_value, limits.max_open_orders.map(i64::from), limits.max_card_notional, limits.max_position.map(i64::from), limits.max_daily_volume.map(i64::from)];
+    if values.iter().flatten().any(|value| *value < 0) {
+        return Err(Error::InvalidRequest("Risk limits must not be negative".to_string()));
     }
-    let r = trade_store.query_trades(card_id, Some(50)).await;
-    match r {
-        Ok(r) => HttpResponse::Ok().json(r),
-        Err(e) => {
-            error!("Failed to query trades: {}", e);
-            HttpResponse::InternalServerError().body("Failed to query trades")
-        },
+    info!("Received risk limits request: operator={} trader={} limits={:?}", operator.0, trader_id, limits);
+    order_service.set_risk_limits(&operator.0, trader_id, limits).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, ToSchema)]
+struct RoleRequest {
+    /// trader, market_maker, support or admin
+    #[schema(example = "support")]
+    role: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RoleResponse {
+    role: ports::Role,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Role of the trader", body = RoleResponse),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not support or admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/traders/{id}/role")]
+async fn get_role(trader_store: web::Data<PostgresTraderStoreImpl>, caller: Caller, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    caller.authorize(Permission::ReadAdmin)?;
+    let role = trader_store.query_role(path.into_inner()).await.context("Failed to query role")?.ok_or(Error::TraderNotFound)?;
+    Ok(HttpResponse::Ok().json(RoleResponse { role }))
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    request_body = RoleRequest,
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 204, description = "Role is changed, the next request of the trader is authorized with it"),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/admin/traders/{id}/role")]
+async fn put_role(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>, req_body: web::Json<RoleRequest>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    let role = ports::Role::from_str(&req_body.role).ok_or_else(|| Error::InvalidRequest("role must be trader, market_maker, support or admin".to_string()))?;
+    info!("Received role request: operator={} trader={} role={:?}", operator.0, trader_id, role);
+    order_service.set_role(&operator.0, trader_id, role).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiKeyResponse {
+    /// Only shown once, the previous key of the trader stops working
+    api_key: String,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(("X-Operator" = String, Header, description = "Operator identity for the audit log")),
+    responses(
+        (status = 200, description = "New API key of the trader", body = ApiKeyResponse),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not an admin", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trader does not exist", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/admin/traders/{id}/api-key")]
+async fn issue_api_key(order_service: web::Data<OrderServiceImpl>, operator: Operator, path: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let trader_id = path.into_inner();
+    info!("Received API key request: operator={} trader={}", operator.0, trader_id);
+    let api_key = order_service.issue_api_key(&operator.0, trader_id).await?;
+    Ok(HttpResponse::Ok().json(ApiKeyResponse { api_key }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuditLogQuery {
+    /// 1 to 1000, defaults to 100
+    limit: Option<i64>,
+}
+
+#[utoipa::path(
+    context_path = "/api/v2",
+    tag = "admin",
+    security(("api_key" = [])),
+    params(AuditLogQuery),
+    responses(
+        (status = 200, description = "Admin actions, latest first", body = [AuditEntry]),
+        (status = 400, description = "Invalid argument", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "API key is missing or unknown", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Caller is not support or admin", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/admin/audit-log")]
+async fn get_audit_log(order_store: web::Data<PostgresOrderStoreImpl>, caller: Caller, query: web::Query<AuditLogQuery>) -> Result<HttpResponse, Error> {
+    caller.authorize(Permission::ReadAdmin)?;
+    let limit = query.limit.unwrap_or(100);
+    if !(1..=1000).contains(&limit) {
+        return Err(Error::InvalidRequest("limit must be 1 to 1000".to_string()));
     }
+    let entries = order_store.query_audit_log(limit).await.context("Failed to query audit log")?;
+    Ok(HttpResponse::Ok().json(entries))
 }
 
+#[utoipa::path(
+    tag = "health",
+    responses(
+        (status = 200, description = "Server is alive", body = String, content_type = "text/plain"),
+    )
+)]
 #[get("/api/health")]
 async fn health() -> impl Responder {
     HttpResponse::Ok().body("alive")
 }
 
+fn configure_v2(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_orders)
+        .service(add_order)
+        .service(add_orders)
+        .service(delete_orders)
+        .service(delete_order)
+        .service(amend_order)
+        .service(get_order_by_client_id)
+        .service(delete_order_by_client_id)
+        .service(get_order_events)
+        .service(get_cards)
+        .service(get_trades)
+        .service(get_depth)
+        .service(get_card_status)
+        .service(get_ticker)
+        .service(get_session)
+        .service(get_market_data)
+        .service(get_closures)
+        .service(get_call_auctions)
+        .service(export_trader_trades)
+        .service(get_trader_trades)
+        .service(create_snapshots)
+        .service(create_call_auction)
+        .service(create_closure)
+        .service(halt_market)
+        .service(resume_market)
+        .service(halt_card)
+        .service(resume_card)
+        .service(get_halts)
+        .service(suspend_trader)
+        .service(reinstate_trader)
+        .service(get_suspensions)
+        .service(cancel_trader_orders)
+        .service(get_risk_limits)
+        .service(put_risk_limits)
+        .service(get_role)
+        .service(put_role)
+        .service(issue_api_key)
+        .service(get_audit_log);
+}
+
+// Unversioned routes are an alias of v1, deprecated since 2026-10-19 (RFC 9745 date)
+const V1_ALIAS_DEPRECATION: &str = "@1792368000";
+
+fn v1_alias_headers(sunset: &str) -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new()
+        .add(("Deprecation", V1_ALIAS_DEPRECATION))
+        .add(("Sunset", sunset.to_string()))
+        .add(("Link", "</api/v1>; rel=\"successor-version\""))
+}
+
+// Issues a key without an operator, so there is nothing to audit
+async fn issue_api_key_of(order_store: &PostgresOrderStoreImpl, trader_id: i64) -> anyhow::Result<Option<String>> {
+    let mut tx = order_store.begin().await?;
+    let api_key = tx.issue_api_key(trader_id).await?;
+    tx.commit().await?;
+    Ok(api_key)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
@@ -122,27 +1223,206 @@ async fn main() -> std::io::Result<()> {
 
     let trader_store = trader_store::PostgresTraderStoreImpl{pg_pool: pool.clone()};
     let order_store = order_store::PostgresOrderStoreImpl{pg_pool: pool.clone()};
-    let trade_store = trade_store::PostgresTradeStoreImpl{pg_pool: pool.clone()};
+    let trade_store = trade_store::PostgresTradeStoreImpl{pg_pool: pool.clone(), counterparty_salt: config.counterparty_salt().expect("Load counterparty salt failed").to_string()};
+    let fee_schedule = order_service::FeeSchedule{maker_bps: config.maker_fee_bps, taker_bps: config.taker_fee_bps};
+    let price_limits = order_service::PriceLimits {
+        band_bps: config.price_band_bps,
+        breaker_bps: config.circuit_breaker_bps,
+        breaker_window: std::time::Duration::from_secs(config.circuit_breaker_window_secs),
+        halt_duration: std::time::Duration::from_secs(config.halt_auction_secs),
+    };
+    let rate_limits = Arc::new(rate_limit::RateLimits::new(rate_limit::RateLimitConfig {
+        trader: rate_limit::Limit{per_second: config.rate_limit_trader_per_second, burst: config.rate_limit_trader_burst},
+        ip: rate_limit::Limit{per_second: config.rate_limit_ip_per_second, burst: config.rate_limit_ip_burst},
+        order: rate_limit::Limit{per_second: config.rate_limit_order_per_second, burst: config.rate_limit_order_burst},
+        trust_forwarded_for: config.rate_limit_trust_forwarded_for,
+    }, Arc::new(trader_store.clone())));
+    // `pokemon_trading verify-journal` checks that replaying the journal gives the stored orders and trades
+    if std::env::args().nth(1).as_deref() == Some("verify-journal") {
+        let mismatches = replay::verify(&order_store, &trade_store).await.map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        println!("{} mismatches", mismatches.len());
+        std::process::exit(if mismatches.is_empty() { 0 } else { 1 });
+    }
+    // `pokemon_trading issue-api-key <trader id>` prints a new API key of the trader, for the first admin
+    if std::env::args().nth(1).as_deref() == Some("issue-api-key") {
+        let trader_id = std::env::args().nth(2).and_then(|id| id.parse::<i64>().ok()).expect("Usage: pokemon_trading issue-api-key <trader id>");
+        match issue_api_key_of(&order_store, trader_id).await.map_err(|e| std::io::Error::other(format!("{:#}", e)))? {
+            Some(api_key) => println!("{}", api_key),
+            None => {
+                eprintln!("Trader {} does not exist", trader_id);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    let calendar = session::TradingCalendar {
+        hours: config.session_hours().expect("Load session hours failed"),
+        cancel_day_orders: config.cancel_day_orders_at_close,
+    };
+    let risk_limits = risk::RiskLimits {
+        max_order_value: config.risk_max_order_value,
+        max_open_orders: config.risk_max_open_orders,
+        max_card_notional: config.risk_max_card_notional,
+        max_position: config.risk_max_position,
+        max_daily_volume: config.risk_max_daily_volume,
+    };
     let order_service = order_service::OrderServiceImpl::new(
-        trader_store.clone(), order_store.clone(), trade_store.clone()).await;
+        trader_store.clone(), order_store.clone(), fee_schedule, price_limits, rate_limits.clone(), calendar, risk_limits);
+    // Trades and order events are in the outbox whether or not a sink is configured
+    let outbox_sinks = outbox::sinks(&config).await.expect("Load outbox sinks failed");
+    if !outbox_sinks.is_empty() {
+        let relay = outbox::Relay::new(outbox_store::PostgresOutboxStoreImpl{pg_pool: pool.clone()}, outbox_sinks, outbox::RelayConfig {
+            batch_size: config.outbox_batch_size,
+            poll_interval: std::time::Duration::from_millis(config.outbox_poll_interval_ms),
+            retry_base: std::time::Duration::from_millis(config.outbox_retry_base_ms),
+            retry_max: std::time::Duration::from_secs(config.outbox_retry_max_secs),
+            max_attempts: config.outbox_max_attempts,
+        });
+        actix_web::rt::spawn(relay.run());
+    }
+    // Serves reads right away, order entry once this instance is elected
+    actix_web::rt::spawn(leader::run(config.database_url.clone(), order_service.clone()));
+    if config.snapshot_interval_secs > 0 {
+        let order_service = order_service.clone();
+        let period = std::time::Duration::from_secs(config.snapshot_interval_secs);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval_at(actix_web::rt::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match order_service.snapshot().await {
+                    Ok(_) | Err(Error::NotLeader) => {},
+                    Err(e) => error!("Failed to snapshot order books: {:#}", e),
+                }
+            }
+        });
+    }
+    {
+        // Session phases change and call auctions open and uncross within a second of their schedule,
+        // risk limits edited on another instance are used within a second too
+        let order_service = order_service.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                match order_service.run_sessions(chrono::Utc::now()).await {
+                    Ok(_) | Err(Error::NotLeader) => {},
+                    Err(e) => error!("Failed to run trading sessions: {:#}", e),
+                }
+                if let Err(e) = order_service.refresh_risk_limits().await {
+                    error!("Failed to refresh risk limits: {:#}", e);
+                }
+            }
+        });
+    }
 
     info!("Listening on {}:{}", config.host, config.port);
     HttpServer::new(move || {
         App::new()
+            .wrap(actix_web_lab::middleware::from_fn(rate_limit::limit_requests))
             .wrap(middleware::Logger::default())
-            .configure(|cfg| graphql::endpoint::configure(cfg, order_store.clone(), trade_store.clone()))
+            .configure(openapi::configure)
+            .configure(|cfg| graphql::endpoint::configure(cfg, trader_store.clone(), order_store.clone(), trade_store.clone(), order_service.clone(), rate_limits.clone()))
+            .app_data(web::JsonConfig::default().error_handler(|e, _| problem::invalid_request(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| problem::invalid_request(e)))
+            .app_data(web::PathConfig::default().error_handler(|e, _| problem::invalid_request(e)))
+            .app_data(web::Data::from(rate_limits.clone()))
             .app_data(web::Data::new(order_service.clone()))
             .app_data(web::Data::new(trader_store.clone()))
             .app_data(web::Data::new(order_store.clone()))
             .app_data(web::Data::new(trade_store.clone()))
             .service(health)
-            .service(get_orders)
-            .service(add_order)
-            .service(delete_order)
-            .service(get_trades)
+            .service(web::scope("/api/v1").configure(v1::configure))
+            .service(web::scope("/api/v2").configure(configure_v2))
+            // Registered last, it would shadow the other /api routes
+            .service(web::scope("/api")
+                .wrap(v1_alias_headers(&config.api_v1_sunset))
+                .configure(v1::configure))
             .wrap(actix_cors::Cors::permissive())
     })
     .bind((config.host, config.port))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::{init_service, call_service, read_body, TestRequest};
+    use super::*;
+
+    // Stores which never connect, for requests answered before a query
+    fn lazy_trade_store() -> PostgresTradeStoreImpl {
+        let pool = PgPoolOptions::new().connect_lazy("postgres://postgres@127.0.0.1:1/pokemon").unwrap();
+        PostgresTradeStoreImpl{pg_pool: Arc::new(pool), counterparty_salt: String::new()}
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_v1_alias_has_deprecation_headers() {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(lazy_trade_store()))
+            .service(web::scope("/api/v1").configure(v1::configure))
+            .service(web::scope("/api").wrap(v1_alias_headers("Sat, 01 May 2027 00:00:00 GMT")).configure(v1::configure))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/api/cards/100000/trades").to_request()).await;
+        assert_eq!(404, response.status().as_u16());
+        assert_eq!("@1792368000", response.headers().get("Deprecation").unwrap());
+        assert_eq!("Sat, 01 May 2027 00:00:00 GMT", response.headers().get("Sunset").unwrap());
+        assert_eq!("</api/v1>; rel=\"successor-version\"", response.headers().get("Link").unwrap());
+        // Errors of v1 stay plain text
+        assert_eq!("Card not found", read_body(response).await);
+
+        let response = call_service(&app, TestRequest::get().uri("/api/v1/cards/100000/trades").to_request()).await;
+        assert_eq!(404, response.status().as_u16());
+        assert!(response.headers().get("Deprecation").is_none());
+        assert!(response.headers().get("Sunset").is_none());
+    }
+
+    // Needs a migrated database in DATABASE_URL, run with `cargo test -- --ignored`, leaves two traders and a cancelled order behind
+    #[actix_web::main]
+    #[test]
+    #[ignore]
+    async fn test_order_events_of_own_orders_only() {
+        let pool = Arc::new(PgPoolOptions::new().connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap());
+        let trader_store = PostgresTraderStoreImpl{pg_pool: pool.clone()};
+        let order_store = PostgresOrderStoreImpl{pg_pool: pool.clone()};
+        let mut keys = vec![];
+        let mut trader_ids = vec![];
+        for _ in 0..2 {
+            let trader_id: i64 = sqlx::query_scalar("INSERT INTO traders DEFAULT VALUES RETURNING id").fetch_one(&*pool).await.unwrap();
+            keys.push(issue_api_key_of(&order_store, trader_id).await.unwrap().unwrap());
+            trader_ids.push(trader_id);
+        }
+        // Cancelled, so the book rebuilt from the journal doesn't miss it
+        let order_id: i64 = sqlx::query_scalar("INSERT INTO orders (card_id, price, side, status, trader_id) VALUES (1, 400, 0, 2, $1) RETURNING id")
+            .bind(trader_ids[0]).fetch_one(&*pool).await.unwrap();
+        let mut tx = order_store.begin().await.unwrap();
+        tx.insert_order_event(ports::NewOrderEvent::new(order_id, ports::OrderEventKind::Created, 400, 1, ports::Actor::Trader(trader_ids[0]))).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(trader_store))
+            .app_data(web::Data::new(order_store))
+            .service(web::scope("/api/v2").service(get_order_events))).await;
+        let get = |key: Option<&str>, order_id: i64| {
+            let mut req = TestRequest::get().uri(&format!("/api/v2/orders/{}/events", order_id));
+            if let Some(key) = key {
+                req = req.insert_header(("Authorization", format!("Bearer {}", key)));
+            }
+            req.to_request()
+        };
+
+        let response = call_service(&app, get(Some(&keys[0]), order_id)).await;
+        assert_eq!(200, response.status().as_u16());
+        let events: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(1, events.as_array().unwrap().len());
+        assert_eq!("created", events[0]["kind"]);
+        assert_eq!(format!("trader:{}", trader_ids[0]), events[0]["actor"]);
+        // Orders of other traders look like unknown orders
+        assert_eq!(404, call_service(&app, get(Some(&keys[1]), order_id)).await.status().as_u16());
+        assert_eq!(404, call_service(&app, get(Some(&keys[0]), i64::MAX)).await.status().as_u16());
+        assert_eq!(401, call_service(&app, get(None, order_id)).await.status().as_u16());
+    }
+}
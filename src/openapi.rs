@@ -0,0 +1,157 @@
+use actix_web::{web, get, HttpResponse, Responder};
+use actix_web_lab::respond::Html;
+use utoipa::{Modify, OpenApi, openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme}};
+
+use crate::ports::{Order, OrderEvent, Trade, TraderTrade, Depth, PriceLevel, AuctionIndication, CallAuction, CardStatus, TradingState, SessionPhase, SessionStatus, ClosureKind, MarketClosure, Ticker, TradingHalt, TraderSuspension, AuditEntry, TraderRiskLimits, Role};
+use crate::problem::Problem;
+
+// Generated from the v2 handler annotations, checked in as design/openapi.yml
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PokemonTrading", version = "2.0", description = "This is a trading platform of Pokemon Trading Card Game. Documents /api/v2, /api/v1 keeps the original shapes and the unversioned /api routes are its deprecated alias."),
+    paths(
+        crate::health,
+        crate::get_orders,
+        crate::add_order,
+        crate::add_orders,
+        crate::delete_orders,
+        crate::delete_order,
+        crate::amend_order,
+        crate::get_order_by_client_id,
+        crate::delete_order_by_client_id,
+        crate::get_order_events,
+        crate::get_trader_trades,
+        crate::export_trader_trades,
+        crate::get_cards,
+        crate::get_trades,
+        crate::get_depth,
+        crate::get_card_status,
+        crate::get_ticker,
+        crate::get_session,
+        crate::get_market_data,
+        crate::get_closures,
+        crate::get_call_auctions,
+        crate::create_snapshots,
+        crate::create_call_auction,
+        crate::create_closure,
+        crate::halt_market,
+        crate::resume_market,
+        crate::halt_card,
+        crate::resume_card,
+        crate::get_halts,
+        crate::suspend_trader,
+        crate::reinstate_trader,
+        crate::get_suspensions,
+        crate::cancel_trader_orders,
+        crate::get_risk_limits,
+        crate::put_risk_limits,
+        crate::get_role,
+        crate::put_role,
+        crate::issue_api_key,
+        crate::get_audit_log,
+    ),
+    components(schemas(
+        Order,
+        OrderEvent,
+        Trade,
+        TraderTrade,
+        Depth,
+        PriceLevel,
+        AuctionIndication,
+        CallAuction,
+        crate::card::Card,
+        CardStatus,
+        TradingState,
+        SessionPhase,
+        SessionStatus,
+        ClosureKind,
+        MarketClosure,
+        Ticker,
+        TradingHalt,
+        TraderSuspension,
+        AuditEntry,
+        TraderRiskLimits,
+        Role,
+        Problem,
+        crate::OrderRequest,
+        crate::BatchOrderRequest,
+        crate::BatchOrderResult,
+        crate::CancelOrdersResponse,
+        crate::AmendOrderRequest,
+        crate::CardSnapshot,
+        crate::SnapshotResponse,
+        crate::CallAuctionRequest,
+        crate::ClosureRequest,
+        crate::RoleRequest,
+        crate::RoleResponse,
+        crate::ApiKeyResponse,
+    )),
+    modifiers(&CallerSecurity),
+    tags(
+        (name = "orders", description = "Order entry and queries"),
+        (name = "trades", description = "Trade history"),
+        (name = "market data", description = "Visible state of the order books"),
+        (name = "admin", description = "Operations of the matching engine for admins, support may read them, actions are written to the audit log with the X-Operator identity"),
+        (name = "health", description = "Liveness probe"),
+    )
+)]
+pub struct ApiDoc;
+
+// Trader routes and admin routes authenticate the caller by its API key, sent as a bearer token
+struct CallerSecurity;
+
+impl Modify for CallerSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("api_key", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+        }
+    }
+}
+
+#[get("/api/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[get("/api/docs")]
+async fn swagger_ui() -> impl Responder {
+    Html(r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>PokemonTrading API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>"##.to_string())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_json)
+        .service(swagger_ui);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Run with UPDATE_OPENAPI=1 to rewrite the checked-in spec after changing the API
+    #[test]
+    fn test_openapi_spec_is_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/design/openapi.yml");
+        let spec = ApiDoc::openapi().to_yaml().unwrap();
+        if std::env::var("UPDATE_OPENAPI").is_ok() {
+            std::fs::write(path, &spec).unwrap();
+            return;
+        }
+        let checked_in = std::fs::read_to_string(path).unwrap();
+        assert!(checked_in == spec, "design/openapi.yml is out of date, run `UPDATE_OPENAPI=1 cargo test openapi` to regenerate it");
+    }
+}
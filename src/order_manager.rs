@@ -1,8 +1,19 @@
-use std::{collections::{BTreeMap, VecDeque}};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+use anyhow::{anyhow, bail, Result, Context};
+use chrono::{DateTime, Utc};
 use futures::future;
+use log::{info, warn, error};
+use tokio::runtime;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::{card};
-use crate::ports::{Action, self, OrderStore};
+use crate::card;
+use crate::error::Error;
+use crate::order_service::{FeeSchedule, PriceLimits};
+use crate::risk::{Exposure, Exposures, TrackedTransaction};
+use crate::ports::{self, Action, OrderType, OrderStore, OrderTransaction, Order, NewOrder, NewTrade, NewOrderEvent, OrderEventKind, Actor, Status, JournalKind, JournalEntry, NewJournalEntry, BookSnapshot, SelfTradePrevention, PriceLevel, Depth, PostOnly, AuctionIndication, NewAuditEntry};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct PendingOrder {
@@ -11,104 +22,623 @@ pub struct PendingOrder {
     // Cents, Shift decimal point by 2, 100 stand for 1.00 USD, 1000 stand for 10.00 USD, etc.
     pub price: i32,
     pub card_id: i32,
+    // Open quantity, decreases while the order is partially filled
+    pub quantity: i32,
+    pub trader_id: i64,
+    // Applied when this order would take liquidity from an order of the same trader
+    pub self_trade_prevention: SelfTradePrevention,
+    pub order_type: OrderType,
+    // Set while a stop order waits in the trigger book, cleared once it's triggered
+    pub stop_price: Option<i32>,
+    // Iceberg orders show at most this much of the open quantity, the rest is a hidden reserve
+    pub display_quantity: Option<i32>,
+    // Open quantity of an iceberg order's current slice, set when it rests
+    pub slice_quantity: i32,
+    pub post_only: PostOnly,
+    // Rests behind the displayed orders of its price and is left out of the depth
+    pub hidden: bool,
 }
 impl Eq for PendingOrder{}
+impl PendingOrder {
+    // A triggered stop market order takes any price and never rests
+    fn is_market(&self) -> bool {
+        self.order_type == OrderType::StopMarket && self.stop_price.is_none()
+    }
+    // Part of the open quantity matched while resting, hidden orders are matched but not shown
+    fn visible_quantity(&self) -> i32 {
+        match self.display_quantity {
+            Some(_) => self.slice_quantity,
+            None => self.quantity,
+        }
+    }
+    // Shows the next slice of an iceberg order from its reserve
+    fn refill(&mut self) {
+        if let Some(display_quantity) = self.display_quantity {
+            self.slice_quantity = display_quantity.min(self.quantity);
+        }
+    }
+    // Fills of a resting order come out of its visible slice
+    fn fill(&mut self, quantity: i32) {
+        self.quantity -= quantity;
+        if self.display_quantity.is_some() {
+            self.slice_quantity -= quantity;
+        }
+    }
+    // Other reductions come out of the reserve first
+    fn reduce(&mut self, quantity: i32) {
+        self.quantity -= quantity;
+        self.slice_quantity = self.slice_quantity.min(self.quantity);
+    }
+}
+
+// New price of a resting order, quantity_delta is applied to its open quantity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Amendment {
+    pub price: i32,
+    pub quantity_delta: i32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AmendResult {
+    // The order is not resting in the book, e.g. already filled
+    NotFound,
+    // The amendment would leave no open quantity
+    Rejected,
+    Amended(Matches),
+}
+
+// What happened to an order, the resting orders it met and the stop orders its trades triggered
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Matches {
+    pub filled_orders: Vec<FilledOrder>,
+    pub prevented_matches: Vec<PreventedMatch>,
+    // In the order they entered the book, after being triggered
+    pub triggered_orders: Vec<PendingOrder>,
+    // Market orders cancelled with open quantity left
+    pub expired_orders: Vec<PendingOrder>,
+    // Post-only orders cancelled instead of taking liquidity
+    pub rejected_orders: Vec<PendingOrder>,
+    pub repriced_orders: Vec<RepricedOrder>,
+}
+impl Matches {
+    fn append(&mut self, mut other: Matches) {
+        self.filled_orders.append(&mut other.filled_orders);
+        self.prevented_matches.append(&mut other.prevented_matches);
+        self.triggered_orders.append(&mut other.triggered_orders);
+        self.expired_orders.append(&mut other.expired_orders);
+        self.rejected_orders.append(&mut other.rejected_orders);
+        self.repriced_orders.append(&mut other.repriced_orders);
+    }
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.filled_orders.is_empty() && self.prevented_matches.is_empty() && self.triggered_orders.is_empty() && self.expired_orders.is_empty()
+            && self.rejected_orders.is_empty() && self.repriced_orders.is_empty()
+    }
+}
+
+// A post-only order moved behind the best opposite price instead of taking liquidity
+#[derive(Debug, PartialEq, Eq)]
+pub struct RepricedOrder {
+    pub order_id: i64,
+    pub price: i32,
+    pub previous_price: i32,
+    pub quantity: i32,
+}
+
+// Open quantity removed from an order instead of trading with an order of the same trader
+#[derive(Debug, PartialEq, Eq)]
+pub struct PreventedMatch {
+    pub order_id: i64,
+    pub counterparty_order_id: i64,
+    pub price: i32,
+    pub quantity: i32,
+    // Nothing is left open, otherwise the order is decremented and keeps resting or matching
+    pub is_cancelled: bool,
+}
+impl PreventedMatch {
+    fn new(order: &PendingOrder, counterparty_order_id: i64, quantity: i32) -> Self {
+        PreventedMatch {
+            order_id: order.id,
+            counterparty_order_id,
+            price: order.price,
+            quantity,
+            is_cancelled: quantity == order.quantity,
+        }
+    }
+}
 
 type PriceBucket = VecDeque<PendingOrder>;
 
-struct OrderBook {
+pub struct OrderBook {
     bids: BTreeMap<i32, PriceBucket>,
-    asks: BTreeMap<i32, PriceBucket>
+    asks: BTreeMap<i32, PriceBucket>,
+    // Stop orders by stop price, they are not visible in the book until triggered
+    buy_stops: BTreeMap<i32, PriceBucket>,
+    sell_stops: BTreeMap<i32, PriceBucket>,
+    // Collecting orders for a call auction, they rest without matching until it's uncrossed
+    auction: bool,
+    last_price: Option<i32>,
+    // Post-only orders are repriced by one tick
+    tick_size: i32,
 }
 impl OrderBook {
-    fn new() -> Self {
+    pub fn new() -> Self {
         OrderBook {
             bids: BTreeMap::new(),
-            asks: BTreeMap::new()
-        }
-    }
-    fn from_db(bids: Vec<ports::PendingOrder>, asks: Vec<ports::PendingOrder>) -> Self {
-        let mut bids_tree = BTreeMap::new();
-        bids.iter().for_each(|bid| {
-            let price_bucket = bids_tree.entry(bid.price).or_insert(PriceBucket::new());
-            price_bucket.push_back(PendingOrder {
-                id: bid.id,
-                side: Action::Buy,
-                price: bid.price,
-                card_id: bid.card_id,
-            });
-        });
-        let mut asks_tree = BTreeMap::new();
-        asks.iter().for_each(|ask| {
-            let price_bucket = asks_tree.entry(ask.price).or_insert(PriceBucket::new());
-            price_bucket.push_back(PendingOrder {
-                id: ask.id,
-                side: Action::Sell,
-                price: ask.price,
-                card_id: ask.card_id,
-            });
-        });
+            asks: BTreeMap::new(),
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            auction: false,
+            last_price: None,
+            tick_size: 1,
+        }
+    }
+    pub fn for_card(card_id: i32) -> Self {
         OrderBook {
-            bids: bids_tree,
-            asks: asks_tree
+            tick_size: card::find(card_id).map_or(1, |card| card.tick_size),
+            ..OrderBook::new()
         }
     }
-    fn try_match(&mut self, order: &PendingOrder) -> Option<PendingOrder> {
-        match order.side {
-            Action::Buy => {
-                let order_book = &mut self.asks;
-                let best_price = *order_book.keys().nth(0)?;
-                let mut matched_order = None;
-                let price_bucket = order_book.get_mut(&best_price).expect("best price should exist");
-                if order.price >= best_price {
-                    matched_order = Some(price_bucket.pop_front().expect("price bucket should not be empty"));
-                }
-                if price_bucket.is_empty() {
-                    order_book.remove(&best_price);
-                }
-                matched_order
+    // Applies a journal entry the way the matcher did when it was appended
+    pub fn apply(&mut self, entry: &JournalEntry) -> Result<Matches> {
+        // With the tick size of that time, the catalog may have changed since
+        let tick_size = std::mem::replace(&mut self.tick_size, entry.tick_size);
+        let matches = self.apply_entry(entry);
+        self.tick_size = tick_size;
+        matches
+    }
+    fn apply_entry(&mut self, entry: &JournalEntry) -> Result<Matches> {
+        if entry.kind == JournalKind::OpenAuction as i16 {
+            self.open_auction();
+            return Ok(Matches::default());
+        }
+        if entry.kind == JournalKind::Uncross as i16 {
+            return Ok(self.uncross().1);
+        }
+        let order = PendingOrder {
+            id: entry.order_id.with_context(|| format!("Journal entry {} has no order", entry.sequence))?,
+            side: if entry.side == Action::Buy as i16 { Action::Buy } else { Action::Sell },
+            price: entry.previous_price.unwrap_or(entry.price),
+            card_id: entry.card_id,
+            quantity: entry.quantity,
+            trader_id: entry.trader_id.with_context(|| format!("Journal entry {} has no trader", entry.sequence))?,
+            self_trade_prevention: entry.self_trade_prevention.into(),
+            order_type: entry.order_type.into(),
+            stop_price: entry.stop_price,
+            display_quantity: entry.display_quantity,
+            slice_quantity: 0,
+            post_only: entry.post_only.into(),
+            hidden: entry.hidden,
+        };
+        let matches = match entry.kind {
+            kind if kind == JournalKind::New as i16 => self.add_order(order),
+            kind if kind == JournalKind::Cancel as i16 => {
+                self.cancel_order(&order);
+                Matches::default()
             },
-            Action::Sell => {
-                let order_book = &mut self.bids;
-                let best_price = *order_book.keys().last()?;
-                let mut matched_order = None;
-                let price_bucket = order_book.get_mut(&best_price).expect("best price should exist");
-                if order.price <= best_price {
-                    matched_order = Some(price_bucket.pop_front().expect("price bucket should not be empty"));
-                }
-                if price_bucket.is_empty() {
-                    order_book.remove(&best_price);
+            kind if kind == JournalKind::Amend as i16 => match self.amend_order(&order, &Amendment { price: entry.price, quantity_delta: entry.quantity }) {
+                AmendResult::Amended(matches) => matches,
+                _ => Matches::default(),
+            },
+            kind => bail!("Unknown kind {} of journal entry {}", kind, entry.sequence),
+        };
+        Ok(matches)
+    }
+    // Bids then asks, in time priority within a price level
+    pub fn resting_orders(&self) -> impl Iterator<Item = &PendingOrder> {
+        self.bids.values().chain(self.asks.values()).flatten()
+    }
+    // Buy stops then sell stops, in trigger order
+    pub fn stop_orders(&self) -> impl Iterator<Item = &PendingOrder> {
+        self.buy_stops.values().chain(self.sell_stops.values().rev()).flatten()
+    }
+    pub fn snapshot(&self, card_id: i32, sequence: i64) -> BookSnapshot {
+        let orders = self.resting_orders().chain(self.stop_orders()).map(|order| ports::PendingOrder {
+            id: order.id,
+            side: order.side.clone() as i16,
+            price: order.price,
+            card_id: order.card_id,
+            quantity: order.quantity,
+            trader_id: order.trader_id,
+            self_trade_prevention: order.self_trade_prevention as i16,
+            order_type: order.order_type as i16,
+            stop_price: order.stop_price,
+            display_quantity: order.display_quantity,
+            visible_quantity: order.display_quantity.map(|_| order.slice_quantity),
+            post_only: order.post_only as i16,
+            hidden: order.hidden,
+        }).collect();
+        BookSnapshot { card_id, sequence, orders, auction: self.auction, last_price: self.last_price }
+    }
+    // Visible quantity of the best price levels of each side, stop orders and hidden reserves are left out
+    pub fn depth(&self, levels: usize) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        let price_level = |(price, price_bucket): (&i32, &PriceBucket)| {
+            let displayed_orders = price_bucket.iter().filter(|order| !order.hidden);
+            PriceLevel {
+                price: *price,
+                quantity: displayed_orders.clone().map(PendingOrder::visible_quantity).sum(),
+                orders: displayed_orders.count(),
+            }
+        };
+        // Levels with only hidden orders are not shown
+        let is_displayed = |level: &PriceLevel| level.orders > 0;
+        (self.bids.iter().rev().map(price_level).filter(is_displayed).take(levels).collect(),
+            self.asks.iter().map(price_level).filter(is_displayed).take(levels).collect())
+    }
+    // The orders are rested in the given order without matching, they only cross during a call auction
+    pub fn from_snapshot(snapshot: &BookSnapshot) -> Self {
+        let mut order_book = OrderBook::for_card(snapshot.card_id);
+        order_book.auction = snapshot.auction;
+        order_book.last_price = snapshot.last_price;
+        for order in &snapshot.orders {
+            let order = PendingOrder {
+                id: order.id,
+                side: if order.side == Action::Buy as i16 { Action::Buy } else { Action::Sell },
+                price: order.price,
+                card_id: order.card_id,
+                quantity: order.quantity,
+                trader_id: order.trader_id,
+                self_trade_prevention: order.self_trade_prevention.into(),
+                order_type: order.order_type.into(),
+                stop_price: order.stop_price,
+                display_quantity: order.display_quantity,
+                slice_quantity: order.visible_quantity.unwrap_or(0),
+                post_only: order.post_only.into(),
+                hidden: order.hidden,
+            };
+            if order.stop_price.is_some() {
+                order_book.rest_stop(order);
+            } else {
+                order_book.rest_order(order);
+            }
+        }
+        order_book
+    }
+    // Fills the order against the opposite side until nothing is left open or prices don't cross
+    fn try_match(&mut self, order: &mut PendingOrder) -> Matches {
+        let mut matches = Matches::default();
+        while order.quantity > 0 {
+            let (best_price, order_book) = match order.side {
+                Action::Buy => (self.asks.keys().next().copied(), &mut self.asks),
+                Action::Sell => (self.bids.keys().next_back().copied(), &mut self.bids),
+            };
+            let best_price = match best_price {
+                Some(best_price) => best_price,
+                None => break,
+            };
+            let is_crossed = order.is_market() || match order.side {
+                Action::Buy => order.price >= best_price,
+                Action::Sell => order.price <= best_price,
+            };
+            if !is_crossed {
+                break;
+            }
+            let price_bucket = order_book.get_mut(&best_price).expect("best price should exist");
+            let matched_order = price_bucket.front_mut().expect("price bucket should not be empty");
+            if Self::is_self_trade(order, matched_order) {
+                Self::prevent_self_trade(order, matched_order, &mut matches);
+            } else {
+                let quantity = matched_order.visible_quantity().min(order.quantity);
+                matches.filled_orders.push(FilledOrder::new(matched_order, order.id, quantity));
+                self.last_price = Some(matched_order.price);
+                matched_order.fill(quantity);
+                order.quantity -= quantity;
+            }
+            if matched_order.quantity == 0 {
+                price_bucket.pop_front();
+            } else if matched_order.visible_quantity() == 0 {
+                // The next slice of an iceberg order goes to the back of the queue
+                let mut matched_order = price_bucket.pop_front().expect("price bucket should not be empty");
+                matched_order.refill();
+                Self::enqueue(price_bucket, matched_order);
+            }
+            if price_bucket.is_empty() {
+                order_book.remove(&best_price);
+            }
+        }
+        matches
+    }
+    fn is_self_trade(order: &PendingOrder, matched_order: &PendingOrder) -> bool {
+        matched_order.trader_id == order.trader_id && order.self_trade_prevention != SelfTradePrevention::Disabled
+    }
+    // Removes open quantity of the incoming and the resting order as the incoming order's mode says
+    fn prevent_self_trade(order: &mut PendingOrder, matched_order: &mut PendingOrder, matches: &mut Matches) {
+        let quantity = matched_order.quantity.min(order.quantity);
+        let (removed, matched_removed) = match order.self_trade_prevention {
+            SelfTradePrevention::CancelOldest => (0, matched_order.quantity),
+            SelfTradePrevention::CancelBoth => (order.quantity, matched_order.quantity),
+            SelfTradePrevention::Decrement => (quantity, quantity),
+            SelfTradePrevention::CancelNewest | SelfTradePrevention::Disabled => (order.quantity, 0),
+        };
+        if removed > 0 {
+            matches.prevented_matches.push(PreventedMatch::new(order, matched_order.id, removed));
+            order.reduce(removed);
+        }
+        if matched_removed > 0 {
+            matches.prevented_matches.push(PreventedMatch::new(matched_order, order.id, matched_removed));
+            matched_order.reduce(matched_removed);
+        }
+    }
+    pub fn is_auction(&self) -> bool {
+        self.auction
+    }
+    // Last trade or auction price
+    pub fn last_price(&self) -> Option<i32> {
+        self.last_price
+    }
+    pub fn open_auction(&mut self) {
+        self.auction = true;
+    }
+    // None unless the book is in a call auction
+    pub fn auction_indication(&self) -> Option<AuctionIndication> {
+        if !self.auction {
+            return None;
+        }
+        Some(match self.auction_price() {
+            Some((price, volume)) => AuctionIndication { price: Some(price), volume },
+            None => AuctionIndication { price: None, volume: 0 },
+        })
+    }
+    // The limit price executing the most quantity, then the one leaving the least surplus. If the surplus
+    // of the remaining prices is all on the buy side the highest is taken, all on the sell side the lowest,
+    // otherwise the one closest to the last trade price, the lowest without a trade
+    fn auction_price(&self) -> Option<(i32, i32)> {
+        let prices: Vec<i32> = self.bids.keys().chain(self.asks.keys()).copied().collect::<BTreeSet<i32>>().into_iter().collect();
+        // price, volume, buy quantity minus sell quantity
+        let candidates: Vec<(i32, i32, i32)> = prices.into_iter().map(|price| {
+            let buy_quantity: i32 = self.bids.range(price..).flat_map(|(_, price_bucket)| price_bucket).map(|order| order.quantity).sum();
+            let sell_quantity: i32 = self.asks.range(..=price).flat_map(|(_, price_bucket)| price_bucket).map(|order| order.quantity).sum();
+            (price, buy_quantity.min(sell_quantity), buy_quantity - sell_quantity)
+        }).collect();
+        let volume = candidates.iter().map(|(_, volume, _)| *volume).max().filter(|volume| *volume > 0)?;
+        let candidates: Vec<_> = candidates.into_iter().filter(|(_, v, _)| *v == volume).collect();
+        let least_surplus = candidates.iter().map(|(_, _, surplus)| surplus.abs()).min()?;
+        let candidates: Vec<_> = candidates.into_iter().filter(|(_, _, surplus)| surplus.abs() == least_surplus).collect();
+        let price = if candidates.iter().all(|(_, _, surplus)| *surplus > 0) {
+            candidates.last()?.0
+        } else if candidates.iter().all(|(_, _, surplus)| *surplus < 0) {
+            candidates.first()?.0
+        } else {
+            let reference_price = self.last_price.unwrap_or(0);
+            candidates.iter().min_by_key(|(price, _, _)| (price - reference_price).abs())?.0
+        };
+        Some((price, volume))
+    }
+    // Executes the crossing orders at the auction price in price and time priority, the newer order of a pair
+    // is the taker. Matching is continuous afterwards, starting with the stop orders triggered by the auction trades
+    pub fn uncross(&mut self) -> (Option<(i32, i32)>, Matches) {
+        let auction_price = self.auction_price();
+        self.auction = false;
+        let mut matches = Matches::default();
+        let (price, _) = match auction_price {
+            Some(auction_price) => auction_price,
+            None => return (None, matches),
+        };
+        while let Some(bid_price) = self.bids.keys().next_back().copied().filter(|bid_price| *bid_price >= price) {
+            let ask_price = match self.asks.keys().next().copied().filter(|ask_price| *ask_price <= price) {
+                Some(ask_price) => ask_price,
+                None => break,
+            };
+            let bid_bucket = self.bids.get_mut(&bid_price).expect("best bid should exist");
+            let ask_bucket = self.asks.get_mut(&ask_price).expect("best ask should exist");
+            let bid = bid_bucket.front_mut().expect("price bucket should not be empty");
+            let ask = ask_bucket.front_mut().expect("price bucket should not be empty");
+            let (order, matched_order) = if bid.id > ask.id { (bid, ask) } else { (ask, bid) };
+            if Self::is_self_trade(order, matched_order) {
+                Self::prevent_self_trade(order, matched_order, &mut matches);
+            } else {
+                // The whole open quantity takes part, including the hidden reserve of iceberg orders
+                let quantity = matched_order.quantity.min(order.quantity);
+                matches.filled_orders.push(FilledOrder { price, ..FilledOrder::new(matched_order, order.id, quantity) });
+                self.last_price = Some(price);
+                matched_order.reduce(quantity);
+                order.reduce(quantity);
+            }
+            Self::remove_filled(&mut self.bids, bid_price);
+            Self::remove_filled(&mut self.asks, ask_price);
+        }
+        let volume = matches.filled_orders.iter().map(|filled| filled.quantity).sum();
+        let mut triggered_orders = VecDeque::from(self.trigger_stops(&matches.filled_orders));
+        while let Some(order) = triggered_orders.pop_front() {
+            matches.triggered_orders.push(order.clone());
+            matches.append(self.execute(order));
+        }
+        (Some((price, volume)), matches)
+    }
+    // Removes the first order of the price level if nothing is left open
+    fn remove_filled(order_book: &mut BTreeMap<i32, PriceBucket>, price: i32) {
+        let price_bucket = order_book.get_mut(&price).expect("price should exist");
+        if price_bucket.front().is_some_and(|order| order.quantity == 0) {
+            price_bucket.pop_front();
+        }
+        if price_bucket.is_empty() {
+            order_book.remove(&price);
+        }
+    }
+    // Matches the order and rests what is left in the book, a stop order waits to be triggered instead
+    pub fn add_order(&mut self, order: PendingOrder) -> Matches {
+        if order.stop_price.is_some() {
+            self.rest_stop(order);
+            return Matches::default();
+        }
+        self.execute(order)
+    }
+    // Matches the order, then the stop orders triggered by its trades one by one, which may trigger more
+    fn execute(&mut self, mut order: PendingOrder) -> Matches {
+        let mut matches = Matches::default();
+        if self.auction {
+            order.refill();
+            self.rest_order(order);
+            return matches;
+        }
+        let mut orders = VecDeque::from([order]);
+        while let Some(mut order) = orders.pop_front() {
+            if !self.apply_post_only(&mut order, &mut matches) {
+                continue;
+            }
+            let order_matches = self.try_match(&mut order);
+            if order.quantity > 0 {
+                if order.is_market() {
+                    matches.expired_orders.push(order.clone());
+                } else {
+                    order.refill();
+                    self.rest_order(order);
                 }
-                matched_order
             }
+            let triggered_orders = self.trigger_stops(&order_matches.filled_orders);
+            orders.extend(triggered_orders.iter().cloned());
+            matches.append(order_matches);
+            matches.triggered_orders.extend(triggered_orders);
         }
+        matches
     }
-    fn add_order(&mut self, order: PendingOrder) {
-        match order.side {
-            Action::Buy => {
-                let order_book = &mut self.bids;
-                let price_bucket = order_book.entry(order.price).or_insert(PriceBucket::new());
-                price_bucket.push_back(order);
-            },
-            Action::Sell => {
-                let order_book = &mut self.asks;
-                let price_bucket = order_book.entry(order.price).or_insert(PriceBucket::new());
-                price_bucket.push_back(order);
-            },
+    // A post-only order that would take liquidity is rejected or moved one tick behind the best opposite price,
+    // returns false if it's rejected
+    fn apply_post_only(&self, order: &mut PendingOrder, matches: &mut Matches) -> bool {
+        if order.post_only == PostOnly::Disabled || order.is_market() {
+            return true;
+        }
+        let price = match order.side {
+            Action::Buy => self.asks.keys().next().filter(|best_price| order.price >= **best_price).map(|best_price| best_price - self.tick_size),
+            Action::Sell => self.bids.keys().next_back().filter(|best_price| order.price <= **best_price).map(|best_price| best_price + self.tick_size),
+        };
+        let price = match price {
+            Some(price) => price,
+            None => return true,
+        };
+        if order.post_only == PostOnly::Reject {
+            matches.rejected_orders.push(order.clone());
+            return false;
+        }
+        matches.repriced_orders.push(RepricedOrder { order_id: order.id, price, previous_price: order.price, quantity: order.quantity });
+        order.price = price;
+        true
+    }
+    // Removes the stop orders reached by the trades, buy stops from the lowest stop price and then
+    // sell stops from the highest, in time priority per stop price
+    fn trigger_stops(&mut self, filled_orders: &[FilledOrder]) -> Vec<PendingOrder> {
+        let (low, high) = match (filled_orders.iter().map(|filled| filled.price).min(), filled_orders.iter().map(|filled| filled.price).max()) {
+            (Some(low), Some(high)) => (low, high),
+            _ => return vec![],
+        };
+        let mut triggered_orders = Vec::new();
+        while let Some(entry) = self.buy_stops.first_entry() {
+            if *entry.key() > high {
+                break;
+            }
+            triggered_orders.extend(entry.remove());
+        }
+        while let Some(entry) = self.sell_stops.last_entry() {
+            if *entry.key() < low {
+                break;
+            }
+            triggered_orders.extend(entry.remove());
+        }
+        for order in &mut triggered_orders {
+            order.stop_price = None;
+        }
+        triggered_orders
+    }
+    fn rest_stop(&mut self, order: PendingOrder) {
+        let stop_price = order.stop_price.expect("stop order should have a stop price");
+        let stops = match order.side {
+            Action::Buy => &mut self.buy_stops,
+            Action::Sell => &mut self.sell_stops,
+        };
+        stops.entry(stop_price).or_default().push_back(order);
+    }
+    fn rest_order(&mut self, order: PendingOrder) {
+        let order_book = match order.side {
+            Action::Buy => &mut self.bids,
+            Action::Sell => &mut self.asks,
+        };
+        Self::enqueue(order_book.entry(order.price).or_default(), order);
+    }
+    // Displayed orders go behind the displayed ones, hidden orders behind all
+    fn enqueue(price_bucket: &mut PriceBucket, order: PendingOrder) {
+        match price_bucket.iter().position(|o| o.hidden).filter(|_| !order.hidden) {
+            Some(position) => price_bucket.insert(position, order),
+            None => price_bucket.push_back(order),
+        }
+    }
+    // Returns false if the order is not resting in the book, e.g. already filled. A stop order is looked up
+    // in the trigger book first, it may have been triggered since the caller read it
+    pub fn cancel_order(&mut self, order: &PendingOrder) -> bool {
+        if let Some(stop_price) = order.stop_price {
+            let stops = match order.side {
+                Action::Buy => &mut self.buy_stops,
+                Action::Sell => &mut self.sell_stops,
+            };
+            if Self::remove_order(stops, stop_price, order.id) {
+                return true;
+            }
         }
+        let order_book = match order.side {
+            Action::Buy => &mut self.bids,
+            Action::Sell => &mut self.asks,
+        };
+        Self::remove_order(order_book, order.price, order.id)
+    }
+    fn remove_order(order_book: &mut BTreeMap<i32, PriceBucket>, price: i32, order_id: i64) -> bool {
+        let price_bucket = match order_book.get_mut(&price) {
+            Some(price_bucket) => price_bucket,
+            None => return false,
+        };
+        let position = match price_bucket.iter().position(|o| o.id == order_id) {
+            Some(position) => position,
+            None => return false,
+        };
+        price_bucket.remove(position);
+        if price_bucket.is_empty() {
+            order_book.remove(&price);
+        }
+        true
+    }
+    // The order is looked up by id, side and price, its quantity is not used. Stop orders waiting to be triggered are not found
+    pub fn amend_order(&mut self, order: &PendingOrder, amendment: &Amendment) -> AmendResult {
+        let order_book = match order.side {
+            Action::Buy => &mut self.bids,
+            Action::Sell => &mut self.asks,
+        };
+        let price_bucket = match order_book.get_mut(&order.price) {
+            Some(price_bucket) => price_bucket,
+            None => return AmendResult::NotFound,
+        };
+        let position = match price_bucket.iter().position(|o| o.id == order.id) {
+            Some(position) => position,
+            None => return AmendResult::NotFound,
+        };
+        let quantity = price_bucket[position].quantity + amendment.quantity_delta;
+        if quantity <= 0 {
+            return AmendResult::Rejected;
+        }
+        // Decreasing quantity keeps the place in the queue
+        if amendment.price == order.price && amendment.quantity_delta <= 0 {
+            price_bucket[position].reduce(-amendment.quantity_delta);
+            return AmendResult::Amended(Matches::default());
+        }
+        // Otherwise the order loses priority and is matched again like a new one
+        let mut amended_order = price_bucket.remove(position).expect("position should exist");
+        if price_bucket.is_empty() {
+            order_book.remove(&order.price);
+        }
+        amended_order.price = amendment.price;
+        amended_order.quantity = quantity;
+        AmendResult::Amended(self.execute(amended_order))
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FilledOrder {
     pub buy_order: i64,
     pub sell_order: i64,
     pub price: i32,
+    pub quantity: i32,
     pub card_id: i32,
     pub first_order_id: i64,
 }
 impl FilledOrder {
-    fn new(pending_order: &PendingOrder, new_order_id: i64) -> Self {
+    fn new(pending_order: &PendingOrder, new_order_id: i64, quantity: i32) -> Self {
         let buy_order;
         let sell_order;
         match pending_order.side {
@@ -125,231 +655,1101 @@ impl FilledOrder {
             buy_order,
             sell_order,
             price: pending_order.price,
+            quantity,
             card_id: pending_order.card_id,
             first_order_id: pending_order.id,
         }
     }
+    // The order that took the liquidity of first_order_id
+    pub fn taker_order_id(&self) -> i64 {
+        if self.buy_order == self.first_order_id { self.sell_order } else { self.buy_order }
+    }
 }
 
-// TODO: may use threads per card to increase performance
-pub struct OrderManager {
-    order_books: Vec<OrderBook>
+impl From<&Order> for PendingOrder {
+    fn from(order: &Order) -> Self {
+        PendingOrder {
+            id: order.id,
+            side: if order.side == Action::Buy as i16 { Action::Buy } else { Action::Sell },
+            price: order.price,
+            card_id: order.card_id,
+            quantity: order.quantity - order.filled_quantity,
+            trader_id: order.trader_id,
+            self_trade_prevention: order.self_trade_prevention.into(),
+            order_type: order.order_type.into(),
+            stop_price: if order.triggered_at.is_none() { order.stop_price } else { None },
+            display_quantity: order.display_quantity,
+            slice_quantity: 0,
+            post_only: order.post_only.into(),
+            hidden: order.hidden,
+        }
+    }
 }
 
-impl OrderManager {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        OrderManager {
-            order_books: (0..card::NUM_CARDS).map(|_| OrderBook::new()).collect()
+// Result of each order of a card, None if its client_order_id was already used
+type PlacedOrders = Vec<Result<Option<Order>, Error>>;
+
+// Commands queued for the matcher of a card
+enum Command {
+    // Returns None for an order whose client_order_id is already used
+    AddOrders { orders: Vec<NewOrder>, reply: oneshot::Sender<Result<PlacedOrders, Error>> },
+    // Returns the ids of the orders cancelled in the book, kind is the event recorded, Cancelled or Expired
+    CancelOrders { orders: Vec<Order>, kind: OrderEventKind, actor: Actor, reply: oneshot::Sender<Result<Vec<i64>, Error>> },
+    AmendOrder { order: Order, price: i32, quantity: i32, reply: oneshot::Sender<Result<(), Error>> },
+    Snapshot { reply: oneshot::Sender<Result<BookSnapshot, Error>> },
+    Depth { levels: usize, reply: oneshot::Sender<Result<Depth, Error>> },
+    OpenAuction { auction_id: i64, reply: oneshot::Sender<Result<(), Error>> },
+    Uncross { auction_id: i64, reply: oneshot::Sender<Result<(), Error>> },
+    // Halted by operations, either the card or the whole market
+    SetHalted { halted: bool, reply: oneshot::Sender<Result<(), Error>> },
+}
+
+// Senders wait once this many commands of a card are queued
+const COMMAND_QUEUE_SIZE: usize = 1024;
+
+// Owns the book of one card, a command is committed before the next one is taken,
+// so no one matches against uncommitted orders
+struct CardMatcher<B: OrderStore> {
+    card_id: i32,
+    order_book: OrderBook,
+    // Journal sequence of the last command applied to the book
+    sequence: i64,
+    snapshot_sequence: i64,
+    // Leader epoch of this instance
+    epoch: i64,
+    order_store: Arc<B>,
+    fee_schedule: FeeSchedule,
+    price_limits: PriceLimits,
+    // Trade prices within the circuit breaker window, oldest first
+    recent_prices: VecDeque<(Instant, i32)>,
+    // Only cancellations are accepted while halted by operations
+    halted: bool,
+    // Shared by every card
+    suspended_traders: Arc<RwLock<HashSet<i64>>>,
+    exposures: Arc<Mutex<Exposures>>,
+}
+
+impl<B: OrderStore> CardMatcher<B> {
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        while let Some(command) = commands.recv().await {
+            // The requester may be gone, the command is done anyway
+            match command {
+                Command::AddOrders { orders, reply } => {
+                    let r = self.add_orders(orders).await;
+                    self.recover(&r).await;
+                    let _ = reply.send(r);
+                },
+                Command::CancelOrders { orders, kind, actor, reply } => {
+                    let r = self.cancel_orders(orders, kind, actor).await;
+                    self.recover(&r).await;
+                    let _ = reply.send(r);
+                },
+                Command::AmendOrder { order, price, quantity, reply } => {
+                    let r = self.amend_order(order, price, quantity).await;
+                    self.recover(&r).await;
+                    let _ = reply.send(r);
+                },
+                Command::Snapshot { reply } => {
+                    let _ = reply.send(self.snapshot().await);
+                },
+                Command::Depth { levels, reply } => {
+                    let (bids, asks) = self.order_book.depth(levels);
+                    let auction = self.order_book.auction_indication();
+                    let _ = reply.send(Ok(Depth { card_id: self.card_id, sequence: self.sequence, bids, asks, auction }));
+                },
+                Command::OpenAuction { auction_id, reply } => {
+                    let r = self.open_auction(auction_id).await;
+                    self.recover(&r).await;
+                    let _ = reply.send(r);
+                },
+                Command::Uncross { auction_id, reply } => {
+                    let r = self.uncross(auction_id).await;
+                    self.recover(&r).await;
+                    let _ = reply.send(r);
+                },
+                Command::SetHalted { halted, reply } => {
+                    if halted != self.halted {
+                        info!("Card {} is {} by operations", self.card_id, if halted { "halted" } else { "resumed" });
+                    }
+                    self.halted = halted;
+                    let _ = reply.send(Ok(()));
+                },
+            }
         }
     }
-    pub async fn from_db(order_sotre: &impl OrderStore) -> Self {
-        let mut order_books = Vec::new();
-        // TODO: join all futures from all cards
-        for card_id in 0..card::NUM_CARDS {
-            let bids = order_sotre.query_pending_orders(card_id as i32, 0);
-            let asks = order_sotre.query_pending_orders(card_id as i32, 1);
-            let (bids, asks) = future::join(bids, asks).await;
-            let order_book = OrderBook::from_db(bids.unwrap(), asks.unwrap());
-            order_books.push(order_book);
+
+    // Restores the latest snapshot and applies the newer journal entries, which keeps the time priority of the book
+    async fn load(&mut self) -> Result<()> {
+        let snapshot = self.order_store.query_snapshot(self.card_id).await.with_context(|| format!("Failed to load snapshot of card {}", self.card_id))?;
+        let (mut order_book, snapshot_sequence) = match snapshot {
+            Some(snapshot) => (OrderBook::from_snapshot(&snapshot), snapshot.sequence),
+            None => (OrderBook::for_card(self.card_id), 0),
+        };
+        let entries = self.order_store.query_journal(self.card_id, snapshot_sequence).await.with_context(|| format!("Failed to load journal of card {}", self.card_id))?;
+        let mut sequence = snapshot_sequence;
+        for entry in &entries {
+            order_book.apply(entry)?;
+            sequence = entry.sequence;
         }
-        OrderManager {
-            order_books
+        info!("Loaded book of card {} from snapshot {} and {} journal entries", self.card_id, snapshot_sequence, entries.len());
+        self.order_book = order_book;
+        self.sequence = sequence;
+        self.snapshot_sequence = snapshot_sequence;
+        Ok(())
+    }
+
+    // The book is ahead of the database after a failed transaction, so it's loaded again
+    async fn recover<T>(&mut self, r: &Result<T, Error>) {
+        if let Err(Error::Internal(e)) = r {
+            error!("Card {} failed to persist a command, reloading its book: {:#}", self.card_id, e);
+            if let Err(e) = self.load().await {
+                error!("{:#}", e);
+            }
         }
     }
-    pub fn add_order(&mut self, order: PendingOrder) -> Option<FilledOrder> {
-        let matched_order = self.order_books[order.card_id as usize].try_match(&order);
-        match matched_order {
-            Some(o) => {
-                Some(FilledOrder::new(&o, order.id))
+
+    // Written from the matcher thread, so the snapshot is consistent with its sequence
+    async fn snapshot(&mut self) -> Result<BookSnapshot, Error> {
+        let snapshot = self.order_book.snapshot(self.card_id, self.sequence);
+        // Unchanged since the last one
+        if self.sequence > self.snapshot_sequence {
+            self.order_store.save_snapshot(&snapshot).await.with_context(|| format!("Failed to save snapshot of card {}", self.card_id))?;
+            self.snapshot_sequence = self.sequence;
+        }
+        Ok(snapshot)
+    }
+
+    async fn add_orders(&mut self, orders: Vec<NewOrder>) -> Result<PlacedOrders, Error> {
+        // An order which isn't admitted or is outside the band is answered on its own
+        let admissions: Vec<Result<(), Error>> = orders.iter()
+            .map(|order| self.check_admitted(order.trader_id).and_then(|_| self.check_band(order.price)))
+            .collect();
+        if admissions.iter().all(Result::is_err) {
+            return Ok(admissions.into_iter().map(|admission| admission.map(|_| None)).collect());
+        }
+        let mut tx = self.begin().await?;
+        let mut new_orders = Vec::with_capacity(orders.len());
+        for (order, admission) in orders.into_iter().zip(&admissions) {
+            if admission.is_err() {
+                continue;
+            }
+            let new_order = Order {
+                id: 0,
+                card_id: order.card_id,
+                price: order.price,
+                side: order.action.clone() as i16,
+                status: order.status,
+                trader_id: order.trader_id,
+                created_at: order.created_at,
+                client_order_id: order.client_order_id.clone(),
+                quantity: order.quantity,
+                filled_quantity: 0,
+                self_trade_prevention: order.self_trade_prevention as i16,
+                order_type: order.order_type as i16,
+                stop_price: order.stop_price,
+                triggered_at: None,
+                display_quantity: order.display_quantity,
+                post_only: order.post_only as i16,
+                hidden: order.hidden,
+                time_in_force: order.time_in_force as i16,
+            };
+            // None if the client_order_id is used by a concurrent submission or earlier in this batch
+            let order_id = tx.insert_order(order).await.with_context(|| "Insert order failed")?;
+            if let Some(order_id) = order_id {
+                tx.insert_order_event(NewOrderEvent::new(order_id, OrderEventKind::Created, new_order.price, new_order.quantity, Actor::Trader(new_order.trader_id))).await
+                    .with_context(|| format!("Failed to insert order event: {}", order_id))?;
+            }
+            new_orders.push(order_id.map(|id| Order { id, ..new_order }));
+        }
+
+        let mut filled_quantities = HashMap::new();
+        let mut decremented_quantities = HashMap::new();
+        let mut cancelled_orders = HashSet::new();
+        let mut triggered_orders = HashSet::new();
+        let mut repriced_orders = HashMap::new();
+        for order in new_orders.iter().flatten() {
+            let pending_order = PendingOrder::from(order);
+            let sequence = self.append_journal(tx.as_mut(), JournalKind::New, &pending_order, pending_order.price, pending_order.quantity).await?;
+            let matches = self.order_book.add_order(pending_order);
+            for filled in &matches.filled_orders {
+                *filled_quantities.entry(filled.taker_order_id()).or_insert(0) += filled.quantity;
+                *filled_quantities.entry(filled.first_order_id).or_insert(0) += filled.quantity;
             }
-            None => {
-                self.order_books[order.card_id as usize].add_order(order);
-                None
+            for prevented in &matches.prevented_matches {
+                if prevented.is_cancelled {
+                    cancelled_orders.insert(prevented.order_id);
+                } else {
+                    *decremented_quantities.entry(prevented.order_id).or_insert(0) += prevented.quantity;
+                }
             }
+            cancelled_orders.extend(matches.expired_orders.iter().map(|expired| expired.id));
+            cancelled_orders.extend(matches.rejected_orders.iter().map(|rejected| rejected.id));
+            repriced_orders.extend(matches.repriced_orders.iter().map(|repriced| (repriced.order_id, repriced.price)));
+            triggered_orders.extend(matches.triggered_orders.iter().map(|triggered| triggered.id));
+            self.record_matches(tx.as_mut(), sequence, &matches).await?;
+            // The rest of the batch is collected by the halt auction
+            self.check_circuit_breaker(tx.as_mut(), &matches).await?;
         }
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+
+        let triggered_at = Utc::now();
+        let mut placed_orders = new_orders.into_iter().map(|order| order.map(|mut order| {
+            order.filled_quantity = filled_quantities.get(&order.id).copied().unwrap_or(0);
+            order.quantity -= decremented_quantities.get(&order.id).copied().unwrap_or(0);
+            if triggered_orders.contains(&order.id) {
+                order.triggered_at = Some(triggered_at);
+            }
+            if let Some(price) = repriced_orders.get(&order.id) {
+                order.price = *price;
+            }
+            if cancelled_orders.contains(&order.id) {
+                order.status = Status::Cancelled as i16;
+            } else if order.filled_quantity >= order.quantity {
+                order.status = Status::Filled as i16;
+            }
+            order
+        }));
+        Ok(admissions.into_iter().map(|admission| admission.map(|_| placed_orders.next().flatten())).collect())
     }
-}
 
-#[cfg(test)]
-mod test{
-    use super::*;
-    #[test]
-    fn test_match_with_same_price() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
+    async fn cancel_orders(&mut self, orders: Vec<Order>, kind: OrderEventKind, actor: Actor) -> Result<Vec<i64>, Error> {
+        let cancelled_orders: Vec<PendingOrder> = orders.iter()
+            .map(PendingOrder::from)
+            .filter(|order| self.order_book.cancel_order(order))
+            .collect();
+        if cancelled_orders.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut tx = self.begin().await?;
+        for order in &cancelled_orders {
+            self.append_journal(tx.as_mut(), JournalKind::Cancel, order, order.price, 0).await?;
+            tx.update_order_status(order.id, Status::Cancelled).await.with_context(|| format!("Failed to update order status: {}", order.id))?;
+            tx.insert_order_event(NewOrderEvent::new(order.id, kind, order.price, order.quantity, actor.clone())).await
+                .with_context(|| format!("Failed to insert order event: {}", order.id))?;
+        }
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        Ok(cancelled_orders.iter().map(|order| order.id).collect())
+    }
+
+    async fn amend_order(&mut self, order: Order, price: i32, quantity: i32) -> Result<(), Error> {
+        // The delta is applied to the open quantity of the book, which may be ahead of filled_quantity in the order
+        let amendment = Amendment {
+            price,
+            quantity_delta: quantity - order.quantity,
         };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Sell,
-            price: 100,
-            card_id: 0,
+        self.check_admitted(order.trader_id)?;
+        if price != order.price {
+            self.check_band(price)?;
+        }
+        let pending_order = PendingOrder::from(&order);
+        let matches = match self.order_book.amend_order(&pending_order, &amendment) {
+            AmendResult::NotFound => return Err(Error::OrderNotPending),
+            AmendResult::Rejected => return Err(Error::QuantityNotAboveFilled),
+            AmendResult::Amended(matches) => matches,
         };
-        let filled_order = order_manager.add_order(order2);
-        match filled_order {
-            Some(o) => {         
-                assert_eq!(FilledOrder{buy_order: 1, sell_order: 2, price: 100, card_id: 0, first_order_id: 1}, o);
-            },
-            None => assert!(false),
+
+        // Rejected amendments leave the book as it is, so they're not journaled
+        let mut tx = self.begin().await?;
+        let sequence = self.append_journal(tx.as_mut(), JournalKind::Amend, &pending_order, price, amendment.quantity_delta).await?;
+        tx.update_order(order.id, price, quantity).await.with_context(|| format!("Failed to update order: {}", order.id))?;
+        tx.insert_order_event(NewOrderEvent {
+            order_id: order.id,
+            kind: OrderEventKind::Amended,
+            price,
+            quantity,
+            previous_price: Some(order.price),
+            previous_quantity: Some(order.quantity),
+            counterparty_order_id: None,
+            trade_id: None,
+            actor: Actor::Trader(order.trader_id),
+        }).await.with_context(|| format!("Failed to insert order event: {}", order.id))?;
+        self.record_matches(tx.as_mut(), sequence, &matches).await?;
+        self.check_circuit_breaker(tx.as_mut(), &matches).await?;
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        Ok(())
+    }
+
+    // Checked in the matcher, so no order is placed after the halt or suspension is acknowledged
+    fn check_admitted(&self, trader_id: i64) -> Result<(), Error> {
+        if self.halted {
+            return Err(Error::TradingHalted);
         }
+        if self.suspended_traders.read().unwrap().contains(&trader_id) {
+            return Err(Error::TraderSuspended);
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_match_with_first_come_order() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
+    // Prices must be within the band around the last trade or auction price, if there was one
+    fn check_band(&self, price: i32) -> Result<(), Error> {
+        match self.order_book.last_price().and_then(|last_price| self.price_limits.band(last_price)) {
+            Some((low, high)) if price < low || price > high => Err(Error::PriceOutsideBand(low, high)),
+            _ => Ok(()),
+        }
+    }
+
+    // Halts the card if a trade moved the price too far from a price traded within the window,
+    // it re-opens through a call auction
+    async fn check_circuit_breaker(&mut self, tx: &mut dyn OrderTransaction, matches: &Matches) -> Result<()> {
+        let now = Instant::now();
+        while self.recent_prices.front().is_some_and(|(traded_at, _)| now.duration_since(*traded_at) > self.price_limits.breaker_window) {
+            self.recent_prices.pop_front();
+        }
+        let mut is_breached = false;
+        for filled in &matches.filled_orders {
+            is_breached |= self.recent_prices.iter().any(|(_, price)| self.price_limits.is_breached(*price, filled.price));
+            self.recent_prices.push_back((now, filled.price));
+        }
+        if !is_breached || self.order_book.is_auction() {
+            return Ok(());
+        }
+        self.order_book.open_auction();
+        self.append_auction_journal(tx, JournalKind::OpenAuction, 0, 0).await?;
+        let closes_at = Utc::now() + chrono::Duration::from_std(self.price_limits.halt_duration).context("Halt duration is out of range")?;
+        tx.insert_halt_auction(self.card_id, closes_at).await.with_context(|| format!("Failed to halt card {}", self.card_id))?;
+        self.recent_prices.clear();
+        warn!("Card {} is halted by the circuit breaker until {}", self.card_id, closes_at);
+        Ok(())
+    }
+
+    // An auction opening while another one collects orders joins it
+    async fn open_auction(&mut self, auction_id: i64) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+        if !self.order_book.is_auction() {
+            self.order_book.open_auction();
+            self.append_auction_journal(tx.as_mut(), JournalKind::OpenAuction, 0, 0).await?;
+        }
+        tx.open_call_auction(auction_id).await.with_context(|| format!("Failed to open call auction: {}", auction_id))?;
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        Ok(())
+    }
+
+    // Nothing is executed if the book was already uncrossed by an overlapping auction,
+    // a halted card keeps collecting until it's resumed
+    async fn uncross(&mut self, auction_id: i64) -> Result<(), Error> {
+        if self.halted {
+            return Ok(());
+        }
+        let mut tx = self.begin().await?;
+        let (price, volume) = if self.order_book.is_auction() {
+            let (auction_price, matches) = self.order_book.uncross();
+            let (price, volume) = match auction_price {
+                Some((price, volume)) => (Some(price), volume),
+                None => (None, 0),
+            };
+            let sequence = self.append_auction_journal(tx.as_mut(), JournalKind::Uncross, price.unwrap_or(0), volume).await?;
+            self.record_matches(tx.as_mut(), sequence, &matches).await?;
+            // The auction price is where the circuit breaker starts again
+            self.recent_prices = price.map(|price| (Instant::now(), price)).into_iter().collect();
+            info!("Uncrossed call auction {} of card {} at {:?} with volume {}", auction_id, self.card_id, price, volume);
+            (price, volume)
+        } else {
+            (None, 0)
         };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
+        tx.uncross_call_auction(auction_id, price, volume).await.with_context(|| format!("Failed to uncross call auction: {}", auction_id))?;
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        Ok(())
+    }
+
+    async fn begin(&self) -> Result<Box<dyn OrderTransaction>> {
+        let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+        tx.fence(self.epoch).await?;
+        Ok(Box::new(TrackedTransaction::new(tx, self.exposures.clone())))
+    }
+
+    // The entry is at the order's current place in the book, price and quantity are what JournalEntry describes
+    async fn append_journal(&mut self, tx: &mut dyn OrderTransaction, kind: JournalKind, order: &PendingOrder, price: i32, quantity: i32) -> Result<i64> {
+        let previous_price = if kind == JournalKind::Amend { Some(order.price) } else { None };
+        self.sequence = tx.append_journal(NewJournalEntry {
+            card_id: self.card_id,
+            kind,
+            order_id: Some(order.id),
+            side: order.side.clone(),
+            price,
+            quantity,
+            previous_price,
+            trader_id: Some(order.trader_id),
+            self_trade_prevention: order.self_trade_prevention,
+            order_type: order.order_type,
+            stop_price: order.stop_price,
+            display_quantity: order.display_quantity,
+            post_only: order.post_only,
+            hidden: order.hidden,
+            tick_size: self.order_book.tick_size,
+        }).await.with_context(|| format!("Failed to append journal: {}", order.id))?;
+        Ok(self.sequence)
+    }
+
+    // Auction entries have no order, the order fields are left at their defaults
+    async fn append_auction_journal(&mut self, tx: &mut dyn OrderTransaction, kind: JournalKind, price: i32, quantity: i32) -> Result<i64> {
+        self.sequence = tx.append_journal(NewJournalEntry {
+            card_id: self.card_id,
+            kind,
+            order_id: None,
             side: Action::Buy,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order2).is_none());
-        let order3 = PendingOrder {
-            id: 3,
-            side: Action::Sell,
-            price: 100,
-            card_id: 0,
-        };
-        let filled_order = order_manager.add_order(order3);
-        match filled_order {
-            Some(o) => {         
-                assert_eq!(FilledOrder{buy_order: 1, sell_order: 3, price: 100, card_id: 0, first_order_id: 1}, o);
-            },
-            None => assert!(false),
+            price,
+            quantity,
+            previous_price: None,
+            trader_id: None,
+            self_trade_prevention: SelfTradePrevention::default(),
+            order_type: OrderType::Limit,
+            stop_price: None,
+            display_quantity: None,
+            post_only: PostOnly::Disabled,
+            hidden: false,
+            tick_size: self.order_book.tick_size,
+        }).await.with_context(|| format!("Failed to append journal of card {}", self.card_id))?;
+        Ok(self.sequence)
+    }
+
+    // Persists the outcome of a command, all of it belongs to the command's journal entry
+    async fn record_matches(&self, tx: &mut dyn OrderTransaction, journal_sequence: i64, matches: &Matches) -> Result<()> {
+        for triggered in &matches.triggered_orders {
+            tx.trigger_order(triggered.id).await.with_context(|| format!("Failed to trigger order: {}", triggered.id))?;
+            tx.insert_order_event(NewOrderEvent {
+                order_id: triggered.id,
+                kind: OrderEventKind::Triggered,
+                price: triggered.price,
+                quantity: triggered.quantity,
+                previous_price: None,
+                previous_quantity: None,
+                counterparty_order_id: None,
+                trade_id: None,
+                actor: Actor::System,
+            }).await.with_context(|| format!("Failed to insert order event: {}", triggered.id))?;
+        }
+        for filled in &matches.filled_orders {
+            let taker_order_id = filled.taker_order_id();
+            let taker_left = tx.fill_order(taker_order_id, filled.quantity).await.with_context(|| format!("Failed to fill order: {}", taker_order_id))?;
+            let maker_left = tx.fill_order(filled.first_order_id, filled.quantity).await.with_context(|| format!("Failed to fill order: {}", filled.first_order_id))?;
+            let maker_fee = self.fee_schedule.maker_fee(filled.price, filled.quantity);
+            let taker_fee = self.fee_schedule.taker_fee(filled.price, filled.quantity);
+            let (buyer_fee, seller_fee) = if filled.buy_order == taker_order_id { (taker_fee, maker_fee) } else { (maker_fee, taker_fee) };
+            let trade_id = tx.insert_trade(NewTrade {
+                card_id: filled.card_id,
+                price: filled.price,
+                quantity: filled.quantity,
+                buyorder_id: filled.buy_order,
+                sellorder_id: filled.sell_order,
+                buyer_fee,
+                seller_fee,
+                journal_sequence,
+            }).await.with_context(|| format!("Failed to insert trade: {}", taker_order_id))?;
+            for (order_id, counterparty_order_id, left) in [(taker_order_id, filled.first_order_id, taker_left), (filled.first_order_id, taker_order_id, maker_left)] {
+                tx.insert_order_event(NewOrderEvent {
+                    order_id,
+                    kind: if left > 0 { OrderEventKind::PartiallyFilled } else { OrderEventKind::Filled },
+                    price: filled.price,
+                    quantity: filled.quantity,
+                    previous_price: None,
+                    previous_quantity: None,
+                    counterparty_order_id: Some(counterparty_order_id),
+                    trade_id: Some(trade_id),
+                    actor: Actor::System,
+                }).await.with_context(|| format!("Failed to insert order event: {}", order_id))?;
+            }
+        }
+        for prevented in &matches.prevented_matches {
+            if prevented.is_cancelled {
+                tx.update_order_status(prevented.order_id, Status::Cancelled).await.with_context(|| format!("Failed to update order status: {}", prevented.order_id))?;
+            } else {
+                tx.decrement_order(prevented.order_id, prevented.quantity).await.with_context(|| format!("Failed to decrement order: {}", prevented.order_id))?;
+            }
+            tx.insert_order_event(NewOrderEvent {
+                order_id: prevented.order_id,
+                kind: OrderEventKind::SelfTradePrevented,
+                price: prevented.price,
+                quantity: prevented.quantity,
+                previous_price: None,
+                previous_quantity: None,
+                counterparty_order_id: Some(prevented.counterparty_order_id),
+                trade_id: None,
+                actor: Actor::System,
+            }).await.with_context(|| format!("Failed to insert order event: {}", prevented.order_id))?;
+            if prevented.is_cancelled {
+                tx.insert_order_event(NewOrderEvent::new(prevented.order_id, OrderEventKind::Cancelled, prevented.price, prevented.quantity, Actor::System)).await
+                    .with_context(|| format!("Failed to insert order event: {}", prevented.order_id))?;
+            }
+        }
+        for expired in &matches.expired_orders {
+            tx.update_order_status(expired.id, Status::Cancelled).await.with_context(|| format!("Failed to update order status: {}", expired.id))?;
+            tx.insert_order_event(NewOrderEvent::new(expired.id, OrderEventKind::Expired, expired.price, expired.quantity, Actor::System)).await
+                .with_context(|| format!("Failed to insert order event: {}", expired.id))?;
+        }
+        for rejected in &matches.rejected_orders {
+            tx.update_order_status(rejected.id, Status::Cancelled).await.with_context(|| format!("Failed to update order status: {}", rejected.id))?;
+            tx.insert_order_event(NewOrderEvent {
+                order_id: rejected.id,
+                kind: OrderEventKind::PostOnlyRejected,
+                price: rejected.price,
+                quantity: rejected.quantity,
+                previous_price: None,
+                previous_quantity: None,
+                counterparty_order_id: None,
+                trade_id: None,
+                actor: Actor::System,
+            }).await.with_context(|| format!("Failed to insert order event: {}", rejected.id))?;
+            tx.insert_order_event(NewOrderEvent::new(rejected.id, OrderEventKind::Cancelled, rejected.price, rejected.quantity, Actor::System)).await
+                .with_context(|| format!("Failed to insert order event: {}", rejected.id))?;
+        }
+        for repriced in &matches.repriced_orders {
+            tx.reprice_order(repriced.order_id, repriced.price).await.with_context(|| format!("Failed to reprice order: {}", repriced.order_id))?;
+            tx.insert_order_event(NewOrderEvent {
+                order_id: repriced.order_id,
+                kind: OrderEventKind::Repriced,
+                price: repriced.price,
+                quantity: repriced.quantity,
+                previous_price: Some(repriced.previous_price),
+                previous_quantity: None,
+                counterparty_order_id: None,
+                trade_id: None,
+                actor: Actor::System,
+            }).await.with_context(|| format!("Failed to insert order event: {}", repriced.order_id))?;
+        }
+        Ok(())
+    }
+}
+
+// Handle of the matchers, each card's book is owned by its own thread, so cards are matched
+// in parallel while the commands of a card are handled one by one in arrival order
+#[derive(Clone)]
+pub struct OrderManager {
+    cards: Vec<mpsc::Sender<Command>>,
+    // Admin actions are written here, fenced like the matchers' writes
+    order_store: Arc<dyn OrderStore + Send + Sync>,
+    epoch: i64,
+    suspended_traders: Arc<RwLock<HashSet<i64>>>,
+    // What the traders have outstanding, for the risk checks before order entry
+    exposures: Arc<Mutex<Exposures>>,
+}
+
+impl OrderManager {
+    // Loads the books from the database and starts a thread per card, the writes are fenced by the leader epoch
+    pub async fn start<B>(order_store: Arc<B>, fee_schedule: FeeSchedule, price_limits: PriceLimits, epoch: i64) -> Result<Self>
+      where B: OrderStore + Send + Sync + 'static {
+        let halts = order_store.query_trading_halts().await.with_context(|| "Failed to load trading halts")?;
+        let suspensions = order_store.query_trader_suspensions().await.with_context(|| "Failed to load trader suspensions")?;
+        let suspended_traders = Arc::new(RwLock::new(suspensions.iter().map(|suspension| suspension.trader_id).collect::<HashSet<i64>>()));
+        let now = Utc::now();
+        let open_orders = order_store.query_open_orders().await.with_context(|| "Failed to load open orders")?;
+        let day_start = DateTime::<Utc>::from_utc(now.naive_utc().date().and_hms(0, 0, 0), Utc);
+        let positions = order_store.query_positions(day_start).await.with_context(|| "Failed to load positions")?;
+        let exposures = Arc::new(Mutex::new(Exposures::load(&open_orders, &positions, now.naive_utc().date())));
+        let mut matchers: Vec<CardMatcher<B>> = (0..card::NUM_CARDS).map(|card_id| CardMatcher {
+            card_id: card_id as i32,
+            order_book: OrderBook::for_card(card_id as i32),
+            sequence: 0,
+            snapshot_sequence: 0,
+            epoch,
+            order_store: order_store.clone(),
+            fee_schedule,
+            price_limits,
+            recent_prices: VecDeque::new(),
+            halted: halts.iter().any(|halt| halt.card_id.is_none() || halt.card_id == Some(card_id as i32)),
+            suspended_traders: suspended_traders.clone(),
+            exposures: exposures.clone(),
+        }).collect();
+        future::try_join_all(matchers.iter_mut().map(|matcher| matcher.load())).await?;
+        let mut cards = Vec::with_capacity(matchers.len());
+        for (card_id, matcher) in matchers.into_iter().enumerate() {
+            let (sender, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+            thread::Builder::new().name(format!("card-{}", card_id)).spawn(move || {
+                let runtime = runtime::Builder::new_current_thread().enable_all().build().expect("Build matcher runtime failed");
+                // Stops once every handle is dropped
+                runtime.block_on(matcher.run(receiver));
+            }).with_context(|| format!("Failed to start matcher of card {}", card_id))?;
+            cards.push(sender);
+        }
+        Ok(OrderManager {
+            cards,
+            order_store,
+            epoch,
+            suspended_traders,
+            exposures,
+        })
+    }
+
+    // Orders must be of the card, the admitted ones are placed in one transaction
+    pub async fn add_orders(&self, card_id: i32, orders: Vec<NewOrder>) -> Result<PlacedOrders, Error> {
+        self.request(card_id, |reply| Command::AddOrders { orders, reply }).await
+    }
+
+    pub async fn cancel_orders(&self, card_id: i32, orders: Vec<Order>, actor: Actor) -> Result<Vec<i64>, Error> {
+        self.request(card_id, |reply| Command::CancelOrders { orders, kind: OrderEventKind::Cancelled, actor, reply }).await
+    }
+
+    // Cancels orders whose time in force ran out
+    pub async fn expire_orders(&self, card_id: i32, orders: Vec<Order>) -> Result<Vec<i64>, Error> {
+        self.request(card_id, |reply| Command::CancelOrders { orders, kind: OrderEventKind::Expired, actor: Actor::System, reply }).await
+    }
+
+    // Quantity is the new total quantity including the filled part
+    pub async fn amend_order(&self, order: Order, price: i32, quantity: i32) -> Result<(), Error> {
+        let card_id = order.card_id;
+        self.request(card_id, |reply| Command::AmendOrder { order, price, quantity, reply }).await
+    }
+
+    // Snapshots every card concurrently
+    pub async fn snapshot(&self) -> Result<Vec<BookSnapshot>, Error> {
+        future::try_join_all((0..self.cards.len() as i32).map(|card_id| self.request(card_id, |reply| Command::Snapshot { reply }))).await
+    }
+
+    pub async fn depth(&self, card_id: i32, levels: usize) -> Result<Depth, Error> {
+        self.request(card_id, |reply| Command::Depth { levels, reply }).await
+    }
+
+    pub async fn open_auction(&self, card_id: i32, auction_id: i64) -> Result<(), Error> {
+        self.request(card_id, |reply| Command::OpenAuction { auction_id, reply }).await
+    }
+
+    pub async fn uncross(&self, card_id: i32, auction_id: i64) -> Result<(), Error> {
+        self.request(card_id, |reply| Command::Uncross { auction_id, reply }).await
+    }
+
+    // Includes the orders committed so far, not the ones queued at the matchers
+    pub fn exposure(&self, trader_id: i64, card_id: i32) -> Exposure {
+        self.exposures.lock().unwrap().exposure(trader_id, card_id, Utc::now().naive_utc().date())
+    }
+
+    // Halts or resumes the card, or the whole market without card_id, a card stays halted while the market is
+    pub async fn set_halted(&self, card_id: Option<i32>, halted: bool, reason: &str, audit: NewAuditEntry) -> Result<(), Error> {
+        if card_id.is_some_and(|card_id| !card::is_valid(card_id)) {
+            return Err(Error::CardNotFound);
+        }
+        let mut tx = self.begin().await?;
+        tx.set_trading_halt(card_id, halted, &audit.operator, reason).await.with_context(|| format!("Failed to set trading halt of {:?}", card_id))?;
+        tx.insert_audit_entry(audit).await.with_context(|| "Failed to insert audit entry")?;
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        let halts = self.order_store.query_trading_halts().await.with_context(|| "Failed to load trading halts")?;
+        let is_market_halted = halts.iter().any(|halt| halt.card_id.is_none());
+        future::try_join_all((0..self.cards.len() as i32).map(|card_id| {
+            let halted = is_market_halted || halts.iter().any(|halt| halt.card_id == Some(card_id));
+            self.request(card_id, move |reply| Command::SetHalted { halted, reply })
+        })).await?;
+        Ok(())
+    }
+
+    // Orders of a suspended trader are rejected by every card
+    pub async fn set_suspended(&self, trader_id: i64, suspended: bool, reason: &str, audit: NewAuditEntry) -> Result<(), Error> {
+        let mut tx = self.begin().await?;
+        tx.set_trader_suspension(trader_id, suspended, &audit.operator, reason).await.with_context(|| format!("Failed to set suspension of trader {}", trader_id))?;
+        tx.insert_audit_entry(audit).await.with_context(|| "Failed to insert audit entry")?;
+        tx.commit().await.with_context(|| "Commit transaction failed")?;
+        let mut suspended_traders = self.suspended_traders.write().unwrap();
+        if suspended {
+            suspended_traders.insert(trader_id);
+        } else {
+            suspended_traders.remove(&trader_id);
         }
+        Ok(())
+    }
+
+    async fn begin(&self) -> Result<Box<dyn OrderTransaction>> {
+        let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+        tx.fence(self.epoch).await?;
+        Ok(tx)
+    }
+
+    async fn request<T>(&self, card_id: i32, command: impl FnOnce(oneshot::Sender<Result<T, Error>>) -> Command) -> Result<T, Error> {
+        let matcher = usize::try_from(card_id).ok().and_then(|card_id| self.cards.get(card_id)).ok_or(Error::CardNotFound)?;
+        let (reply, response) = oneshot::channel();
+        matcher.send(command(reply)).await.map_err(|_| anyhow!("Matcher of card {} stopped", card_id))?;
+        response.await.map_err(|_| anyhow!("Matcher of card {} stopped", card_id))?
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    fn order(id: i64, side: Action, price: i32, quantity: i32) -> PendingOrder {
+        PendingOrder { id, side, price, card_id: 0, quantity, trader_id: id, self_trade_prevention: SelfTradePrevention::CancelNewest, order_type: OrderType::Limit, stop_price: None, display_quantity: None, slice_quantity: 0, post_only: PostOnly::Disabled, hidden: false }
     }
 
     #[test]
-    fn test_different_card_not_matching() {
-        // different card_id should not affect each other
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Sell,
-            price: 100,
-            card_id: 1,
-        };
-        assert!(order_manager.add_order(order2).is_none());
+    fn test_match_with_same_price() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Sell, 100, 1);
+        let filled_orders = order_book.add_order(order2).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 1, sell_order: 2, price: 100, quantity: 1, card_id: 0, first_order_id: 1}], filled_orders);
+    }
+
+    #[test]
+    fn test_match_with_first_come_order() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order2).is_empty());
+        let order3 = order(3, Action::Sell, 100, 1);
+        let filled_orders = order_book.add_order(order3).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 1, sell_order: 3, price: 100, quantity: 1, card_id: 0, first_order_id: 1}], filled_orders);
     }
 
     #[test]
     fn test_not_match_with_higher_sell_price() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Sell,
-            price: 101,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order2).is_none());
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Sell, 101, 1);
+        assert!(order_book.add_order(order2).is_empty());
     }
 
     #[test]
     fn test_not_match_with_lower_buy_price() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Sell,
-            price: 101,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order2).is_none());
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Sell, 101, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order2).is_empty());
     }
 
     #[test]
     fn test_higher_buy_price_should_be_matched_first() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Buy,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Buy,
-            price: 102,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order2).is_none());
-        let order3 = PendingOrder {
-            id: 3,
-            side: Action::Sell,
-            price: 99,
-            card_id: 0,
-        };        
-        let filled_order = order_manager.add_order(order3);
-        match filled_order {
-            Some(o) => {         
-                assert_eq!(FilledOrder{buy_order: 2, sell_order: 3, price: 102, card_id: 0, first_order_id: 2}, o);
-            },
-            None => assert!(false),
-        }
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Buy, 102, 1);
+        assert!(order_book.add_order(order2).is_empty());
+        let order3 = order(3, Action::Sell, 99, 1);
+        let filled_orders = order_book.add_order(order3).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 2, sell_order: 3, price: 102, quantity: 1, card_id: 0, first_order_id: 2}], filled_orders);
     }
 
     #[test]
     fn test_lower_sell_price_should_be_matched_first() {
-        let mut order_manager = OrderManager::new();
-        let order1 = PendingOrder {
-            id: 1,
-            side: Action::Sell,
-            price: 101,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order1).is_none());
-        let order2 = PendingOrder {
-            id: 2,
-            side: Action::Sell,
-            price: 100,
-            card_id: 0,
-        };
-        assert!(order_manager.add_order(order2).is_none());
-        let order3 = PendingOrder {
-            id: 3,
-            side: Action::Buy,
-            price: 101,
-            card_id: 0,
-        };        
-        let filled_order = order_manager.add_order(order3);
-        match filled_order {
-            Some(o) => {         
-                assert_eq!(FilledOrder{buy_order: 3, sell_order: 2, price: 100, card_id: 0, first_order_id: 2}, o);
-            },
-            None => assert!(false),
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Sell, 101, 1);
+        assert!(order_book.add_order(order1).is_empty());
+        let order2 = order(2, Action::Sell, 100, 1);
+        assert!(order_book.add_order(order2).is_empty());
+        let order3 = order(3, Action::Buy, 101, 1);
+        let filled_orders = order_book.add_order(order3).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 3, sell_order: 2, price: 100, quantity: 1, card_id: 0, first_order_id: 2}], filled_orders);
+    }
+
+    #[test]
+    fn test_cancelled_order_not_matching() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        assert!(order_book.cancel_order(&order1));
+        assert!(!order_book.cancel_order(&order1));
+        let order2 = order(2, Action::Sell, 100, 1);
+        assert!(order_book.add_order(order2).is_empty());
+    }
+
+    #[test]
+    fn test_filled_order_not_cancellable() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Sell, 100, 1);
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        let order2 = order(2, Action::Buy, 100, 1);
+        assert!(!order_book.add_order(order2).is_empty());
+        assert!(!order_book.cancel_order(&order1));
+    }
+
+    #[test]
+    fn test_partial_fill_across_price_levels() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(order(1, Action::Sell, 100, 2)).is_empty());
+        assert!(order_book.add_order(order(2, Action::Sell, 101, 2)).is_empty());
+        let filled_orders = order_book.add_order(order(3, Action::Buy, 101, 3)).filled_orders;
+        assert_eq!(vec![
+            FilledOrder{buy_order: 3, sell_order: 1, price: 100, quantity: 2, card_id: 0, first_order_id: 1},
+            FilledOrder{buy_order: 3, sell_order: 2, price: 101, quantity: 1, card_id: 0, first_order_id: 2},
+        ], filled_orders);
+        // order 2 keeps its remaining quantity
+        let filled_orders = order_book.add_order(order(4, Action::Buy, 101, 5)).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 4, sell_order: 2, price: 101, quantity: 1, card_id: 0, first_order_id: 2}], filled_orders);
+        // the rest of order 4 is resting
+        let filled_orders = order_book.add_order(order(5, Action::Sell, 100, 10)).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 4, sell_order: 5, price: 101, quantity: 4, card_id: 0, first_order_id: 4}], filled_orders);
+    }
+
+    #[test]
+    fn test_decreasing_quantity_keeps_priority() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 5);
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        assert!(order_book.add_order(order(2, Action::Buy, 100, 5)).is_empty());
+        assert_eq!(AmendResult::Amended(Matches::default()), order_book.amend_order(&order1, &Amendment{price: 100, quantity_delta: -3}));
+        let filled_orders = order_book.add_order(order(3, Action::Sell, 100, 3)).filled_orders;
+        assert_eq!(vec![
+            FilledOrder{buy_order: 1, sell_order: 3, price: 100, quantity: 2, card_id: 0, first_order_id: 1},
+            FilledOrder{buy_order: 2, sell_order: 3, price: 100, quantity: 1, card_id: 0, first_order_id: 2},
+        ], filled_orders);
+    }
+
+    #[test]
+    fn test_increasing_quantity_loses_priority() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Buy, 100, 1);
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        assert!(order_book.add_order(order(2, Action::Buy, 100, 1)).is_empty());
+        assert_eq!(AmendResult::Amended(Matches::default()), order_book.amend_order(&order1, &Amendment{price: 100, quantity_delta: 1}));
+        let filled_orders = order_book.add_order(order(3, Action::Sell, 100, 1)).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 2, sell_order: 3, price: 100, quantity: 1, card_id: 0, first_order_id: 2}], filled_orders);
+    }
+
+    #[test]
+    fn test_price_amendment_matches_immediately() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(order(1, Action::Sell, 105, 1)).is_empty());
+        let order2 = order(2, Action::Buy, 100, 2);
+        assert!(order_book.add_order(order2.clone()).is_empty());
+        let amend_result = order_book.amend_order(&order2, &Amendment{price: 105, quantity_delta: 0});
+        assert_eq!(AmendResult::Amended(Matches {
+            filled_orders: vec![FilledOrder{buy_order: 2, sell_order: 1, price: 105, quantity: 1, card_id: 0, first_order_id: 1}],
+            ..Matches::default()
+        }), amend_result);
+        // the remaining quantity rests at the new price
+        let filled_orders = order_book.add_order(order(3, Action::Sell, 105, 1)).filled_orders;
+        assert_eq!(vec![FilledOrder{buy_order: 2, sell_order: 3, price: 105, quantity: 1, card_id: 0, first_order_id: 2}], filled_orders);
+    }
+
+    #[test]
+    fn test_amend_rejected_or_not_found() {
+        let mut order_book = OrderBook::new();
+        let order1 = order(1, Action::Sell, 100, 2);
+        assert_eq!(AmendResult::NotFound, order_book.amend_order(&order1, &Amendment{price: 100, quantity_delta: -1}));
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        assert_eq!(AmendResult::Rejected, order_book.amend_order(&order1, &Amendment{price: 100, quantity_delta: -2}));
+        assert_eq!(AmendResult::NotFound, order_book.amend_order(&order(1, Action::Sell, 101, 2), &Amendment{price: 100, quantity_delta: 0}));
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let prevented = |order_id, counterparty_order_id, price, quantity, is_cancelled| PreventedMatch { order_id, counterparty_order_id, price, quantity, is_cancelled };
+        let filled = FilledOrder{buy_order: 3, sell_order: 2, price: 101, quantity: 1, card_id: 0, first_order_id: 2};
+        let cases = [
+            (SelfTradePrevention::CancelNewest, vec![prevented(3, 1, 101, 3, true)], vec![], vec![1, 2]),
+            (SelfTradePrevention::CancelOldest, vec![prevented(1, 3, 100, 2, true)], vec![filled.clone()], vec![3]),
+            (SelfTradePrevention::CancelBoth, vec![prevented(3, 1, 101, 3, true), prevented(1, 3, 100, 2, true)], vec![], vec![2]),
+            (SelfTradePrevention::Decrement, vec![prevented(3, 1, 101, 2, false), prevented(1, 3, 100, 2, true)], vec![filled.clone()], vec![]),
+            (SelfTradePrevention::Disabled, vec![], vec![
+                FilledOrder{buy_order: 3, sell_order: 1, price: 100, quantity: 2, card_id: 0, first_order_id: 1},
+                filled.clone(),
+            ], vec![]),
+        ];
+        for (self_trade_prevention, prevented_matches, filled_orders, resting_orders) in cases {
+            let mut order_book = OrderBook::new();
+            assert!(order_book.add_order(PendingOrder { trader_id: 3, ..order(1, Action::Sell, 100, 2) }).is_empty());
+            assert!(order_book.add_order(order(2, Action::Sell, 101, 1)).is_empty());
+            let matches = order_book.add_order(PendingOrder { self_trade_prevention, ..order(3, Action::Buy, 101, 3) });
+            assert_eq!(Matches { filled_orders, prevented_matches, ..Matches::default() }, matches, "{:?}", self_trade_prevention);
+            assert_eq!(resting_orders, order_book.resting_orders().map(|order| order.id).collect::<Vec<i64>>(), "{:?}", self_trade_prevention);
         }
     }
-}
\ No newline at end of file
+
+    fn stop_order(id: i64, side: Action, order_type: OrderType, price: i32, stop_price: i32, quantity: i32) -> PendingOrder {
+        PendingOrder { order_type, stop_price: Some(stop_price), ..order(id, side, price, quantity) }
+    }
+
+    #[test]
+    fn test_stop_orders_triggered_by_trades() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(order(1, Action::Sell, 100, 1)).is_empty());
+        assert!(order_book.add_order(order(2, Action::Buy, 95, 5)).is_empty());
+        assert!(order_book.add_order(stop_order(3, Action::Sell, OrderType::StopMarket, 98, 98, 2)).is_empty());
+        assert!(order_book.add_order(stop_order(4, Action::Sell, OrderType::StopLimit, 96, 99, 1)).is_empty());
+        assert!(order_book.add_order(stop_order(5, Action::Buy, OrderType::StopMarket, 100, 100, 5)).is_empty());
+        // stop orders are not in the book until triggered
+        assert_eq!(vec![2, 1], order_book.resting_orders().map(|order| order.id).collect::<Vec<i64>>());
+        assert_eq!(vec![5, 4, 3], order_book.stop_orders().map(|order| order.id).collect::<Vec<i64>>());
+
+        // the trade at 95 reaches both sell stops, the higher stop price first
+        let matches = order_book.add_order(order(6, Action::Sell, 95, 1));
+        assert_eq!(vec![
+            FilledOrder{buy_order: 2, sell_order: 6, price: 95, quantity: 1, card_id: 0, first_order_id: 2},
+            FilledOrder{buy_order: 2, sell_order: 3, price: 95, quantity: 2, card_id: 0, first_order_id: 2},
+        ], matches.filled_orders);
+        assert_eq!(vec![4, 3], matches.triggered_orders.iter().map(|order| order.id).collect::<Vec<i64>>());
+        assert!(matches.expired_orders.is_empty());
+        // the stop limit rests at its limit price
+        assert_eq!(vec![2, 4, 1], order_book.resting_orders().map(|order| order.id).collect::<Vec<i64>>());
+
+        // the trade at 100 triggers the buy stop, nothing is left to buy so it expires
+        let matches = order_book.add_order(order(7, Action::Buy, 100, 1));
+        assert_eq!(vec![FilledOrder{buy_order: 7, sell_order: 4, price: 96, quantity: 1, card_id: 0, first_order_id: 4}], matches.filled_orders);
+        assert!(matches.triggered_orders.is_empty());
+        let matches = order_book.add_order(order(8, Action::Buy, 100, 1));
+        assert_eq!(vec![8], matches.filled_orders.iter().map(|filled| filled.buy_order).collect::<Vec<i64>>());
+        assert_eq!(vec![5], matches.triggered_orders.iter().map(|order| order.id).collect::<Vec<i64>>());
+        assert_eq!(vec![5], matches.expired_orders.iter().map(|order| order.id).collect::<Vec<i64>>());
+        assert_eq!(vec![2], order_book.resting_orders().map(|order| order.id).collect::<Vec<i64>>());
+        assert_eq!(0, order_book.stop_orders().count());
+    }
+
+    #[test]
+    fn test_cancel_and_snapshot_stop_orders() {
+        let mut order_book = OrderBook::new();
+        let order1 = stop_order(1, Action::Sell, OrderType::StopLimit, 95, 98, 1);
+        assert!(order_book.add_order(order1.clone()).is_empty());
+        assert!(order_book.add_order(stop_order(2, Action::Buy, OrderType::StopMarket, 105, 105, 1)).is_empty());
+        let restored = OrderBook::from_snapshot(&order_book.snapshot(0, 0));
+        assert_eq!(order_book.stop_orders().collect::<Vec<_>>(), restored.stop_orders().collect::<Vec<_>>());
+        assert_eq!(0, restored.resting_orders().count());
+        assert!(order_book.cancel_order(&order1));
+        assert!(!order_book.cancel_order(&order1));
+        assert_eq!(vec![2], order_book.stop_orders().map(|order| order.id).collect::<Vec<i64>>());
+    }
+
+    fn level(price: i32, quantity: i32, orders: usize) -> PriceLevel {
+        PriceLevel { price, quantity, orders }
+    }
+
+    #[test]
+    fn test_iceberg_slice_refills_behind_the_queue() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(PendingOrder { display_quantity: Some(3), ..order(1, Action::Sell, 100, 10) }).is_empty());
+        assert!(order_book.add_order(order(2, Action::Sell, 100, 2)).is_empty());
+        assert!(order_book.add_order(PendingOrder { display_quantity: Some(2), ..order(3, Action::Buy, 90, 5) }).is_empty());
+        // only the slices are visible
+        assert_eq!((vec![level(90, 2, 1)], vec![level(100, 5, 2)]), order_book.depth(10));
+
+        let filled_orders = order_book.add_order(order(4, Action::Buy, 100, 4)).filled_orders;
+        assert_eq!(vec![
+            FilledOrder{buy_order: 4, sell_order: 1, price: 100, quantity: 3, card_id: 0, first_order_id: 1},
+            FilledOrder{buy_order: 4, sell_order: 2, price: 100, quantity: 1, card_id: 0, first_order_id: 2},
+        ], filled_orders);
+        assert_eq!(vec![level(100, 4, 2)], order_book.depth(10).1);
+
+        // order 1 is behind order 2 with its next slice
+        let filled_orders = order_book.add_order(order(5, Action::Buy, 100, 5)).filled_orders;
+        assert_eq!(vec![(2, 1), (1, 3), (1, 1)], filled_orders.iter().map(|filled| (filled.sell_order, filled.quantity)).collect::<Vec<_>>());
+        assert_eq!(vec![level(100, 2, 1)], order_book.depth(10).1);
+        assert_eq!(3, order_book.resting_orders().find(|order| order.id == 1).unwrap().quantity);
+        assert_eq!(order_book.depth(10), OrderBook::from_snapshot(&order_book.snapshot(0, 0)).depth(10));
+        assert_eq!((vec![], vec![]), order_book.depth(0));
+    }
+
+    #[test]
+    fn test_post_only_rejected_or_repriced() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(order(1, Action::Sell, 100, 1)).is_empty());
+        assert!(order_book.add_order(order(2, Action::Buy, 95, 1)).is_empty());
+        let order3 = PendingOrder { post_only: PostOnly::Reject, ..order(3, Action::Buy, 100, 1) };
+        assert_eq!(Matches { rejected_orders: vec![order3.clone()], ..Matches::default() }, order_book.add_order(order3));
+        let matches = order_book.add_order(PendingOrder { post_only: PostOnly::Reprice, ..order(4, Action::Sell, 90, 2) });
+        assert_eq!(Matches { repriced_orders: vec![RepricedOrder { order_id: 4, price: 96, previous_price: 90, quantity: 2 }], ..Matches::default() }, matches);
+        // orders that don't cross rest as they are
+        assert!(order_book.add_order(PendingOrder { post_only: PostOnly::Reject, ..order(5, Action::Buy, 94, 1) }).is_empty());
+        assert_eq!((vec![level(95, 1, 1), level(94, 1, 1)], vec![level(96, 2, 1), level(100, 1, 1)]), order_book.depth(10));
+
+        // one tick behind on a card with a larger tick size
+        let mut order_book = OrderBook::for_card(3);
+        assert!(order_book.add_order(order(1, Action::Sell, 1000, 10)).is_empty());
+        let matches = order_book.add_order(PendingOrder { post_only: PostOnly::Reprice, ..order(2, Action::Buy, 1000, 10) });
+        assert_eq!(vec![RepricedOrder { order_id: 2, price: 990, previous_price: 1000, quantity: 10 }], matches.repriced_orders);
+    }
+
+    #[test]
+    fn test_hidden_orders_behind_displayed() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(PendingOrder { hidden: true, ..order(1, Action::Sell, 100, 2) }).is_empty());
+        assert!(order_book.add_order(order(2, Action::Sell, 100, 1)).is_empty());
+        assert!(order_book.add_order(PendingOrder { hidden: true, ..order(3, Action::Sell, 101, 1) }).is_empty());
+        assert_eq!(vec![level(100, 1, 1)], order_book.depth(10).1);
+        let filled_orders = order_book.add_order(order(4, Action::Buy, 100, 2)).filled_orders;
+        assert_eq!(vec![(2, 1), (1, 1)], filled_orders.iter().map(|filled| (filled.sell_order, filled.quantity)).collect::<Vec<_>>());
+        // the next slice of an iceberg order stays ahead of hidden orders
+        assert!(order_book.add_order(PendingOrder { display_quantity: Some(1), ..order(5, Action::Sell, 100, 2) }).is_empty());
+        let filled_orders = order_book.add_order(order(6, Action::Buy, 100, 3)).filled_orders;
+        assert_eq!(vec![5, 5, 1], filled_orders.iter().map(|filled| filled.sell_order).collect::<Vec<i64>>());
+        assert_eq!((vec![], vec![]), order_book.depth(10));
+        assert_eq!(vec![3], order_book.resting_orders().map(|order| order.id).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn test_call_auction_uncrosses_at_one_price() {
+        let mut order_book = OrderBook::new();
+        assert_eq!(None, order_book.auction_indication());
+        order_book.open_auction();
+        for order in [order(1, Action::Buy, 110, 3), order(2, Action::Buy, 105, 2), order(3, Action::Buy, 100, 1),
+            order(4, Action::Sell, 95, 2), order(5, Action::Sell, 100, 2), order(6, Action::Sell, 108, 4)] {
+            assert!(order_book.add_order(order).is_empty());
+        }
+        // 4 would be executed at 100 and 105, 105 leaves the least surplus
+        assert_eq!(Some(AuctionIndication { price: Some(105), volume: 4 }), order_book.auction_indication());
+        let restored = OrderBook::from_snapshot(&order_book.snapshot(0, 0));
+        assert_eq!(order_book.auction_indication(), restored.auction_indication());
+
+        let (auction_price, matches) = order_book.uncross();
+        assert_eq!(Some((105, 4)), auction_price);
+        assert_eq!(vec![(1, 4, 2), (1, 5, 1), (2, 5, 1)], matches.filled_orders.iter()
+            .map(|filled| (filled.buy_order, filled.sell_order, filled.quantity)).collect::<Vec<_>>());
+        assert!(matches.filled_orders.iter().all(|filled| filled.price == 105));
+        assert_eq!((vec![level(105, 1, 1), level(100, 1, 1)], vec![level(108, 4, 1)]), order_book.depth(10));
+        // Continuous matching afterwards
+        assert_eq!(None, order_book.auction_indication());
+        assert_eq!(1, order_book.add_order(order(7, Action::Sell, 105, 1)).filled_orders.len());
+    }
+
+    #[test]
+    fn test_auction_price_tie_breakers() {
+        let mut order_book = OrderBook::new();
+        order_book.open_auction();
+        order_book.add_order(order(1, Action::Buy, 100, 5));
+        order_book.add_order(order(2, Action::Sell, 98, 2));
+        order_book.add_order(order(3, Action::Sell, 99, 1));
+        // 3 at 99 and 100 with buy surplus at both, the highest is taken
+        assert_eq!(Some(AuctionIndication { price: Some(100), volume: 3 }), order_book.auction_indication());
+
+        let mut order_book = OrderBook::new();
+        order_book.add_order(order(1, Action::Buy, 103, 1));
+        order_book.add_order(order(2, Action::Sell, 103, 1));
+        order_book.open_auction();
+        order_book.add_order(order(3, Action::Buy, 102, 1));
+        order_book.add_order(order(4, Action::Sell, 100, 1));
+        // No surplus at 100 to 102, the closest to the last trade is taken
+        assert_eq!(Some(AuctionIndication { price: Some(102), volume: 1 }), order_book.auction_indication());
+        order_book.cancel_order(&order(3, Action::Buy, 102, 1));
+        assert_eq!(Some(AuctionIndication { price: None, volume: 0 }), order_book.auction_indication());
+        assert_eq!((None, Matches::default()), order_book.uncross());
+    }
+
+    fn entry(sequence: i64, kind: JournalKind, order_id: i64, side: Action, price: i32, quantity: i32, previous_price: Option<i32>) -> JournalEntry {
+        JournalEntry { sequence, card_id: 0, kind: kind as i16, order_id: Some(order_id), side: side as i16, price, quantity, previous_price, trader_id: Some(order_id), self_trade_prevention: SelfTradePrevention::CancelNewest as i16, order_type: OrderType::Limit as i16, stop_price: None, display_quantity: None, post_only: PostOnly::Disabled as i16, hidden: false, tick_size: 1 }
+    }
+
+    #[test]
+    fn test_replay_journal() {
+        let mut order_book = OrderBook::new();
+        let journal = [
+            entry(1, JournalKind::New, 1, Action::Sell, 100, 2, None),
+            entry(2, JournalKind::New, 2, Action::Sell, 100, 2, None),
+            entry(3, JournalKind::New, 3, Action::Buy, 90, 1, None),
+            // order 1 goes behind order 2
+            entry(4, JournalKind::Amend, 1, Action::Sell, 100, 1, Some(100)),
+            entry(5, JournalKind::Cancel, 3, Action::Buy, 90, 0, None),
+            entry(6, JournalKind::New, 4, Action::Buy, 100, 3, None),
+        ];
+        let filled_orders: Vec<FilledOrder> = journal.iter().flat_map(|entry| order_book.apply(entry).unwrap().filled_orders).collect();
+        assert_eq!(vec![
+            FilledOrder{buy_order: 4, sell_order: 2, price: 100, quantity: 2, card_id: 0, first_order_id: 2},
+            FilledOrder{buy_order: 4, sell_order: 1, price: 100, quantity: 1, card_id: 0, first_order_id: 1},
+        ], filled_orders);
+        assert_eq!(vec![&order(1, Action::Sell, 100, 2)], order_book.resting_orders().collect::<Vec<_>>());
+        assert!(OrderBook::new().apply(&JournalEntry { kind: 9, ..entry(1, JournalKind::New, 1, Action::Buy, 100, 1, None) }).is_err());
+        // post-only orders are repriced by the tick size of the entry, not the current one of the card
+        let mut order_book = OrderBook::for_card(3);
+        order_book.apply(&entry(1, JournalKind::New, 1, Action::Sell, 1000, 10, None)).unwrap();
+        let repriced = JournalEntry { post_only: PostOnly::Reprice as i16, ..entry(2, JournalKind::New, 2, Action::Buy, 1000, 10, None) };
+        assert_eq!(999, order_book.apply(&repriced).unwrap().repriced_orders[0].price);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_priority() {
+        let mut order_book = OrderBook::new();
+        assert!(order_book.add_order(order(1, Action::Buy, 100, 1)).is_empty());
+        assert!(order_book.add_order(order(2, Action::Buy, 100, 2)).is_empty());
+        assert!(order_book.add_order(order(3, Action::Sell, 105, 1)).is_empty());
+        let mut restored = OrderBook::from_snapshot(&order_book.snapshot(0, 0));
+        assert_eq!(order_book.resting_orders().collect::<Vec<_>>(), restored.resting_orders().collect::<Vec<_>>());
+        let filled_orders = restored.add_order(order(4, Action::Sell, 100, 2)).filled_orders;
+        assert_eq!(vec![1, 2], filled_orders.iter().map(|filled| filled.first_order_id).collect::<Vec<i64>>());
+    }
+}
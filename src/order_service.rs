@@ -1,88 +1,1621 @@
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use futures::future;
 use async_trait::async_trait;
-use anyhow::{anyhow, Result, Context};
-use chrono::Utc;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
 
-use crate::ports::{OrderService, TraderStore, OrderStore, TradeStore, Status, NewOrder, Action};
-use crate::order_manager::{self, OrderManager};
+use crate::card;
+use log::{info, warn};
+use tokio::sync::broadcast;
+
+use crate::ports::{OrderService, TraderStore, OrderStore, Status, NewOrder, Order, Actor, PlaceOrder, Action, OrderType, BookSnapshot, Depth, CallAuction, CardStatus, TradingState,
+  SessionPhase, ClosureKind, MarketClosure, SessionStatus, Ticker, MarketDataEvent, AdminAction, NewAuditEntry, TraderRiskLimits, Role};
+use crate::order_manager::OrderManager;
+use crate::error::Error;
+use crate::rate_limit::RateLimits;
+use crate::session::{self, TradingCalendar};
+use crate::risk::RiskLimits;
+
+// Events a subscriber may fall behind by before it misses some
+const MARKET_DATA_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeeSchedule {
+  // Basis points of the trade amount, the maker is the resting order
+  pub maker_bps: i32,
+  pub taker_bps: i32,
+}
+impl FeeSchedule {
+  pub fn maker_fee(&self, price: i32, quantity: i32) -> i32 {
+    (price as i64 * quantity as i64 * self.maker_bps as i64 / 10000) as i32
+  }
+  pub fn taker_fee(&self, price: i32, quantity: i32) -> i32 {
+    (price as i64 * quantity as i64 * self.taker_bps as i64 / 10000) as i32
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PriceLimits {
+  // Basis points around the reference price that order prices must be within, 0 disables the band
+  pub band_bps: i32,
+  // Basis points the price may move within the window before the card halts, 0 disables the circuit breaker
+  pub breaker_bps: i32,
+  pub breaker_window: Duration,
+  // A halted card re-opens through a call auction of this length
+  pub halt_duration: Duration,
+}
+impl PriceLimits {
+  // Lowest and highest allowed price around the last trade or auction price
+  pub fn band(&self, reference_price: i32) -> Option<(i32, i32)> {
+    if self.band_bps == 0 {
+      return None;
+    }
+    let width = (reference_price as i64 * self.band_bps as i64 / 10000) as i32;
+    Some((reference_price - width, reference_price + width))
+  }
+  pub fn is_breached(&self, reference_price: i32, price: i32) -> bool {
+    self.breaker_bps > 0 && (price - reference_price).abs() as i64 * 10000 > reference_price as i64 * self.breaker_bps as i64
+  }
+}
+
+// Every entry point goes through here, so the rules of the card catalog apply to all of them
+fn check_trading_rules(order: &PlaceOrder) -> Result<(), Error> {
+  let card = card::find(order.card_id).ok_or(Error::InvalidCardId)?;
+  if let Some(stop_price) = order.stop_price {
+    if !card.is_valid_price(stop_price) {
+      return Err(Error::InvalidStopPrice);
+    }
+  }
+  if !card.is_valid_price(order.price) {
+    return Err(Error::InvalidPrice);
+  }
+  if !card.is_valid_quantity(order.quantity) {
+    return Err(Error::InvalidQuantity);
+  }
+  if let Some(display_quantity) = order.display_quantity {
+    // Stop market and hidden orders show nothing to cap
+    if order.order_type == OrderType::StopMarket || order.hidden || display_quantity > order.quantity || !card.is_valid_quantity(display_quantity) {
+      return Err(Error::InvalidDisplayQuantity);
+    }
+  }
+  Ok(())
+}
 
 #[derive(Clone)]
-pub struct OrderServiceImpl<A: TraderStore, B: OrderStore, C: TradeStore> {
+pub struct OrderServiceImpl<A: TraderStore, B: OrderStore> {
   pub trader_store: A,
-  pub order_store: B,
-  pub trade_store: C,
-  order_manager: Arc<Mutex<OrderManager>>,
+  pub order_store: Arc<B>,
+  fee_schedule: FeeSchedule,
+  price_limits: PriceLimits,
+  // Caps order entry messages per trader before they get to the order manager
+  rate_limits: Arc<RateLimits>,
+  // Only set while this instance is the leader
+  order_manager: Arc<RwLock<Option<OrderManager>>>,
+  calendar: TradingCalendar,
+  // Closures which haven't ended, refreshed by run_sessions
+  closures: Arc<RwLock<Vec<MarketClosure>>>,
+  // The phase order entry is in, it changes once the leader has opened the auctions of the new phase
+  phase: Arc<RwLock<SessionPhase>>,
+  market_data: broadcast::Sender<MarketDataEvent>,
+  // Configured defaults and the per-trader overrides, refreshed by refresh_risk_limits
+  risk_limits: RiskLimits,
+  risk_overrides: Arc<RwLock<HashMap<i64, TraderRiskLimits>>>,
 }
-impl <A, B, C> OrderServiceImpl<A, B, C>  
+impl <A, B> OrderServiceImpl<A, B>  
   where A: TraderStore + Sync + Send,
-        B: OrderStore + Sync + Send,
-        C: TradeStore + Sync + Send {
-  pub async fn new(trader_store: A, order_store: B, trade_store: C) -> Self {
-    let order_manager = Arc::new(Mutex::new(OrderManager::from_db(&order_store).await));
+        B: OrderStore + Sync + Send + 'static {
+  // Order entry is rejected until the engine is started
+  pub fn new(trader_store: A, order_store: B, fee_schedule: FeeSchedule, price_limits: PriceLimits, rate_limits: Arc<RateLimits>, calendar: TradingCalendar, risk_limits: RiskLimits) -> Self {
+    // Closed until the leader moves to the phase of the session hours
+    let phase = if calendar.hours.is_some() { SessionPhase::Closed } else { SessionPhase::Continuous };
     Self {
       trader_store,
-      order_store,
-      trade_store,
-      order_manager,
+      order_store: Arc::new(order_store),
+      fee_schedule,
+      price_limits,
+      rate_limits,
+      order_manager: Arc::new(RwLock::new(None)),
+      calendar,
+      closures: Arc::new(RwLock::new(vec![])),
+      phase: Arc::new(RwLock::new(phase)),
+      market_data: broadcast::channel(MARKET_DATA_CAPACITY).0,
+      risk_limits,
+      risk_overrides: Arc::new(RwLock::new(HashMap::new())),
+    }
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<MarketDataEvent> {
+    self.market_data.subscribe()
+  }
+
+  // Rebuilds the books from the database and starts matching
+  pub async fn start(&self, epoch: i64) -> anyhow::Result<()> {
+    let order_manager = OrderManager::start(self.order_store.clone(), self.fee_schedule, self.price_limits, epoch).await?;
+    *self.order_manager.write().unwrap() = Some(order_manager);
+    Ok(())
+  }
+
+  // The matchers stop once the commands in flight are done
+  pub fn stop(&self) {
+    self.order_manager.write().unwrap().take();
+  }
+
+  fn order_manager(&self) -> Result<OrderManager, Error> {
+    self.order_manager.read().unwrap().clone().ok_or(Error::NotLeader)
+  }
+
+  // Orders are accepted from pre-open until the close, cancellations at any time
+  fn check_phase(&self) -> Result<(), Error> {
+    match *self.phase.read().unwrap() {
+      SessionPhase::Closed => Err(Error::MarketClosed),
+      _ => Ok(()),
+    }
+  }
+
+  fn calendar_phase(&self, now: DateTime<Utc>) -> SessionPhase {
+    self.calendar.phase(now, &self.closures.read().unwrap())
+  }
+
+  // Opens the auctions of the new phase and cancels the day orders at the close
+  async fn enter_phase(&self, order_manager: &OrderManager, previous: SessionPhase, phase: SessionPhase, now: DateTime<Utc>) -> Result<(), Error> {
+    let window = self.calendar.hours.and_then(|hours| hours.auction_window(now.naive_utc().date(), phase));
+    if let Some((opens_at, closes_at)) = window {
+      for card_id in 0..card::NUM_CARDS as i32 {
+        let auctions = self.order_store.query_call_auctions(card_id).await.with_context(|| "Query call auctions failed")?;
+        if !auctions.iter().any(|auction| auction.opens_at == opens_at) {
+          self.order_store.insert_call_auction(card_id, opens_at, closes_at).await.with_context(|| "Insert call auction failed")?;
+        }
+      }
+      self.run_call_auctions(now).await?;
+    }
+    if phase == SessionPhase::Closed && previous != SessionPhase::Closed && self.calendar.cancel_day_orders {
+      let orders = self.order_store.query_day_orders().await.with_context(|| "Query day orders failed")?;
+      let mut card_orders: BTreeMap<i32, Vec<Order>> = BTreeMap::new();
+      for order in orders {
+        card_orders.entry(order.card_id).or_default().push(order);
+      }
+      for (card_id, orders) in card_orders {
+        let cancelled_ids = order_manager.expire_orders(card_id, orders).await?;
+        info!("Cancelled {} day orders of card {} at the close", cancelled_ids.len(), card_id);
+      }
+    }
+    Ok(())
+  }
+
+  fn trader_risk_limits(&self, trader_id: i64) -> RiskLimits {
+    self.risk_limits.with_overrides(self.risk_overrides.read().unwrap().get(&trader_id))
+  }
+
+  async fn check_trader(&self, trader_id: i64) -> Result<(), Error> {
+    match self.trader_store.is_exist(trader_id).await {
+      Some(true) => Ok(()),
+      Some(false) => Err(Error::TraderNotFound),
+      None => Err(anyhow!("Failed to query trader: {}", trader_id).into()),
+    }
+  }
+
+  // Cancels the trader's pending orders card by card, returns the cancelled ids in order
+  async fn cancel_pending_orders(&self, order_manager: &OrderManager, trader_id: i64, card_id: Option<i32>, side: Option<Action>, actor: Actor) -> Result<Vec<i64>, Error> {
+    let orders = self.order_store.query_trader_pending_orders(trader_id, card_id, side.map(|side| side as i16)).await.with_context(|| "Query orders failed")?;
+    let mut card_orders: BTreeMap<i32, Vec<Order>> = BTreeMap::new();
+    for order in orders {
+      card_orders.entry(order.card_id).or_default().push(order);
+    }
+    let card_ids: Vec<i32> = card_orders.keys().copied().collect();
+    let cancellations = card_orders.into_iter().map(|(card_id, orders)| order_manager.cancel_orders(card_id, orders, actor.clone()));
+    let mut cancelled_ids: Vec<i64> = future::try_join_all(cancellations).await?.into_iter().flatten().collect();
+    cancelled_ids.sort_unstable();
+    self.publish_tickers(card_ids).await;
+    Ok(cancelled_ids)
+  }
+
+  // Skipped without subscribers, the ticker needs the matching engine; called after the change
+  // is committed, so a failure is only logged and the card's next change publishes it again
+  async fn publish_tickers(&self, card_ids: impl IntoIterator<Item = i32>) {
+    if self.market_data.receiver_count() == 0 {
+      return;
+    }
+    for card_id in card_ids {
+      match self.ticker(card_id).await {
+        // Fails only if the last subscriber left meanwhile
+        Ok(ticker) => { let _ = self.market_data.send(MarketDataEvent::Ticker(ticker)); },
+        Err(e) => warn!("Failed to publish the ticker of card {}: {:#}", card_id, e),
+      }
     }
   }
 }
 
 #[async_trait]
-impl <A, B, C> OrderService for OrderServiceImpl<A, B, C> 
+impl <A, B> OrderService for OrderServiceImpl<A, B> 
   where A: TraderStore + Sync + Send,
-        B: OrderStore + Sync + Send,
-        C: TradeStore + Sync + Send {
-  async fn add_order(&self, trader_id: i64, side: Action, price: i32, card_id: i32) -> Result<()> {
-    let is_trader_exist = self.trader_store.is_exist(trader_id).await;
-    if is_trader_exist.is_none() || is_trader_exist.unwrap() == false {
-      return Err(anyhow!("Trader not exist"));
-    }
-
-    let order_id = self.order_store.insert_order(NewOrder{
-        card_id,
-        price,
-        action: side.clone(),
-        status: Status::Pending as i16,
-        trader_id,
-        created_at: Utc::now(),
-    }).await.with_context(|| "Insert order failed")?;
-
-    let filled_order = self.order_manager.lock().unwrap().add_order(order_manager::PendingOrder {
-        id: order_id,
-        side,
-        price,
-        card_id,
+        B: OrderStore + Sync + Send + 'static {
+  async fn add_order(&self, trader_id: i64, order: PlaceOrder) -> Result<Order, Error> {
+    self.add_orders(trader_id, vec![order]).await?.pop().ok_or_else(|| anyhow!("Insert order failed"))?
+  }
+
+  async fn add_orders(&self, trader_id: i64, orders: Vec<PlaceOrder>) -> Result<Vec<Result<Order, Error>>, Error> {
+    // Orders breaking the trading rules are answered on their own, the rest go on
+    let mut results: Vec<Option<Result<Order, Error>>> = orders.iter().map(|order| check_trading_rules(order).err().map(Err)).collect();
+    if results.iter().all(Option::is_some) {
+      return Ok(results.into_iter().flatten().collect());
+    }
+    let order_manager = self.order_manager()?;
+    self.check_phase()?;
+    self.check_trader(trader_id).await?;
+    self.rate_limits.check_orders(trader_id, results.iter().filter(|result| result.is_none()).count()).await?;
+
+    let client_order_ids: Vec<Option<String>> = orders.iter().map(|order| order.client_order_id.clone()).collect();
+    for (order, result) in orders.iter().zip(results.iter_mut()) {
+      if let (Some(client_order_id), None) = (&order.client_order_id, &result) {
+        let existing_order = self.order_store.query_order_by_client_id(trader_id, client_order_id).await.with_context(|| "Query order failed")?;
+        *result = existing_order.map(Ok);
+      }
+    }
+    if results.iter().all(Option::is_some) {
+      return Ok(results.into_iter().flatten().collect());
+    }
+    // Orders in flight aren't counted, so concurrent requests of a trader may go over a limit by their size
+    let risk_limits = self.trader_risk_limits(trader_id);
+    if !risk_limits.is_disabled() {
+      let new_orders = orders.iter().zip(&results).filter(|(_, result)| result.is_none()).map(|(order, _)| order);
+      risk_limits.check_orders(new_orders, |card_id| order_manager.exposure(trader_id, card_id))?;
+    }
+
+    // Positions in the request and the new orders, grouped by card
+    let created_at = Utc::now();
+    let mut card_orders: BTreeMap<i32, (Vec<usize>, Vec<NewOrder>)> = BTreeMap::new();
+    for (index, order) in orders.into_iter().enumerate() {
+      if results[index].is_some() {
+        continue;
+      }
+      let (indexes, new_orders) = card_orders.entry(order.card_id).or_default();
+      indexes.push(index);
+      new_orders.push(NewOrder{
+          card_id: order.card_id,
+          price: order.price,
+          quantity: order.quantity,
+          action: order.side,
+          status: Status::Pending as i16,
+          trader_id,
+          created_at,
+          client_order_id: order.client_order_id,
+          self_trade_prevention: order.self_trade_prevention,
+          order_type: order.order_type,
+          stop_price: order.stop_price,
+          display_quantity: order.display_quantity,
+          post_only: order.post_only,
+          hidden: order.hidden,
+          time_in_force: order.time_in_force,
+      });
+    }
+    let card_ids: Vec<i32> = card_orders.keys().copied().collect();
+    let order_manager = &order_manager;
+    // Every card runs to completion, a failed card answers each of its orders with its error
+    let placements = card_orders.into_iter().map(|(card_id, (indexes, new_orders))| async move {
+      (indexes, order_manager.add_orders(card_id, new_orders).await)
     });
+    for (indexes, placed_orders) in future::join_all(placements).await {
+      match placed_orders {
+        Ok(placed_orders) => {
+          for (index, order) in indexes.into_iter().zip(placed_orders) {
+            results[index] = order.transpose();
+          }
+        },
+        Err(e) => {
+          for index in indexes {
+            results[index] = Some(Err(e.clone()));
+          }
+        },
+      }
+    }
+    self.publish_tickers(card_ids).await;
+
+    // None is left if the client_order_id was used concurrently or earlier in this batch
+    let mut placed_orders = Vec::with_capacity(results.len());
+    for (result, client_order_id) in results.into_iter().zip(client_order_ids) {
+      let result = match (result, client_order_id) {
+        (Some(result), _) => result,
+        (None, Some(client_order_id)) => self.order_store.query_order_by_client_id(trader_id, &client_order_id).await.with_context(|| "Query order failed")
+          .and_then(|order| order.ok_or_else(|| anyhow!("Order not exist: {}", client_order_id)))
+          .map_err(Error::from),
+        (None, None) => Err(anyhow!("Insert order failed").into()),
+      };
+      placed_orders.push(result);
+    }
+    Ok(placed_orders)
+  }
+
+  async fn cancel_order(&self, order: &Order) -> Result<(), Error> {
+    if order.status != Status::Pending as i16 {
+      return Err(Error::OrderNotPending);
+    }
+    let order_manager = self.order_manager()?;
+    self.rate_limits.check_orders(order.trader_id, 1).await?;
+    let cancelled_ids = order_manager.cancel_orders(order.card_id, vec![order.clone()], Actor::Trader(order.trader_id)).await?;
+    if cancelled_ids.is_empty() {
+      return Err(Error::OrderNotPending);
+    }
+    self.publish_tickers([order.card_id]).await;
+    Ok(())
+  }
+
+  async fn cancel_orders(&self, trader_id: i64, card_id: Option<i32>, side: Option<Action>) -> Result<Vec<i64>, Error> {
+    let order_manager = self.order_manager()?;
+    self.rate_limits.check_orders(trader_id, 1).await?;
+    self.cancel_pending_orders(&order_manager, trader_id, card_id, side, Actor::Trader(trader_id)).await
+  }
+
+  async fn amend_order(&self, order: &Order, price: Option<i32>, quantity: Option<i32>) -> Result<Order, Error> {
+    if order.status != Status::Pending as i16 {
+      return Err(Error::OrderNotPending);
+    }
+    let card = card::find(order.card_id).ok_or(Error::CardNotFound)?;
+    if matches!(price, Some(price) if !card.is_valid_price(price)) {
+      return Err(Error::InvalidPrice);
+    }
+    if matches!(quantity, Some(quantity) if !card.is_valid_quantity(quantity)) {
+      return Err(Error::InvalidQuantity);
+    }
+    let order_manager = self.order_manager()?;
+    self.check_phase()?;
+    self.rate_limits.check_orders(order.trader_id, 1).await?;
+    let price = price.unwrap_or(order.price);
+    let quantity = quantity.unwrap_or(order.quantity);
+    let risk_limits = self.trader_risk_limits(order.trader_id);
+    if !risk_limits.is_disabled() {
+      risk_limits.check_amendment(&order_manager.exposure(order.trader_id, order.card_id), order, price, quantity)?;
+    }
+    order_manager.amend_order(order.clone(), price, quantity).await?;
+    self.publish_tickers([order.card_id]).await;
+    Ok(self.order_store.query_order(order.id).await.with_context(|| "Query order failed")?
+      .ok_or_else(|| anyhow!("Order not exist: {}", order.id))?)
+  }
+
+  async fn snapshot(&self) -> Result<Vec<BookSnapshot>, Error> {
+    self.order_manager()?.snapshot().await
+  }
+
+  async fn depth(&self, card_id: i32, levels: usize) -> Result<Depth, Error> {
+    self.order_manager()?.depth(card_id, levels).await
+  }
+
+  async fn schedule_call_auction(&self, operator: &str, card_id: i32, opens_at: DateTime<Utc>, closes_at: DateTime<Utc>) -> Result<CallAuction, Error> {
+    if !card::is_valid(card_id) {
+      return Err(Error::CardNotFound);
+    }
+    if closes_at <= opens_at || closes_at <= Utc::now() {
+      return Err(Error::InvalidAuctionWindow);
+    }
+    let auctions = self.order_store.query_call_auctions(card_id).await.with_context(|| "Query call auctions failed")?;
+    if auctions.iter().any(|auction| auction.uncrossed_at.is_none() && auction.opens_at < closes_at && opens_at < auction.closes_at) {
+      return Err(Error::AuctionOverlaps);
+    }
+    let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+    let auction = tx.insert_call_auction(card_id, opens_at, closes_at).await.with_context(|| "Insert call auction failed")?;
+    tx.insert_audit_entry(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::ScheduleCallAuction,
+      card_id: Some(card_id),
+      trader_id: None,
+      details: serde_json::json!({ "auction_id": auction.id, "opens_at": auction.opens_at, "closes_at": auction.closes_at }),
+    }).await.with_context(|| "Insert audit entry failed")?;
+    tx.commit().await.with_context(|| "Commit transaction failed")?;
+    Ok(auction)
+  }
+
+  async fn card_status(&self, card_id: i32) -> Result<CardStatus, Error> {
+    if !card::is_valid(card_id) {
+      return Err(Error::CardNotFound);
+    }
+    let reference_price = self.order_store.query_last_price(card_id).await.with_context(|| "Query last price failed")?;
+    let auction = self.order_store.query_open_call_auction(card_id).await.with_context(|| "Query call auction failed")?;
+    let band = reference_price.and_then(|reference_price| self.price_limits.band(reference_price));
+    let halts = self.order_store.query_trading_halts().await.with_context(|| "Query trading halts failed")?;
+    let is_halted = halts.iter().any(|halt| halt.card_id.is_none() || halt.card_id == Some(card_id));
+    Ok(CardStatus {
+      card_id,
+      phase: self.calendar_phase(Utc::now()),
+      state: match &auction {
+        _ if is_halted => TradingState::Halted,
+        Some(auction) if auction.halt => TradingState::Halted,
+        Some(_) => TradingState::Auction,
+        None => TradingState::Continuous,
+      },
+      reference_price,
+      band_low: band.map(|(low, _)| low),
+      band_high: band.map(|(_, high)| high),
+      auction_closes_at: auction.map(|auction| auction.closes_at),
+    })
+  }
+
+  async fn run_call_auctions(&self, now: DateTime<Utc>) -> Result<(), Error> {
+    let order_manager = self.order_manager()?;
+    let auctions = self.order_store.query_due_call_auctions(now).await.with_context(|| "Query call auctions failed")?;
+    for auction in auctions {
+      // Both happen at once if the window passed while no instance was leading
+      if auction.opened_at.is_none() {
+        order_manager.open_auction(auction.card_id, auction.id).await?;
+      }
+      if auction.closes_at <= now {
+        order_manager.uncross(auction.card_id, auction.id).await?;
+      }
+      self.publish_tickers([auction.card_id]).await;
+    }
+    Ok(())
+  }
+
+  async fn run_sessions(&self, now: DateTime<Utc>) -> Result<(), Error> {
+    let closures = self.order_store.query_market_closures(now).await.with_context(|| "Query market closures failed")?;
+    *self.closures.write().unwrap() = closures;
+    let order_manager = self.order_manager()?;
+    // The closing auction is uncrossed before the close cancels the day orders
+    self.run_call_auctions(now).await?;
+    let phase = self.calendar_phase(now);
+    let previous = *self.phase.read().unwrap();
+    if phase != previous {
+      self.enter_phase(&order_manager, previous, phase, now).await?;
+      *self.phase.write().unwrap() = phase;
+      info!("Trading session moved from {:?} to {:?}", previous, phase);
+      let _ = self.market_data.send(MarketDataEvent::Session(self.session_status().await?));
+      self.publish_tickers(0..card::NUM_CARDS as i32).await;
+    }
+    Ok(())
+  }
+
+  async fn schedule_closure(&self, operator: &str, kind: ClosureKind, starts_at: DateTime<Utc>, ends_at: DateTime<Utc>, reason: &str) -> Result<MarketClosure, Error> {
+    if ends_at <= starts_at || ends_at <= Utc::now() {
+      return Err(Error::InvalidClosureWindow);
+    }
+    let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+    let closure = tx.insert_market_closure(kind, starts_at, ends_at, reason).await.with_context(|| "Insert market closure failed")?;
+    tx.insert_audit_entry(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::ScheduleClosure,
+      card_id: None,
+      trader_id: None,
+      details: serde_json::json!({ "closure_id": closure.id, "kind": kind, "starts_at": closure.starts_at, "ends_at": closure.ends_at, "reason": closure.reason }),
+    }).await.with_context(|| "Insert audit entry failed")?;
+    tx.commit().await.with_context(|| "Commit transaction failed")?;
+    self.closures.write().unwrap().push(closure.clone());
+    Ok(closure)
+  }
+
+  async fn session_status(&self) -> Result<SessionStatus, Error> {
+    let now = Utc::now();
+    let closures = self.closures.read().unwrap();
+    Ok(SessionStatus {
+      phase: self.calendar.phase(now, &closures),
+      closure: session::closure_at(now, &closures).cloned(),
+    })
+  }
+
+  async fn ticker(&self, card_id: i32) -> Result<Ticker, Error> {
+    if !card::is_valid(card_id) {
+      return Err(Error::CardNotFound);
+    }
+    let depth = self.depth(card_id, 1).await?;
+    let last_price = self.order_store.query_last_price(card_id).await.with_context(|| "Query last price failed")?;
+    Ok(Ticker {
+      card_id,
+      phase: *self.phase.read().unwrap(),
+      last_price,
+      best_bid: depth.bids.first().map(|level| level.price),
+      best_ask: depth.asks.first().map(|level| level.price),
+    })
+  }
+
+  async fn set_trading_halt(&self, operator: &str, card_id: Option<i32>, halted: bool, reason: &str) -> Result<(), Error> {
+    let order_manager = self.order_manager()?;
+    let action = match (card_id, halted) {
+      (None, true) => AdminAction::HaltMarket,
+      (None, false) => AdminAction::ResumeMarket,
+      (Some(_), true) => AdminAction::HaltCard,
+      (Some(_), false) => AdminAction::ResumeCard,
+    };
+    let audit = NewAuditEntry { operator: operator.to_string(), action, card_id, trader_id: None, details: serde_json::json!({ "reason": reason }) };
+    order_manager.set_halted(card_id, halted, reason, audit).await?;
+    info!("{} by {}: {:?} {}", action.as_str(), operator, card_id, reason);
+    self.publish_tickers(card_id.map_or(0..card::NUM_CARDS as i32, |card_id| card_id..card_id + 1)).await;
+    Ok(())
+  }
 
-    if let Some(order) = filled_order {
-        self.order_store.update_order_status( order_id, Status::Filled).await.with_context(|| format!("Failed to update order status: {}", order_id))?;
-        self.order_store.update_order_status( order.first_order_id, Status::Filled).await.with_context(|| format!("Failed to update order status: {}", order.first_order_id))?;
-        self.trade_store.insert_trade(order.card_id, order.price, order.buy_order, order.sell_order).await.with_context(|| format!("Failed to insert trade: {}", order_id))?;
+  async fn set_trader_suspension(&self, operator: &str, trader_id: i64, suspended: bool, reason: &str) -> Result<(), Error> {
+    let order_manager = self.order_manager()?;
+    self.check_trader(trader_id).await?;
+    let action = if suspended { AdminAction::SuspendTrader } else { AdminAction::ReinstateTrader };
+    let audit = NewAuditEntry { operator: operator.to_string(), action, card_id: None, trader_id: Some(trader_id), details: serde_json::json!({ "reason": reason }) };
+    order_manager.set_suspended(trader_id, suspended, reason, audit).await?;
+    info!("{} by {}: trader {} {}", action.as_str(), operator, trader_id, reason);
+    Ok(())
+  }
+
+  async fn cancel_trader_orders(&self, operator: &str, trader_id: i64, reason: &str) -> Result<Vec<i64>, Error> {
+    let order_manager = self.order_manager()?;
+    self.check_trader(trader_id).await?;
+    let cancelled_ids = self.cancel_pending_orders(&order_manager, trader_id, None, None, Actor::Operator(operator.to_string())).await?;
+    self.audit(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::CancelTraderOrders,
+      card_id: None,
+      trader_id: Some(trader_id),
+      details: serde_json::json!({ "reason": reason, "order_ids": cancelled_ids }),
+    }).await?;
+    info!("Cancelled {} orders of trader {} by {}", cancelled_ids.len(), trader_id, operator);
+    Ok(cancelled_ids)
+  }
+
+  async fn risk_limits(&self, trader_id: i64) -> Result<Option<TraderRiskLimits>, Error> {
+    self.check_trader(trader_id).await?;
+    Ok(self.risk_overrides.read().unwrap().get(&trader_id).cloned())
+  }
+
+  async fn set_risk_limits(&self, operator: &str, trader_id: i64, limits: TraderRiskLimits) -> Result<(), Error> {
+    self.check_trader(trader_id).await?;
+    self.trader_store.save_risk_limits(trader_id, &limits).await.with_context(|| "Save risk limits failed")?;
+    self.audit(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::SetRiskLimits,
+      card_id: None,
+      trader_id: Some(trader_id),
+      details: serde_json::to_value(&limits).with_context(|| "Serialize risk limits failed")?,
+    }).await?;
+    info!("Risk limits of trader {} set by {}: {:?}", trader_id, operator, limits);
+    self.risk_overrides.write().unwrap().insert(trader_id, limits);
+    Ok(())
+  }
+
+  async fn refresh_risk_limits(&self) -> Result<(), Error> {
+    let overrides = self.trader_store.query_risk_limits().await.with_context(|| "Query risk limits failed")?;
+    *self.risk_overrides.write().unwrap() = overrides;
+    Ok(())
+  }
+
+  async fn audit(&self, entry: NewAuditEntry) -> Result<(), Error> {
+    Ok(self.order_store.insert_audit_entry(entry).await.with_context(|| "Insert audit entry failed")?)
+  }
+
+  async fn set_role(&self, operator: &str, trader_id: i64, role: Role) -> Result<(), Error> {
+    let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+    if !tx.save_role(trader_id, role).await.with_context(|| "Save role failed")? {
+      return Err(Error::TraderNotFound);
     }
+    tx.insert_audit_entry(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::SetRole,
+      card_id: None,
+      trader_id: Some(trader_id),
+      details: serde_json::json!({ "role": role }),
+    }).await.with_context(|| "Insert audit entry failed")?;
+    tx.commit().await.with_context(|| "Commit transaction failed")?;
+    info!("Role of trader {} set to {:?} by {}", trader_id, role, operator);
     Ok(())
   }
+
+  async fn issue_api_key(&self, operator: &str, trader_id: i64) -> Result<String, Error> {
+    let mut tx = self.order_store.begin().await.with_context(|| "Begin transaction failed")?;
+    let api_key = tx.issue_api_key(trader_id).await.with_context(|| "Issue API key failed")?.ok_or(Error::TraderNotFound)?;
+    // The key itself never goes to the audit log
+    tx.insert_audit_entry(NewAuditEntry {
+      operator: operator.to_string(),
+      action: AdminAction::IssueApiKey,
+      card_id: None,
+      trader_id: Some(trader_id),
+      details: serde_json::json!({}),
+    }).await.with_context(|| "Insert audit entry failed")?;
+    tx.commit().await.with_context(|| "Commit transaction failed")?;
+    info!("API key of trader {} issued by {}", trader_id, operator);
+    Ok(api_key)
+  }
 }
 
 #[cfg(test)]
 mod test {
-  use crate::{ports::{MockTraderStore, MockOrderStore, MockTradeStore}};
+  use std::collections::HashSet;
+  use std::sync::Mutex as StdMutex;
+  use crate::{ports::{MockTraderStore, MockOrderStore, MockOrderTransaction, OrderEventKind, JournalKind, SelfTradePrevention, PriceLevel, PostOnly, AuctionIndication, TimeInForce, TradingHalt, TraderPosition}};
+use crate::session::SessionHours;
+  use crate::rate_limit::{Limit, RateLimitConfig};
   use super::*;
 
+  fn rate_limits(order: Limit) -> Arc<RateLimits> {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_query_rate_limits().returning(|_| Ok(None));
+    let unlimited = Limit { per_second: 1000, burst: 1000 };
+    let config = RateLimitConfig { trader: unlimited, ip: unlimited, order, trust_forwarded_for: false };
+    Arc::new(RateLimits::new(config, Arc::new(trader_store)))
+  }
+
+  #[derive(Debug, Clone, PartialEq)]
+  enum Write {
+    Insert(i64),
+    Status(i64, Status),
+    Fill(i64, i32),
+    Update(i64, i32, i32),
+    Decrement(i64, i32),
+    Trigger(i64),
+    Reprice(i64, i32),
+    // order, kind, price, quantity, previous price, previous quantity, counterparty order
+    Event(i64, OrderEventKind, i32, i32, Option<i32>, Option<i32>, Option<i64>),
+    // Created, PartiallyFilled, Filled, Cancelled and Expired events: order, kind, trade
+    Lifecycle(i64, OrderEventKind, Option<i64>, Actor),
+    // kind, order id, price, quantity
+    Journal(JournalKind, i64, i32, i32),
+    // buy order, sell order, price, quantity, buyer fee, seller fee
+    Trade(i64, i64, i32, i32, i32, i32),
+    OpenAuction(i64),
+    // auction, price, volume
+    Uncross(i64, Option<i32>, i32),
+    // card
+    Halt(i32),
+    // card or the market, halted
+    TradingHalt(Option<i32>, bool),
+    // trader, suspended
+    Suspension(i64, bool),
+    // trader, role
+    Role(i64, Role),
+    // trader
+    ApiKey(i64),
+    // card
+    ScheduleAuction(i32),
+    ScheduleClosure(ClosureKind),
+    // action, operator
+    Audit(&'static str, String),
+    Commit,
+  }
+
+  // Every transaction begun on the store appends its writes to the returned log,
+  // inserted orders get increasing ids and client_order_id is unique per trader like in the database,
+  // fills return the quantity left of the inserted orders
+  fn record_transactions(order_store: &mut MockOrderStore) -> Arc<StdMutex<Vec<Write>>> {
+    let writes = Arc::new(StdMutex::new(Vec::new()));
+    let client_order_ids = Arc::new(StdMutex::new(HashSet::new()));
+    let order_id = Arc::new(StdMutex::new(0));
+    let quantities_left = Arc::new(StdMutex::new(HashMap::new()));
+    let log = writes.clone();
+    order_store.expect_begin().returning(move || {
+      let mut tx = MockOrderTransaction::new();
+      tx.expect_fence().returning(|_| Ok(()));
+      let (w, ids, next_id, left) = (log.clone(), client_order_ids.clone(), order_id.clone(), quantities_left.clone());
+      tx.expect_insert_order().returning(move |order| {
+        if let Some(client_order_id) = order.client_order_id {
+          if !ids.lock().unwrap().insert((order.trader_id, client_order_id)) {
+            return Ok(None);
+          }
+        }
+        let mut next_id = next_id.lock().unwrap();
+        *next_id += 1;
+        left.lock().unwrap().insert(*next_id, order.quantity);
+        w.lock().unwrap().push(Write::Insert(*next_id));
+        Ok(Some(*next_id))
+      });
+      let w = log.clone();
+      tx.expect_update_order_status().returning(move |order_id, status| { w.lock().unwrap().push(Write::Status(order_id, status)); Ok(()) });
+      let w = log.clone();
+      let left = quantities_left.clone();
+      tx.expect_fill_order().returning(move |order_id, quantity| {
+        w.lock().unwrap().push(Write::Fill(order_id, quantity));
+        let mut left = left.lock().unwrap();
+        let left = left.entry(order_id).or_insert(0);
+        *left -= quantity;
+        Ok(*left)
+      });
+      let w = log.clone();
+      let left = quantities_left.clone();
+      tx.expect_update_order().returning(move |order_id, price, quantity| {
+        w.lock().unwrap().push(Write::Update(order_id, price, quantity));
+        // Only the total quantity is known, so the fills so far are kept
+        let mut left = left.lock().unwrap();
+        let order_left = left.entry(order_id).or_insert(0);
+        *order_left += quantity - *order_left;
+        Ok(())
+      });
+      let w = log.clone();
+      let left = quantities_left.clone();
+      tx.expect_decrement_order().returning(move |order_id, quantity| {
+        w.lock().unwrap().push(Write::Decrement(order_id, quantity));
+        *left.lock().unwrap().entry(order_id).or_insert(0) -= quantity;
+        Ok(())
+      });
+      let w = log.clone();
+      tx.expect_trigger_order().returning(move |order_id| { w.lock().unwrap().push(Write::Trigger(order_id)); Ok(()) });
+      let w = log.clone();
+      tx.expect_reprice_order().returning(move |order_id, price| { w.lock().unwrap().push(Write::Reprice(order_id, price)); Ok(()) });
+      let w = log.clone();
+      tx.expect_insert_order_event().returning(move |e| {
+        let write = match e.kind {
+          OrderEventKind::Created | OrderEventKind::PartiallyFilled | OrderEventKind::Filled | OrderEventKind::Cancelled | OrderEventKind::Expired =>
+            Write::Lifecycle(e.order_id, e.kind, e.trade_id, e.actor),
+          _ => Write::Event(e.order_id, e.kind, e.price, e.quantity, e.previous_price, e.previous_quantity, e.counterparty_order_id),
+        };
+        w.lock().unwrap().push(write);
+        Ok(())
+      });
+      let w = log.clone();
+      tx.expect_insert_trade().returning(move |t| {
+        let mut w = w.lock().unwrap();
+        w.push(Write::Trade(t.buyorder_id, t.sellorder_id, t.price, t.quantity, t.buyer_fee, t.seller_fee));
+        Ok(w.iter().filter(|w| matches!(w, Write::Trade(..))).count() as i64)
+      });
+      let w = log.clone();
+      tx.expect_append_journal().returning(move |e| {
+        let mut w = w.lock().unwrap();
+        w.push(Write::Journal(e.kind, e.order_id.unwrap_or(0), e.price, e.quantity));
+        Ok(w.iter().filter(|w| matches!(w, Write::Journal(..))).count() as i64)
+      });
+      let w = log.clone();
+      tx.expect_open_call_auction().returning(move |auction_id| { w.lock().unwrap().push(Write::OpenAuction(auction_id)); Ok(()) });
+      let w = log.clone();
+      tx.expect_uncross_call_auction().returning(move |auction_id, price, volume| { w.lock().unwrap().push(Write::Uncross(auction_id, price, volume)); Ok(()) });
+      let w = log.clone();
+      tx.expect_insert_halt_auction().returning(move |card_id, _| { w.lock().unwrap().push(Write::Halt(card_id)); Ok(1) });
+      let w = log.clone();
+      tx.expect_set_trading_halt().returning(move |card_id, halted, _, _| { w.lock().unwrap().push(Write::TradingHalt(card_id, halted)); Ok(()) });
+      let w = log.clone();
+      tx.expect_set_trader_suspension().returning(move |trader_id, suspended, _, _| { w.lock().unwrap().push(Write::Suspension(trader_id, suspended)); Ok(()) });
+      let w = log.clone();
+      tx.expect_insert_call_auction().returning(move |card_id, opens_at, closes_at| {
+        w.lock().unwrap().push(Write::ScheduleAuction(card_id));
+        Ok(CallAuction { card_id, ..call_auction(2, opens_at, closes_at) })
+      });
+      let w = log.clone();
+      tx.expect_insert_market_closure().returning(move |kind, starts_at, ends_at, reason| {
+        w.lock().unwrap().push(Write::ScheduleClosure(kind));
+        Ok(MarketClosure { id: 1, kind, starts_at, ends_at, reason: reason.to_string() })
+      });
+      // Trader 9 doesn't exist
+      let w = log.clone();
+      tx.expect_save_role().returning(move |trader_id, role| {
+        if trader_id == 9 {
+          return Ok(false);
+        }
+        w.lock().unwrap().push(Write::Role(trader_id, role));
+        Ok(true)
+      });
+      let w = log.clone();
+      tx.expect_issue_api_key().returning(move |trader_id| {
+        if trader_id == 9 {
+          return Ok(None);
+        }
+        w.lock().unwrap().push(Write::ApiKey(trader_id));
+        Ok(Some(format!("key{}", trader_id)))
+      });
+      let w = log.clone();
+      tx.expect_insert_audit_entry().returning(move |e| { w.lock().unwrap().push(Write::Audit(e.action.as_str(), e.operator)); Ok(()) });
+      let w = log.clone();
+      tx.expect_commit().returning(move || { w.lock().unwrap().push(Write::Commit); Ok(()) });
+      Ok(Box::new(tx))
+    });
+    writes
+  }
+
+  async fn order_service(order_store: MockOrderStore, fee_schedule: FeeSchedule) -> OrderServiceImpl<MockTraderStore, MockOrderStore> {
+    limited_order_service(order_store, fee_schedule, PriceLimits::default()).await
+  }
+
+  async fn limited_order_service(order_store: MockOrderStore, fee_schedule: FeeSchedule, price_limits: PriceLimits) -> OrderServiceImpl<MockTraderStore, MockOrderStore> {
+    session_order_service(order_store, fee_schedule, price_limits, TradingCalendar::default()).await
+  }
+
+  async fn session_order_service(mut order_store: MockOrderStore, fee_schedule: FeeSchedule, price_limits: PriceLimits, calendar: TradingCalendar) -> OrderServiceImpl<MockTraderStore, MockOrderStore> {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(true));
+    order_store.expect_query_journal().returning(|_, _| Ok(vec![]));
+    order_store.expect_query_snapshot().returning(|_| Ok(None));
+    order_store.expect_query_trading_halts().returning(|| Ok(vec![]));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![]));
+    let order_service = OrderServiceImpl::new(trader_store, order_store, fee_schedule, price_limits, rate_limits(Limit { per_second: 1000, burst: 1000 }), calendar, RiskLimits::default());
+    order_service.start(1).await.unwrap();
+    order_service
+  }
+
+  fn place_order(side: Action, price: i32, quantity: i32, client_order_id: Option<&str>) -> PlaceOrder {
+    PlaceOrder {
+      card_id: 1,
+      side,
+      price,
+      quantity,
+      client_order_id: client_order_id.map(|id| id.to_string()),
+      self_trade_prevention: SelfTradePrevention::default(),
+      order_type: OrderType::Limit,
+      stop_price: None,
+      display_quantity: None,
+      post_only: PostOnly::Disabled,
+      hidden: false,
+      time_in_force: TimeInForce::GoodTillCancel,
+    }
+  }
+
+  fn order(id: i64, trader_id: i64, client_order_id: &str) -> Order {
+    Order {
+      id,
+      card_id: 1,
+      price: 100,
+      side: Action::Buy as i16,
+      status: Status::Pending as i16,
+      trader_id,
+      created_at: Utc::now(),
+      client_order_id: Some(client_order_id.to_string()),
+      quantity: 1,
+      filled_quantity: 0,
+      self_trade_prevention: SelfTradePrevention::default() as i16,
+      order_type: OrderType::Limit as i16,
+      stop_price: None,
+      triggered_at: None,
+      display_quantity: None,
+      post_only: PostOnly::Disabled as i16,
+      hidden: false,
+      time_in_force: TimeInForce::GoodTillCancel as i16,
+    }
+  }
+
   #[actix_web::main]
   #[test]
   async fn test_add_order() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+    assert!(order_service.add_order(2, place_order(Action::Sell, 100, 1, None)).await.is_ok());
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 1), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 2, 100, 1), Write::Fill(2, 1), Write::Fill(1, 1), Write::Trade(1, 2, 100, 1, 0, 0), Write::Lifecycle(2, OrderEventKind::Filled, Some(1), Actor::System), Write::Lifecycle(1, OrderEventKind::Filled, Some(1), Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_add_order_trader_not_exist() {
     let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(false));
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_query_journal().returning(|_, _| Ok(vec![]));
+    order_store.expect_query_snapshot().returning(|_| Ok(None));
+    order_store.expect_query_trading_halts().returning(|| Ok(vec![]));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![]));
+    order_store.expect_begin().times(0);
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), RiskLimits::default());
+    order_service.start(1).await.unwrap();
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::TraderNotFound)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_card_trading_rules() {
     let mut order_store = MockOrderStore::new();
-    let mut trade_store = MockTradeStore::new();
-    trader_store.expect_is_exist().returning(|_| Some(true)).times(2);
-    order_store.expect_query_pending_orders().returning(|_, _| Ok(vec![])).times(1..);
-    order_store.expect_insert_order().returning(|_| Ok(1)).times(2);
-    order_store.expect_update_order_status().returning(|_, _| Ok(())).times(2);
-    trade_store.expect_insert_trade().returning(|_, _, _, _| Ok(())).times(1);
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    // card 3 trades from 500 to 5000 in ticks of 10 and lots of 10
+    let card_order = |price, quantity| PlaceOrder { card_id: 3, ..place_order(Action::Buy, price, quantity, None) };
+    assert!(matches!(order_service.add_order(1, card_order(100, 10)).await, Err(Error::InvalidPrice)));
+    assert!(matches!(order_service.add_order(1, card_order(5010, 10)).await, Err(Error::InvalidPrice)));
+    assert!(matches!(order_service.add_order(1, card_order(505, 10)).await, Err(Error::InvalidPrice)));
+    assert!(matches!(order_service.add_order(1, card_order(500, 5)).await, Err(Error::InvalidQuantity)));
+    assert!(matches!(order_service.add_order(1, card_order(500, 15)).await, Err(Error::InvalidQuantity)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { display_quantity: Some(5), ..card_order(500, 20) }).await, Err(Error::InvalidDisplayQuantity)));
+    assert!(matches!(order_service.add_order(1, card_order(500, 1_000_010)).await, Err(Error::InvalidQuantity)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { display_quantity: Some(30), ..card_order(500, 20) }).await, Err(Error::InvalidDisplayQuantity)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { display_quantity: Some(10), hidden: true, ..card_order(500, 20) }).await, Err(Error::InvalidDisplayQuantity)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { display_quantity: Some(10), order_type: OrderType::StopMarket, stop_price: Some(500), ..card_order(500, 20) }).await, Err(Error::InvalidDisplayQuantity)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { order_type: OrderType::StopLimit, stop_price: Some(503), ..card_order(500, 10) }).await, Err(Error::InvalidStopPrice)));
+    assert!(matches!(order_service.add_order(1, PlaceOrder { card_id: 9, ..card_order(500, 10) }).await, Err(Error::InvalidCardId)));
+    assert!(writes.lock().unwrap().is_empty());
+
+    // An invalid order of a batch is answered on its own, the rest is placed
+    let mut results = order_service.add_orders(1, vec![card_order(501, 10), card_order(500, 10)]).await.unwrap();
+    assert!(matches!(results[0], Err(Error::InvalidPrice)));
+    let order = results.remove(1).unwrap();
+    assert!(matches!(order_service.amend_order(&order, Some(512), None).await, Err(Error::InvalidPrice)));
+    assert!(matches!(order_service.amend_order(&order, None, Some(25)).await, Err(Error::InvalidQuantity)));
+    // The same prices are fine on card 1
+    assert!(order_service.add_order(1, place_order(Action::Buy, 505, 15, None)).await.is_ok());
+  }
 
-    let order_service = OrderServiceImpl::new(trader_store, order_store, trade_store).await;
-    assert!(order_service.add_order(1, Action::Buy, 100, 1).await.is_ok());
-    assert!(order_service.add_order(2, Action::Sell, 100, 1).await.is_ok());
+  #[actix_web::main]
+  #[test]
+  async fn test_order_entry_needs_leadership() {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().times(0);
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_begin().times(0);
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), RiskLimits::default());
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::NotLeader)));
+    assert!(matches!(order_service.cancel_orders(1, None, None).await, Err(Error::NotLeader)));
+    assert!(matches!(order_service.snapshot().await, Err(Error::NotLeader)));
   }
-}
\ No newline at end of file
+
+  #[actix_web::main]
+  #[test]
+  async fn test_stopped_engine_rejects_order_entry() {
+    let mut order_store = MockOrderStore::new();
+    record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+    order_service.stop();
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::NotLeader)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_add_order_charges_maker_and_taker_fee() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule { maker_bps: 100, taker_bps: 200 }).await;
+    // order 1 rests as a sell, order 2 is the buying taker
+    order_service.add_order(1, place_order(Action::Sell, 500, 2, None)).await.unwrap();
+    order_service.add_order(2, place_order(Action::Buy, 500, 2, None)).await.unwrap();
+    assert!(writes.lock().unwrap().contains(&Write::Trade(2, 1, 500, 2, 20, 10)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_add_order_partially_filled() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Sell, 100, 2, None)).await.unwrap();
+    let order = order_service.add_order(2, place_order(Action::Buy, 100, 5, None)).await.unwrap();
+    assert_eq!(order.filled_quantity, 2);
+    assert_eq!(order.status, Status::Pending as i16);
+    assert_eq!(2, writes.lock().unwrap().iter().filter(|w| matches!(w, Write::Fill(_, 2))).count());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_resubmitted_client_order_id_returns_original_order() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let mut is_inserted = false;
+    order_store.expect_query_order_by_client_id()
+      .withf(|trader_id, client_order_id| *trader_id == 1 && client_order_id == "abc")
+      .returning(move |_, _| {
+        let r = if is_inserted { Some(order(1, 1, "abc")) } else { None };
+        is_inserted = true;
+        Ok(r)
+      })
+      .times(2);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let first = order_service.add_order(1, place_order(Action::Buy, 100, 1, Some("abc"))).await.unwrap();
+    let second = order_service.add_order(1, place_order(Action::Buy, 100, 1, Some("abc"))).await.unwrap();
+    assert_eq!(first.id, 1);
+    assert_eq!(second.id, 1);
+    assert_eq!(vec![Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 1), Write::Commit], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_concurrent_client_order_id_returns_winning_order() {
+    let mut order_store = MockOrderStore::new();
+    let mut tx = MockOrderTransaction::new();
+    tx.expect_fence().returning(|_| Ok(()));
+    tx.expect_insert_order().returning(|_| Ok(None)).times(1);
+    tx.expect_commit().returning(|| Ok(())).times(1);
+    order_store.expect_begin().return_once(move || Ok(Box::new(tx)));
+    let mut is_queried = false;
+    order_store.expect_query_order_by_client_id()
+      .returning(move |_, _| {
+        let r = if is_queried { Some(order(3, 1, "abc")) } else { None };
+        is_queried = true;
+        Ok(r)
+      });
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let order = order_service.add_order(1, place_order(Action::Buy, 100, 1, Some("abc"))).await.unwrap();
+    assert_eq!(order.id, 3);
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_add_orders_in_one_transaction() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_order_by_client_id().returning(|trader_id, client_order_id| {
+      Ok(if client_order_id == "dup" { Some(order(9, trader_id, "dup")) } else { None })
+    });
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let orders = order_service.add_orders(1, vec![
+      place_order(Action::Sell, 100, 2, Some("dup")),
+      place_order(Action::Sell, 100, 2, None),
+      place_order(Action::Buy, 100, 1, None),
+    ]).await.unwrap().into_iter().map(Result::unwrap).collect::<Vec<Order>>();
+    assert_eq!(vec![9, 1, 2], orders.iter().map(|order| order.id).collect::<Vec<i64>>());
+    // the buy would trade with the sell of the same trader, so it's cancelled instead
+    assert_eq!(Status::Pending as i16, orders[1].status);
+    assert_eq!(Status::Cancelled as i16, orders[2].status);
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(1)),
+      Write::Journal(JournalKind::New, 1, 100, 2),
+      Write::Journal(JournalKind::New, 2, 100, 1), Write::Status(2, Status::Cancelled), Write::Event(2, OrderEventKind::SelfTradePrevented, 100, 1, None, None, Some(1)), Write::Lifecycle(2, OrderEventKind::Cancelled, None, Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_self_trade_prevention_decrement() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Sell, 100, 3, None)).await.unwrap();
+    let order = order_service.add_order(1, PlaceOrder {
+      self_trade_prevention: SelfTradePrevention::Decrement,
+      ..place_order(Action::Buy, 100, 5, None)
+    }).await.unwrap();
+    // the sell has nothing left and is cancelled, the rest of the buy keeps resting
+    assert_eq!((2, 0, Status::Pending as i16), (order.quantity, order.filled_quantity, order.status));
+    order_service.add_order(2, place_order(Action::Sell, 100, 2, None)).await.unwrap();
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 3), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 2, 100, 5),
+      Write::Decrement(2, 3), Write::Event(2, OrderEventKind::SelfTradePrevented, 100, 3, None, None, Some(1)),
+      Write::Status(1, Status::Cancelled), Write::Event(1, OrderEventKind::SelfTradePrevented, 100, 3, None, None, Some(2)), Write::Lifecycle(1, OrderEventKind::Cancelled, None, Actor::System), Write::Commit,
+      Write::Insert(3), Write::Lifecycle(3, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 3, 100, 2), Write::Fill(3, 2), Write::Fill(2, 2), Write::Trade(2, 3, 100, 2, 0, 0), Write::Lifecycle(3, OrderEventKind::Filled, Some(1), Actor::System), Write::Lifecycle(2, OrderEventKind::Filled, Some(1), Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_stop_order_triggered_by_trade() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Buy, 100, 2, None)).await.unwrap();
+    let stop_order = order_service.add_order(2, PlaceOrder {
+      order_type: OrderType::StopMarket,
+      stop_price: Some(100),
+      ..place_order(Action::Sell, 100, 1, None)
+    }).await.unwrap();
+    assert_eq!((Status::Pending as i16, None), (stop_order.status, stop_order.triggered_at));
+    order_service.add_order(3, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 2), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 2, 100, 1), Write::Commit,
+      Write::Insert(3), Write::Lifecycle(3, OrderEventKind::Created, None, Actor::Trader(3)), Write::Journal(JournalKind::New, 3, 100, 1),
+      Write::Trigger(2), Write::Event(2, OrderEventKind::Triggered, 100, 1, None, None, None),
+      Write::Fill(3, 1), Write::Fill(1, 1), Write::Trade(1, 3, 100, 1, 0, 0), Write::Lifecycle(3, OrderEventKind::Filled, Some(1), Actor::System), Write::Lifecycle(1, OrderEventKind::PartiallyFilled, Some(1), Actor::System),
+      Write::Fill(2, 1), Write::Fill(1, 1), Write::Trade(1, 2, 100, 1, 0, 0), Write::Lifecycle(2, OrderEventKind::Filled, Some(2), Actor::System), Write::Lifecycle(1, OrderEventKind::Filled, Some(2), Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_depth_hides_iceberg_reserve() {
+    let mut order_store = MockOrderStore::new();
+    record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, PlaceOrder { display_quantity: Some(2), ..place_order(Action::Sell, 100, 10, None) }).await.unwrap();
+    order_service.add_order(1, place_order(Action::Sell, 101, 1, None)).await.unwrap();
+    let depth = order_service.depth(1, 10).await.unwrap();
+    assert_eq!((1, 2), (depth.card_id, depth.sequence));
+    assert!(depth.bids.is_empty());
+    assert_eq!(vec![PriceLevel { price: 100, quantity: 2, orders: 1 }, PriceLevel { price: 101, quantity: 1, orders: 1 }], depth.asks);
+    assert!(matches!(order_service.depth(9, 10).await, Err(Error::CardNotFound)));
+  }
+
+  fn call_auction(id: i64, opens_at: DateTime<Utc>, closes_at: DateTime<Utc>) -> CallAuction {
+    CallAuction { id, card_id: 1, opens_at, closes_at, opened_at: None, uncrossed_at: None, price: None, volume: None, halt: false }
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_call_auction_collects_then_uncrosses() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let now = Utc::now();
+    let runs = StdMutex::new(0);
+    order_store.expect_query_due_call_auctions().returning(move |_| {
+      let mut runs = runs.lock().unwrap();
+      *runs += 1;
+      Ok(match *runs {
+        1 => vec![call_auction(1, now, now + chrono::Duration::minutes(5))],
+        _ => vec![CallAuction { opened_at: Some(now), ..call_auction(1, now, now - chrono::Duration::seconds(1)) }],
+      })
+    });
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.run_call_auctions(Utc::now()).await.unwrap();
+    order_service.add_order(1, place_order(Action::Buy, 110, 1, None)).await.unwrap();
+    let order = order_service.add_order(2, place_order(Action::Sell, 109, 1, None)).await.unwrap();
+    assert_eq!(Status::Pending as i16, order.status);
+    let depth = order_service.depth(1, 10).await.unwrap();
+    assert_eq!(Some(AuctionIndication { price: Some(109), volume: 1 }), depth.auction);
+    assert_eq!((vec![PriceLevel { price: 110, quantity: 1, orders: 1 }], vec![PriceLevel { price: 109, quantity: 1, orders: 1 }]), (depth.bids, depth.asks));
+    order_service.run_call_auctions(Utc::now()).await.unwrap();
+    assert_eq!(None, order_service.depth(1, 10).await.unwrap().auction);
+    assert_eq!(vec![
+      Write::Journal(JournalKind::OpenAuction, 0, 0, 0), Write::OpenAuction(1), Write::Commit,
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 110, 1), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 2, 109, 1), Write::Commit,
+      Write::Journal(JournalKind::Uncross, 0, 109, 1), Write::Fill(2, 1), Write::Fill(1, 1), Write::Trade(1, 2, 109, 1, 0, 0), Write::Lifecycle(2, OrderEventKind::Filled, Some(1), Actor::System), Write::Lifecycle(1, OrderEventKind::Filled, Some(1), Actor::System), Write::Uncross(1, Some(109), 1), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_price_band_and_circuit_breaker() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let now = Utc::now();
+    order_store.expect_query_last_price().returning(|_| Ok(Some(115)));
+    order_store.expect_query_open_call_auction().returning(move |_| Ok(Some(CallAuction { opened_at: Some(now), halt: true, ..call_auction(1, now, now + chrono::Duration::minutes(5)) })));
+    let price_limits = PriceLimits { band_bps: 2000, breaker_bps: 1000, breaker_window: Duration::from_secs(60), halt_duration: Duration::from_secs(300) };
+    let order_service = limited_order_service(order_store, FeeSchedule::default(), price_limits).await;
+    // No band before the first trade
+    order_service.add_order(1, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    order_service.add_order(2, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    assert!(matches!(order_service.add_order(2, place_order(Action::Buy, 121, 1, None)).await, Err(Error::PriceOutsideBand(80, 120))));
+    // Only the order outside the band is rejected from a batch
+    let results = order_service.add_orders(1, vec![place_order(Action::Buy, 121, 1, None), place_order(Action::Sell, 115, 1, None)]).await.unwrap();
+    assert!(matches!(results[0], Err(Error::PriceOutsideBand(80, 120))));
+    assert_eq!(3, results[1].as_ref().unwrap().id);
+    writes.lock().unwrap().clear();
+    // 15% above the trade a moment ago
+    let order = order_service.add_order(2, place_order(Action::Buy, 115, 1, None)).await.unwrap();
+    assert_eq!(Status::Filled as i16, order.status);
+    assert_eq!(vec![
+      Write::Insert(4), Write::Lifecycle(4, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 4, 115, 1), Write::Fill(4, 1), Write::Fill(3, 1), Write::Trade(4, 3, 115, 1, 0, 0), Write::Lifecycle(4, OrderEventKind::Filled, Some(1), Actor::System), Write::Lifecycle(3, OrderEventKind::Filled, Some(1), Actor::System),
+      Write::Journal(JournalKind::OpenAuction, 0, 0, 0), Write::Halt(1), Write::Commit,
+    ], *writes.lock().unwrap());
+    // Halted until the auction uncrosses
+    order_service.add_order(1, place_order(Action::Sell, 110, 1, None)).await.unwrap();
+    assert_eq!(Status::Pending as i16, order_service.add_order(2, place_order(Action::Buy, 110, 1, None)).await.unwrap().status);
+    assert_eq!(Some(AuctionIndication { price: Some(110), volume: 1 }), order_service.depth(1, 10).await.unwrap().auction);
+    let status = order_service.card_status(1).await.unwrap();
+    assert_eq!((TradingState::Halted, Some(115), Some(92), Some(138)), (status.state, status.reference_price, status.band_low, status.band_high));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_schedule_call_auction() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let now = Utc::now();
+    order_store.expect_query_call_auctions().returning(move |_| Ok(vec![call_auction(1, now, now + chrono::Duration::minutes(5))]));
+    let order_service = OrderServiceImpl::new(MockTraderStore::new(), order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), RiskLimits::default());
+    let minutes = |minutes| now + chrono::Duration::minutes(minutes);
+    assert!(matches!(order_service.schedule_call_auction("ops", 9, minutes(1), minutes(2)).await, Err(Error::CardNotFound)));
+    assert!(matches!(order_service.schedule_call_auction("ops", 1, minutes(7), minutes(6)).await, Err(Error::InvalidAuctionWindow)));
+    assert!(matches!(order_service.schedule_call_auction("ops", 1, minutes(-2), minutes(-1)).await, Err(Error::InvalidAuctionWindow)));
+    assert!(matches!(order_service.schedule_call_auction("ops", 1, minutes(4), minutes(6)).await, Err(Error::AuctionOverlaps)));
+    assert_eq!(2, order_service.schedule_call_auction("ops", 1, minutes(5), minutes(6)).await.unwrap().id);
+    // Audited in the transaction of the auction
+    assert_eq!(vec![Write::ScheduleAuction(1), Write::Audit("schedule_call_auction", "ops".to_string()), Write::Commit], *writes.lock().unwrap());
+    // Opening and uncrossing is up to the leader
+    assert!(matches!(order_service.run_call_auctions(Utc::now()).await, Err(Error::NotLeader)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_schedule_closure() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let now = Utc::now();
+    assert!(matches!(order_service.schedule_closure("ops", ClosureKind::Holiday, now, now - chrono::Duration::hours(1), "").await, Err(Error::InvalidClosureWindow)));
+    order_service.schedule_closure("ops", ClosureKind::Holiday, now, now + chrono::Duration::hours(1), "New year").await.unwrap();
+    // Audited in the transaction of the closure
+    assert_eq!(vec![Write::ScheduleClosure(ClosureKind::Holiday), Write::Audit("schedule_closure", "ops".to_string()), Write::Commit], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_post_only_never_takes_liquidity() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    let repriced = order_service.add_order(2, PlaceOrder { post_only: PostOnly::Reprice, ..place_order(Action::Buy, 100, 1, None) }).await.unwrap();
+    assert_eq!((99, Status::Pending as i16), (repriced.price, repriced.status));
+    let rejected = order_service.add_order(2, PlaceOrder { post_only: PostOnly::Reject, ..place_order(Action::Buy, 100, 1, None) }).await.unwrap();
+    assert_eq!(Status::Cancelled as i16, rejected.status);
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 1), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 2, 100, 1),
+      Write::Reprice(2, 99), Write::Event(2, OrderEventKind::Repriced, 99, 1, Some(100), None, None), Write::Commit,
+      Write::Insert(3), Write::Lifecycle(3, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 3, 100, 1),
+      Write::Status(3, Status::Cancelled), Write::Event(3, OrderEventKind::PostOnlyRejected, 100, 1, None, None, None), Write::Lifecycle(3, OrderEventKind::Cancelled, None, Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_different_card_not_matching() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let orders = order_service.add_orders(1, vec![
+      PlaceOrder { card_id: 0, ..place_order(Action::Buy, 100, 1, None) },
+      place_order(Action::Sell, 100, 1, None),
+    ]).await.unwrap().into_iter().map(Result::unwrap).collect::<Vec<Order>>();
+    assert!(orders.iter().all(|order| order.filled_quantity == 0));
+    // one transaction per card
+    let writes = writes.lock().unwrap();
+    assert_eq!(2, writes.iter().filter(|w| **w == Write::Commit).count());
+    assert!(!writes.iter().any(|w| matches!(w, Write::Trade(..))));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_failed_ticker_keeps_the_placed_order() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_last_price().returning(|_| Err(anyhow!("Connection refused")));
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let mut market_data = order_service.subscribe();
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+    assert_eq!(Some(&Write::Commit), writes.lock().unwrap().last());
+    assert!(market_data.try_recv().is_err());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_role_and_api_key_are_audited_in_their_transaction() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.set_role("ops", 1, Role::Admin).await.unwrap();
+    assert_eq!("key1", order_service.issue_api_key("ops", 1).await.unwrap());
+    assert!(matches!(order_service.set_role("ops", 9, Role::Admin).await, Err(Error::TraderNotFound)));
+    assert!(matches!(order_service.issue_api_key("ops", 9).await, Err(Error::TraderNotFound)));
+    assert_eq!(vec![
+      Write::Role(1, Role::Admin), Write::Audit("set_role", "ops".to_string()), Write::Commit,
+      Write::ApiKey(1), Write::Audit("issue_api_key", "ops".to_string()), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_cancel_order() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_order_by_client_id().returning(|_, _| Ok(None));
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    let order = order_service.add_order(1, place_order(Action::Buy, 100, 1, Some("abc"))).await.unwrap();
+    order_service.cancel_order(&order).await.unwrap();
+    assert!(matches!(order_service.cancel_order(&order).await, Err(Error::OrderNotPending)));
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 1), Write::Commit,
+      Write::Journal(JournalKind::Cancel, 1, 100, 0), Write::Status(1, Status::Cancelled), Write::Lifecycle(1, OrderEventKind::Cancelled, None, Actor::Trader(1)), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_cancel_orders_with_filters() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_trader_pending_orders()
+      .withf(|trader_id, card_id, side| (*trader_id, *card_id, *side) == (1, Some(1), Some(Action::Buy as i16)))
+      .returning(|trader_id, _, _| Ok(vec![order(1, trader_id, "a"), order(2, trader_id, "b"), order(3, trader_id, "c")]));
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_orders(1, vec![place_order(Action::Buy, 100, 1, None), place_order(Action::Buy, 100, 1, None)]).await.unwrap();
+    // order 3 is not in the book anymore
+    let cancelled_ids = order_service.cancel_orders(1, Some(1), Some(Action::Buy)).await.unwrap();
+    assert_eq!(vec![1, 2], cancelled_ids);
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 100, 1), Write::Journal(JournalKind::New, 2, 100, 1), Write::Commit,
+      Write::Journal(JournalKind::Cancel, 1, 100, 0), Write::Status(1, Status::Cancelled), Write::Lifecycle(1, OrderEventKind::Cancelled, None, Actor::Trader(1)),
+      Write::Journal(JournalKind::Cancel, 2, 100, 0), Write::Status(2, Status::Cancelled), Write::Lifecycle(2, OrderEventKind::Cancelled, None, Actor::Trader(1)), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_trading_halt_rejects_orders_until_resumed() {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(true));
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_query_journal().returning(|_, _| Ok(vec![]));
+    order_store.expect_query_snapshot().returning(|_| Ok(None));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![]));
+    let writes = record_transactions(&mut order_store);
+    // halts are what the committed transactions left
+    let log = writes.clone();
+    order_store.expect_query_trading_halts().returning(move || {
+      let mut halted: Vec<Option<i32>> = vec![];
+      for write in log.lock().unwrap().iter() {
+        match write {
+          Write::TradingHalt(card_id, true) if !halted.contains(card_id) => halted.push(*card_id),
+          Write::TradingHalt(card_id, false) => halted.retain(|halted| halted != card_id),
+          _ => {},
+        }
+      }
+      Ok(halted.into_iter().map(|card_id| TradingHalt { card_id, operator: "ops".to_string(), reason: String::new(), halted_at: Utc::now() }).collect())
+    });
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), RiskLimits::default());
+    order_service.start(1).await.unwrap();
+    let card_order = |card_id| PlaceOrder { card_id, ..place_order(Action::Buy, 100, 1, None) };
+    let order = order_service.add_order(1, card_order(1)).await.unwrap();
+
+    order_service.set_trading_halt("ops", Some(1), true, "news").await.unwrap();
+    assert!(matches!(order_service.add_order(1, card_order(1)).await, Err(Error::TradingHalted)));
+    assert!(matches!(order_service.amend_order(&order, Some(101), None).await, Err(Error::TradingHalted)));
+    assert!(order_service.add_order(1, card_order(0)).await.is_ok());
+    // a halted card doesn't hold back the other cards of a batch
+    let results = order_service.add_orders(1, vec![card_order(1), card_order(0)]).await.unwrap();
+    assert!(matches!(results[0], Err(Error::TradingHalted)));
+    assert!(results[1].is_ok());
+    // cancellations are accepted while halted
+    order_service.cancel_order(&order).await.unwrap();
+
+    order_service.set_trading_halt("ops", None, true, "").await.unwrap();
+    assert!(matches!(order_service.add_order(1, card_order(0)).await, Err(Error::TradingHalted)));
+    // the card stays halted after the market is resumed
+    order_service.set_trading_halt("ops", None, false, "").await.unwrap();
+    assert!(order_service.add_order(1, card_order(0)).await.is_ok());
+    assert!(matches!(order_service.add_order(1, card_order(1)).await, Err(Error::TradingHalted)));
+    order_service.set_trading_halt("ops", Some(1), false, "").await.unwrap();
+    assert!(order_service.add_order(1, card_order(1)).await.is_ok());
+    assert!(matches!(order_service.set_trading_halt("ops", Some(9), true, "").await, Err(Error::CardNotFound)));
+
+    let audits: Vec<Write> = writes.lock().unwrap().iter().filter(|w| matches!(w, Write::Audit(..))).cloned().collect();
+    assert_eq!(vec![
+      Write::Audit("halt_card", "ops".to_string()), Write::Audit("halt_market", "ops".to_string()),
+      Write::Audit("resume_market", "ops".to_string()), Write::Audit("resume_card", "ops".to_string()),
+    ], audits);
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_lifecycle_events_and_actors() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_trader_pending_orders().returning(|trader_id, _, _| Ok(vec![order(5, trader_id, "e")]));
+    order_store.expect_insert_audit_entry().returning(|_| Ok(()));
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Buy, 100, 3, None)).await.unwrap();
+    order_service.add_order(2, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    order_service.add_order(2, place_order(Action::Sell, 100, 2, None)).await.unwrap();
+    let order = order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    order_service.cancel_order(&order).await.unwrap();
+    order_service.add_order(3, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    order_service.cancel_trader_orders("ops", 3, "risk").await.unwrap();
+    // Expired events of day orders at the close are in test_market_closure_rejects_orders_and_cancels_day_orders
+    let lifecycle: Vec<_> = writes.lock().unwrap().iter().filter(|w| matches!(w, Write::Lifecycle(..))).cloned().collect();
+    assert_eq!(vec![
+      Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)),
+      Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)),
+      Write::Lifecycle(2, OrderEventKind::Filled, Some(1), Actor::System),
+      Write::Lifecycle(1, OrderEventKind::PartiallyFilled, Some(1), Actor::System),
+      Write::Lifecycle(3, OrderEventKind::Created, None, Actor::Trader(2)),
+      Write::Lifecycle(3, OrderEventKind::Filled, Some(2), Actor::System),
+      Write::Lifecycle(1, OrderEventKind::Filled, Some(2), Actor::System),
+      Write::Lifecycle(4, OrderEventKind::Created, None, Actor::Trader(1)),
+      Write::Lifecycle(4, OrderEventKind::Cancelled, None, Actor::Trader(1)),
+      Write::Lifecycle(5, OrderEventKind::Created, None, Actor::Trader(3)),
+      Write::Lifecycle(5, OrderEventKind::Cancelled, None, Actor::Operator("ops".to_string())),
+    ], lifecycle);
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_suspended_trader_and_mass_cancel() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_trader_pending_orders()
+      .withf(|trader_id, card_id, side| (*trader_id, *card_id, *side) == (1, None, None))
+      .returning(|trader_id, _, _| Ok(vec![order(1, trader_id, "a"), Order { card_id: 0, ..order(2, trader_id, "b") }]));
+    let log = writes.clone();
+    order_store.expect_insert_audit_entry().returning(move |e| { log.lock().unwrap().push(Write::Audit(e.action.as_str(), e.operator)); Ok(()) });
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    order_service.add_order(1, PlaceOrder { card_id: 0, ..place_order(Action::Buy, 100, 1, None) }).await.unwrap();
+
+    order_service.set_trader_suspension("ops", 1, true, "").await.unwrap();
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::TraderSuspended)));
+    // other traders still trade against its open orders
+    order_service.add_order(2, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    // the mass cancel ignores the rate limit of the trader and is audited on its own
+    writes.lock().unwrap().clear();
+    assert_eq!(vec![2], order_service.cancel_trader_orders("ops", 1, "risk").await.unwrap());
+    assert_eq!(vec![
+      Write::Journal(JournalKind::Cancel, 2, 100, 0), Write::Status(2, Status::Cancelled), Write::Lifecycle(2, OrderEventKind::Cancelled, None, Actor::Operator("ops".to_string())), Write::Commit,
+      Write::Audit("cancel_trader_orders", "ops".to_string()),
+    ], *writes.lock().unwrap());
+    order_service.set_trader_suspension("ops", 1, false, "").await.unwrap();
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_pre_trade_risk_limits() {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(true));
+    trader_store.expect_query_risk_limits().returning(|| Ok(HashMap::from([(1, TraderRiskLimits { max_open_orders: Some(2), max_position: Some(3), ..TraderRiskLimits::default() })])));
+    trader_store.expect_save_risk_limits().withf(|trader_id, limits| *trader_id == 1 && limits.max_position == Some(0)).returning(|_, _| Ok(()));
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_query_journal().returning(|_, _| Ok(vec![]));
+    order_store.expect_query_snapshot().returning(|_| Ok(None));
+    order_store.expect_query_trading_halts().returning(|| Ok(vec![]));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    // trader 1 holds 1 card from before
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![TraderPosition { trader_id: 1, card_id: 1, position: 1, daily_volume: 0 }]));
+    let writes = record_transactions(&mut order_store);
+    let log = writes.clone();
+    order_store.expect_insert_audit_entry().returning(move |e| { log.lock().unwrap().push(Write::Audit(e.action.as_str(), e.operator)); Ok(()) });
+    let risk_limits = RiskLimits { max_order_value: 1000, ..RiskLimits::default() };
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), risk_limits);
+    order_service.start(1).await.unwrap();
+    order_service.refresh_risk_limits().await.unwrap();
+
+    assert!(matches!(order_service.add_order(2, place_order(Action::Buy, 100, 11, None)).await, Err(Error::OrderValueLimitExceeded(1000))));
+    let order = order_service.add_order(1, place_order(Action::Buy, 100, 2, None)).await.unwrap();
+    // the second order of the batch would be the third open one
+    let batch = vec![place_order(Action::Sell, 100, 1, None), place_order(Action::Sell, 100, 1, None)];
+    assert!(matches!(order_service.add_orders(1, batch).await, Err(Error::OpenOrdersLimitExceeded(2))));
+    assert!(matches!(order_service.amend_order(&order, None, Some(3)).await, Err(Error::PositionLimitExceeded(3))));
+    // a fill moves the quantity from the open order to the position
+    order_service.add_order(2, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 2, None)).await, Err(Error::PositionLimitExceeded(3))));
+    assert!(order_service.add_order(1, place_order(Action::Sell, 100, 3, None)).await.is_ok());
+
+    order_service.set_risk_limits("ops", 1, TraderRiskLimits { max_position: Some(0), ..TraderRiskLimits::default() }).await.unwrap();
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 5, None)).await.is_ok());
+    assert!(writes.lock().unwrap().contains(&Write::Audit("set_risk_limits", "ops".to_string())));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_amend_order_records_event_and_fills() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_order().returning(|order_id| Ok(Some(Order { price: 110, quantity: 3, filled_quantity: 1, ..order(order_id, 2, "b") })));
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Sell, 110, 1, None)).await.unwrap();
+    let order = order_service.add_order(2, place_order(Action::Buy, 100, 2, None)).await.unwrap();
+    let amended = order_service.amend_order(&order, Some(110), Some(3)).await.unwrap();
+    assert_eq!(amended.filled_quantity, 1);
+    assert_eq!(vec![
+      Write::Insert(1), Write::Lifecycle(1, OrderEventKind::Created, None, Actor::Trader(1)), Write::Journal(JournalKind::New, 1, 110, 1), Write::Commit,
+      Write::Insert(2), Write::Lifecycle(2, OrderEventKind::Created, None, Actor::Trader(2)), Write::Journal(JournalKind::New, 2, 100, 2), Write::Commit,
+      Write::Journal(JournalKind::Amend, 2, 110, 1), Write::Update(2, 110, 3), Write::Event(2, OrderEventKind::Amended, 110, 3, Some(100), Some(2), None),
+      Write::Fill(2, 1), Write::Fill(1, 1), Write::Trade(2, 1, 110, 1, 0, 0), Write::Lifecycle(2, OrderEventKind::PartiallyFilled, Some(1), Actor::System), Write::Lifecycle(1, OrderEventKind::Filled, Some(1), Actor::System), Write::Commit,
+    ], *writes.lock().unwrap());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_amend_order_not_pending() {
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_begin().times(0);
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    // never added to the book
+    assert!(matches!(order_service.amend_order(&order(1, 1, "a"), Some(120), None).await, Err(Error::OrderNotPending)));
+    let filled = Order { status: Status::Filled as i16, ..order(2, 1, "b") };
+    assert!(matches!(order_service.amend_order(&filled, Some(120), None).await, Err(Error::OrderNotPending)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_order_entry_is_throttled() {
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(true));
+    let mut order_store = MockOrderStore::new();
+    order_store.expect_query_journal().returning(|_, _| Ok(vec![]));
+    order_store.expect_query_snapshot().returning(|_| Ok(None));
+    order_store.expect_query_trading_halts().returning(|| Ok(vec![]));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![]));
+    let writes = record_transactions(&mut order_store);
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1, burst: 2 }), TradingCalendar::default(), RiskLimits::default());
+    order_service.start(1).await.unwrap();
+    let orders = vec![place_order(Action::Buy, 100, 1, None), place_order(Action::Buy, 100, 1, None)];
+    assert!(order_service.add_orders(1, orders).await.is_ok());
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::RateLimited(1))));
+    // other traders have their own limit
+    assert!(order_service.add_order(2, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+    assert_eq!(3, writes.lock().unwrap().iter().filter(|w| matches!(w, Write::Insert(_))).count());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_snapshot_restores_book() {
+    let mut order_store = MockOrderStore::new();
+    record_transactions(&mut order_store);
+    let snapshots = Arc::new(StdMutex::new(Vec::new()));
+    let saved = snapshots.clone();
+    order_store.expect_save_snapshot().returning(move |snapshot| { saved.lock().unwrap().push(snapshot.clone()); Ok(()) });
+    let order_service = order_service(order_store, FeeSchedule::default()).await;
+    order_service.add_order(1, place_order(Action::Sell, 100, 2, None)).await.unwrap();
+    order_service.snapshot().await.unwrap();
+    // unchanged books are not written again
+    order_service.snapshot().await.unwrap();
+    let snapshots = snapshots.lock().unwrap().clone();
+    assert_eq!(1, snapshots.len());
+    assert_eq!((1, 1), (snapshots[0].card_id, snapshots[0].sequence));
+
+    // only the journal after the snapshot is replayed
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    order_store.expect_query_snapshot().returning(move |card_id| Ok(snapshots.iter().find(|snapshot| snapshot.card_id == card_id).cloned()));
+    order_store.expect_query_trading_halts().returning(|| Ok(vec![]));
+    order_store.expect_query_trader_suspensions().returning(|| Ok(vec![]));
+    order_store.expect_query_open_orders().returning(|| Ok(vec![]));
+    order_store.expect_query_positions().returning(|_| Ok(vec![]));
+    order_store.expect_query_journal().withf(|card_id, after_sequence| *after_sequence == if *card_id == 1 { 1 } else { 0 }).returning(|_, _| Ok(vec![]));
+    let mut trader_store = MockTraderStore::new();
+    trader_store.expect_is_exist().returning(|_| Some(true));
+    let order_service = OrderServiceImpl::new(trader_store, order_store, FeeSchedule::default(), PriceLimits::default(), rate_limits(Limit { per_second: 1000, burst: 1000 }), TradingCalendar::default(), RiskLimits::default());
+    order_service.start(1).await.unwrap();
+    order_service.add_order(2, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    assert!(writes.lock().unwrap().contains(&Write::Trade(1, 1, 100, 1, 0, 0)));
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_market_closure_rejects_orders_and_cancels_day_orders() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let now = Utc::now();
+    let runs = StdMutex::new(0);
+    order_store.expect_query_market_closures().returning(move |_| {
+      let mut runs = runs.lock().unwrap();
+      *runs += 1;
+      Ok(match *runs {
+        1 => vec![MarketClosure { id: 1, kind: ClosureKind::Maintenance, starts_at: now, ends_at: now + chrono::Duration::hours(1), reason: String::new() }],
+        _ => vec![],
+      })
+    });
+    order_store.expect_query_due_call_auctions().returning(|_| Ok(vec![]));
+    order_store.expect_query_day_orders().returning(|| Ok(vec![Order { time_in_force: TimeInForce::Day as i16, ..order(1, 1, "day") }]));
+    let order_service = session_order_service(order_store, FeeSchedule::default(), PriceLimits::default(), TradingCalendar { hours: None, cancel_day_orders: true }).await;
+    order_service.add_order(1, PlaceOrder { time_in_force: TimeInForce::Day, ..place_order(Action::Buy, 100, 1, None) }).await.unwrap();
+    let gtc = order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.unwrap();
+    writes.lock().unwrap().clear();
+
+    order_service.run_sessions(Utc::now()).await.unwrap();
+    assert_eq!(SessionPhase::Closed, order_service.session_status().await.unwrap().phase);
+    assert_eq!(vec![Write::Journal(JournalKind::Cancel, 1, 100, 0), Write::Status(1, Status::Cancelled), Write::Lifecycle(1, OrderEventKind::Expired, None, Actor::System), Write::Commit], *writes.lock().unwrap());
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::MarketClosed)));
+    assert!(matches!(order_service.amend_order(&gtc, Some(101), None).await, Err(Error::MarketClosed)));
+    // Cancelling is always possible
+    order_service.cancel_order(&gtc).await.unwrap();
+
+    order_service.run_sessions(Utc::now()).await.unwrap();
+    assert_eq!(SessionPhase::Continuous, order_service.session_status().await.unwrap().phase);
+    assert!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await.is_ok());
+  }
+
+  #[actix_web::main]
+  #[test]
+  async fn test_pre_open_collects_orders_for_opening_auction() {
+    let mut order_store = MockOrderStore::new();
+    let writes = record_transactions(&mut order_store);
+    let auctions = Arc::new(StdMutex::new(Vec::new()));
+    order_store.expect_query_market_closures().returning(|_| Ok(vec![]));
+    order_store.expect_query_call_auctions().returning(|_| Ok(vec![]));
+    let inserted = auctions.clone();
+    order_store.expect_insert_call_auction().returning(move |card_id, opens_at, closes_at| {
+      let mut inserted = inserted.lock().unwrap();
+      let auction = CallAuction { card_id, ..call_auction(inserted.len() as i64 + 1, opens_at, closes_at) };
+      inserted.push(auction.clone());
+      Ok(auction)
+    });
+    let due = auctions.clone();
+    order_store.expect_query_due_call_auctions().returning(move |_| Ok(due.lock().unwrap().clone()));
+    // In the pre-open for all of the day but its last seconds
+    let time = |hour, minute, second| chrono::NaiveTime::from_hms(hour, minute, second);
+    let hours = SessionHours::new(time(0, 0, 0), time(23, 59, 58), time(23, 59, 59), time(23, 59, 59));
+    let order_service = session_order_service(order_store, FeeSchedule::default(), PriceLimits::default(), TradingCalendar { hours, cancel_day_orders: true }).await;
+    assert!(matches!(order_service.add_order(1, place_order(Action::Buy, 100, 1, None)).await, Err(Error::MarketClosed)));
+
+    // At a fixed time, so the test doesn't depend on the wall clock
+    let day = chrono::NaiveDate::from_ymd(2026, 1, 5);
+    order_service.run_sessions(DateTime::<Utc>::from_utc(day.and_time(time(12, 0, 0)), Utc)).await.unwrap();
+    assert_eq!(SessionPhase::PreOpen, *order_service.phase.read().unwrap());
+    assert_eq!(vec![(0, DateTime::<Utc>::from_utc(day.and_time(time(0, 0, 0)), Utc), DateTime::<Utc>::from_utc(day.and_time(time(23, 59, 58)), Utc))],
+      auctions.lock().unwrap().iter().filter(|auction| auction.card_id == 0).map(|auction| (auction.card_id, auction.opens_at, auction.closes_at)).collect::<Vec<_>>());
+    assert_eq!(card::NUM_CARDS, writes.lock().unwrap().iter().filter(|write| matches!(write, Write::OpenAuction(_))).count());
+    // Crossing orders are collected without matching
+    order_service.add_order(1, place_order(Action::Buy, 110, 1, None)).await.unwrap();
+    let order = order_service.add_order(2, place_order(Action::Sell, 100, 1, None)).await.unwrap();
+    assert_eq!(Status::Pending as i16, order.status);
+    assert!(order_service.depth(1, 10).await.unwrap().auction.is_some());
+  }
+}
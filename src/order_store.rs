@@ -1,9 +1,9 @@
-
 use std::sync::Arc;
 use async_trait::async_trait;
-use sqlx::{PgPool};
-use anyhow::Result;
-use crate::ports::{OrderStore, Order, NewOrder, PendingOrder, Status};
+use sqlx::{PgPool, Postgres, Transaction, types::Json};
+use anyhow::{anyhow, bail, Result};
+use crate::ports::{OrderStore, OrderTransaction, Order, NewOrder, PendingOrder, Status, NewOrderEvent, OrderEvent, OrderEventKind, NewTrade, Trade, JournalEntry, NewJournalEntry, BookSnapshot, CallAuction, ClosureKind, MarketClosure, TradingHalt, TraderSuspension, AuditEntry, NewAuditEntry, TraderPosition, Role, OUTBOX_TOPIC_TRADES, OUTBOX_TOPIC_ORDERS};
+use chrono::{DateTime, Utc};
 
 #[derive(Clone)]
 pub struct PostgresOrderStoreImpl {
@@ -16,19 +16,332 @@ impl OrderStore for PostgresOrderStoreImpl {
         Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE trader_id = $1 ORDER BY created_at DESC LIMIT $2", trader_id, limit.unwrap_or(50))
             .fetch_all(&*self.pg_pool).await?)
     }
-    async fn insert_order(&self, order: NewOrder) -> Result<i64> {
-        let id = sqlx::query!("INSERT INTO orders (card_id, price, side, status, trader_id, created_at) VALUES ($1, $2, $3, $4, $5, $6) returning id;",
-            order.card_id, order.price, order.action as i16, order.status, order.trader_id, order.created_at)
-            .fetch_one(&*self.pg_pool).await?.id;
-        Ok(id)
+    async fn query_order(&self, order_id: i64) -> Result<Option<Order>> {
+        Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE id = $1", order_id)
+            .fetch_optional(&*self.pg_pool).await?)
     }
-    async fn update_order_status(&self, order_id: i64, status: Status) -> Result<()> {
-        sqlx::query!("UPDATE orders SET status = $1 WHERE id = $2", status as i16, order_id)
+    async fn query_order_events(&self, order_id: i64) -> Result<Vec<OrderEvent>> {
+        let rows = sqlx::query!("SELECT id, order_id, kind, price, quantity, previous_price, previous_quantity, counterparty_order_id, trade_id, actor, created_at FROM order_events WHERE order_id = $1 ORDER BY id", order_id)
+            .fetch_all(&*self.pg_pool).await?;
+        rows.into_iter().map(|r| Ok(OrderEvent {
+            id: r.id,
+            order_id: r.order_id,
+            kind: OrderEventKind::try_from(r.kind)?.as_str().to_string(),
+            price: r.price,
+            quantity: r.quantity,
+            previous_price: r.previous_price,
+            previous_quantity: r.previous_quantity,
+            counterparty_order_id: r.counterparty_order_id,
+            trade_id: r.trade_id,
+            actor: r.actor,
+            created_at: r.created_at,
+        })).collect()
+    }
+    async fn query_order_by_client_id(&self, trader_id: i64, client_order_id: &str) -> Result<Option<Order>> {
+        Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE trader_id = $1 AND client_order_id = $2", trader_id, client_order_id)
+            .fetch_optional(&*self.pg_pool).await?)
+    }
+    async fn query_pending_orders(&self, card_id: i32, side: i16) -> Result<Vec<PendingOrder>> {
+        Ok(sqlx::query_as!(PendingOrder, r#"SELECT id, side, price, card_id, quantity - filled_quantity AS "quantity!", trader_id, self_trade_prevention, order_type, CASE WHEN triggered_at IS NULL THEN stop_price END AS stop_price, display_quantity, NULL::int AS visible_quantity, post_only, hidden FROM orders WHERE status = 0 AND card_id = $1 AND side = $2 ORDER BY id"#, card_id, side)
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_trader_pending_orders(&self, trader_id: i64, card_id: Option<i32>, side: Option<i16>) -> Result<Vec<Order>> {
+        Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE trader_id = $1 AND status = 0 AND ($2::int IS NULL OR card_id = $2) AND ($3::smallint IS NULL OR side = $3) ORDER BY id",
+            trader_id, card_id, side)
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_journal(&self, card_id: i32, after_sequence: i64) -> Result<Vec<JournalEntry>> {
+        Ok(sqlx::query_as!(JournalEntry, "SELECT sequence, card_id, kind, order_id, side, price, quantity, previous_price, trader_id, self_trade_prevention, order_type, stop_price, display_quantity, post_only, hidden, tick_size FROM engine_journal WHERE card_id = $1 AND sequence > $2 ORDER BY sequence",
+            card_id, after_sequence)
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_snapshot(&self, card_id: i32) -> Result<Option<BookSnapshot>> {
+        let r = sqlx::query!(r#"SELECT sequence, orders AS "orders: Json<Vec<PendingOrder>>", auction, last_price FROM book_snapshots WHERE card_id = $1"#, card_id)
+            .fetch_optional(&*self.pg_pool).await?;
+        Ok(r.map(|r| BookSnapshot { card_id, sequence: r.sequence, orders: r.orders.0, auction: r.auction, last_price: r.last_price }))
+    }
+    async fn save_snapshot(&self, snapshot: &BookSnapshot) -> Result<()> {
+        sqlx::query!("INSERT INTO book_snapshots (card_id, sequence, orders, auction, last_price) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (card_id) DO UPDATE SET sequence = EXCLUDED.sequence, orders = EXCLUDED.orders, auction = EXCLUDED.auction, last_price = EXCLUDED.last_price, created_at = CURRENT_TIMESTAMP
+            WHERE book_snapshots.sequence < EXCLUDED.sequence",
+            snapshot.card_id, snapshot.sequence, Json(&snapshot.orders) as _, snapshot.auction, snapshot.last_price)
             .execute(&*self.pg_pool).await?;
         Ok(())
     }
-    async fn query_pending_orders(&self, card_id: i32, side: i16) -> Result<Vec<PendingOrder>> {
-        Ok(sqlx::query_as!(PendingOrder, "SELECT id, side, price, card_id FROM orders WHERE status = 0 AND card_id = $1 AND side = $2", card_id, side)
+    async fn insert_call_auction(&self, card_id: i32, opens_at: DateTime<Utc>, closes_at: DateTime<Utc>) -> Result<CallAuction> {
+        Ok(sqlx::query_as!(CallAuction, "INSERT INTO call_auctions (card_id, opens_at, closes_at) VALUES ($1, $2, $3)
+            RETURNING id, card_id, opens_at, closes_at, opened_at, uncrossed_at, price, volume, halt",
+            card_id, opens_at, closes_at)
+            .fetch_one(&*self.pg_pool).await?)
+    }
+    async fn query_call_auctions(&self, card_id: i32) -> Result<Vec<CallAuction>> {
+        Ok(sqlx::query_as!(CallAuction, "SELECT id, card_id, opens_at, closes_at, opened_at, uncrossed_at, price, volume, halt FROM call_auctions WHERE card_id = $1 ORDER BY opens_at DESC, id DESC", card_id)
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_open_call_auction(&self, card_id: i32) -> Result<Option<CallAuction>> {
+        Ok(sqlx::query_as!(CallAuction, "SELECT id, card_id, opens_at, closes_at, opened_at, uncrossed_at, price, volume, halt FROM call_auctions
+            WHERE card_id = $1 AND opened_at IS NOT NULL AND uncrossed_at IS NULL ORDER BY closes_at LIMIT 1", card_id)
+            .fetch_optional(&*self.pg_pool).await?)
+    }
+    async fn query_last_price(&self, card_id: i32) -> Result<Option<i32>> {
+        Ok(sqlx::query_scalar!("SELECT price FROM trades WHERE card_id = $1 ORDER BY id DESC LIMIT 1", card_id)
+            .fetch_optional(&*self.pg_pool).await?)
+    }
+    async fn query_due_call_auctions(&self, now: DateTime<Utc>) -> Result<Vec<CallAuction>> {
+        Ok(sqlx::query_as!(CallAuction, "SELECT id, card_id, opens_at, closes_at, opened_at, uncrossed_at, price, volume, halt FROM call_auctions
+            WHERE uncrossed_at IS NULL AND (opened_at IS NULL AND opens_at <= $1 OR closes_at <= $1) ORDER BY opens_at, id", now)
             .fetch_all(&*self.pg_pool).await?)
     }
+    async fn query_day_orders(&self) -> Result<Vec<Order>> {
+        Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE status = 0 AND time_in_force = 1 ORDER BY id")
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_market_closures(&self, after: DateTime<Utc>) -> Result<Vec<MarketClosure>> {
+        let rows = sqlx::query!("SELECT id, kind, starts_at, ends_at, reason FROM market_closures WHERE ends_at > $1 ORDER BY starts_at, id", after)
+            .fetch_all(&*self.pg_pool).await?;
+        Ok(rows.into_iter().map(|r| MarketClosure { id: r.id, kind: r.kind.into(), starts_at: r.starts_at, ends_at: r.ends_at, reason: r.reason }).collect())
+    }
+    async fn query_trading_halts(&self) -> Result<Vec<TradingHalt>> {
+        Ok(sqlx::query_as!(TradingHalt, "SELECT card_id, operator, reason, halted_at FROM trading_halts ORDER BY halted_at")
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_open_orders(&self) -> Result<Vec<Order>> {
+        Ok(sqlx::query_as!(Order, "SELECT * FROM orders WHERE status = 0 ORDER BY id")
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_positions(&self, day_start: DateTime<Utc>) -> Result<Vec<TraderPosition>> {
+        let rows = sqlx::query!(r#"SELECT o.trader_id, o.card_id,
+            SUM(CASE WHEN o.side = 0 THEN t.quantity ELSE -t.quantity END)::bigint AS "position!",
+            SUM(CASE WHEN t.created_at >= $1 THEN t.quantity ELSE 0 END)::bigint AS "daily_volume!"
+            FROM trades t JOIN orders o ON o.id = t.buyorder_id OR o.id = t.sellorder_id
+            GROUP BY o.trader_id, o.card_id"#, day_start)
+            .fetch_all(&*self.pg_pool).await?;
+        Ok(rows.into_iter().map(|r| TraderPosition { trader_id: r.trader_id, card_id: r.card_id, position: r.position, daily_volume: r.daily_volume }).collect())
+    }
+    async fn query_trader_suspensions(&self) -> Result<Vec<TraderSuspension>> {
+        Ok(sqlx::query_as!(TraderSuspension, "SELECT trader_id, operator, reason, suspended_at FROM trader_suspensions ORDER BY suspended_at")
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn insert_audit_entry(&self, entry: NewAuditEntry) -> Result<()> {
+        sqlx::query!("INSERT INTO admin_audit_log (operator, action, card_id, trader_id, details) VALUES ($1, $2, $3, $4, $5)",
+            entry.operator, entry.action.as_str(), entry.card_id, entry.trader_id, entry.details)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+    async fn query_audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>> {
+        Ok(sqlx::query_as!(AuditEntry, "SELECT id, operator, action, card_id, trader_id, details, created_at FROM admin_audit_log ORDER BY id DESC LIMIT $1", limit)
+            .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn begin(&self) -> Result<Box<dyn OrderTransaction>> {
+        Ok(Box::new(PostgresOrderTransaction {
+            tx: Some(self.pg_pool.begin().await?),
+        }))
+    }
+}
+
+pub struct PostgresOrderTransaction {
+    // Taken on commit
+    tx: Option<Transaction<'static, Postgres>>,
+}
+impl PostgresOrderTransaction {
+    fn tx(&mut self) -> Result<&mut Transaction<'static, Postgres>> {
+        self.tx.as_mut().ok_or_else(|| anyhow!("Transaction is already committed"))
+    }
+}
+
+#[async_trait]
+impl OrderTransaction for PostgresOrderTransaction {
+    async fn fence(&mut self, epoch: i64) -> Result<()> {
+        // The shared lock makes the next election wait for this transaction
+        let current_epoch = sqlx::query_scalar!("SELECT epoch FROM engine_leader WHERE id = 1 FOR SHARE")
+            .fetch_one(&mut *self.tx()?).await?;
+        if current_epoch != epoch {
+            bail!("Leader epoch {} is fenced by epoch {}", epoch, current_epoch);
+        }
+        Ok(())
+    }
+    async fn insert_order(&mut self, order: NewOrder) -> Result<Option<i64>> {
+        let r = sqlx::query!("INSERT INTO orders (card_id, price, quantity, side, status, trader_id, created_at, client_order_id, self_trade_prevention, order_type, stop_price, display_quantity, post_only, hidden, time_in_force) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) ON CONFLICT (trader_id, client_order_id) DO NOTHING returning id;",
+            order.card_id, order.price, order.quantity, order.action as i16, order.status, order.trader_id, order.created_at, order.client_order_id, order.self_trade_prevention as i16, order.order_type as i16, order.stop_price, order.display_quantity,
+            order.post_only as i16, order.hidden, order.time_in_force as i16)
+            .fetch_optional(&mut *self.tx()?).await?;
+        Ok(r.map(|r| r.id))
+    }
+    async fn update_order_status(&mut self, order_id: i64, status: Status) -> Result<()> {
+        sqlx::query!("UPDATE orders SET status = $1 WHERE id = $2", status as i16, order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn fill_order(&mut self, order_id: i64, quantity: i32) -> Result<i32> {
+        let r = sqlx::query!("UPDATE orders SET filled_quantity = filled_quantity + $1, status = (CASE WHEN filled_quantity + $1 >= quantity THEN $2 ELSE status END) WHERE id = $3 RETURNING quantity - filled_quantity AS \"left!\"",
+            quantity, Status::Filled as i16, order_id)
+            .fetch_one(&mut *self.tx()?).await?;
+        Ok(r.left)
+    }
+    async fn update_order(&mut self, order_id: i64, price: i32, quantity: i32) -> Result<()> {
+        sqlx::query!("UPDATE orders SET price = $1, quantity = $2 WHERE id = $3", price, quantity, order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn decrement_order(&mut self, order_id: i64, quantity: i32) -> Result<()> {
+        sqlx::query!("UPDATE orders SET quantity = quantity - $1 WHERE id = $2", quantity, order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn trigger_order(&mut self, order_id: i64) -> Result<()> {
+        sqlx::query!("UPDATE orders SET triggered_at = CURRENT_TIMESTAMP WHERE id = $1", order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn reprice_order(&mut self, order_id: i64, price: i32) -> Result<()> {
+        sqlx::query!("UPDATE orders SET price = $1 WHERE id = $2", price, order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn insert_order_event(&mut self, event: NewOrderEvent) -> Result<()> {
+        let actor = event.actor.to_string();
+        let r = sqlx::query!("INSERT INTO order_events (order_id, kind, price, quantity, previous_price, previous_quantity, counterparty_order_id, trade_id, actor) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id, created_at",
+            event.order_id, event.kind as i16, event.price, event.quantity, event.previous_price, event.previous_quantity, event.counterparty_order_id, event.trade_id, actor)
+            .fetch_one(&mut *self.tx()?).await?;
+        // Published by the outbox relay once this transaction commits
+        let order_event = OrderEvent {
+            id: r.id,
+            order_id: event.order_id,
+            kind: event.kind.as_str().to_string(),
+            price: event.price,
+            quantity: event.quantity,
+            previous_price: event.previous_price,
+            previous_quantity: event.previous_quantity,
+            counterparty_order_id: event.counterparty_order_id,
+            trade_id: event.trade_id,
+            actor,
+            created_at: r.created_at,
+        };
+        sqlx::query!("INSERT INTO outbox (topic, payload, card_id) SELECT $1, $2::jsonb || jsonb_build_object('trader_id', trader_id, 'card_id', card_id), card_id FROM orders WHERE id = $3",
+            OUTBOX_TOPIC_ORDERS, Json(&order_event) as _, event.order_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn insert_trade(&mut self, trade: NewTrade) -> Result<i64> {
+        let trade = sqlx::query_as!(Trade, "INSERT INTO trades (card_id, price, quantity, buyorder_id, sellorder_id, buyer_fee, seller_fee, journal_sequence) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, card_id, price, buyorder_id, sellorder_id, created_at, buyer_fee, seller_fee, quantity",
+            trade.card_id, trade.price, trade.quantity, trade.buyorder_id, trade.sellorder_id, trade.buyer_fee, trade.seller_fee, trade.journal_sequence)
+            .fetch_one(&mut *self.tx()?).await?;
+        sqlx::query!("INSERT INTO outbox (topic, payload, card_id) VALUES ($1, $2, $3)", OUTBOX_TOPIC_TRADES, Json(&trade) as _, trade.card_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(trade.id)
+    }
+    async fn append_journal(&mut self, entry: NewJournalEntry) -> Result<i64> {
+        let r = sqlx::query!("INSERT INTO engine_journal (card_id, kind, order_id, side, price, quantity, previous_price, trader_id, self_trade_prevention, order_type, stop_price, display_quantity, post_only, hidden, tick_size) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) RETURNING sequence",
+            entry.card_id, entry.kind as i16, entry.order_id, entry.side as i16, entry.price, entry.quantity, entry.previous_price, entry.trader_id, entry.self_trade_prevention as i16, entry.order_type as i16, entry.stop_price, entry.display_quantity,
+            entry.post_only as i16, entry.hidden, entry.tick_size)
+            .fetch_one(&mut *self.tx()?).await?;
+        Ok(r.sequence)
+    }
+    async fn open_call_auction(&mut self, auction_id: i64) -> Result<()> {
+        sqlx::query!("UPDATE call_auctions SET opened_at = CURRENT_TIMESTAMP WHERE id = $1", auction_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn uncross_call_auction(&mut self, auction_id: i64, price: Option<i32>, volume: i32) -> Result<()> {
+        sqlx::query!("UPDATE call_auctions SET uncrossed_at = CURRENT_TIMESTAMP, price = $1, volume = $2 WHERE id = $3", price, volume, auction_id)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn insert_market_closure(&mut self, kind: ClosureKind, starts_at: DateTime<Utc>, ends_at: DateTime<Utc>, reason: &str) -> Result<MarketClosure> {
+        let r = sqlx::query!("INSERT INTO market_closures (kind, starts_at, ends_at, reason) VALUES ($1, $2, $3, $4) RETURNING id",
+            kind as i16, starts_at, ends_at, reason)
+            .fetch_one(&mut *self.tx()?).await?;
+        Ok(MarketClosure { id: r.id, kind, starts_at, ends_at, reason: reason.to_string() })
+    }
+    async fn insert_call_auction(&mut self, card_id: i32, opens_at: DateTime<Utc>, closes_at: DateTime<Utc>) -> Result<CallAuction> {
+        Ok(sqlx::query_as!(CallAuction, "INSERT INTO call_auctions (card_id, opens_at, closes_at) VALUES ($1, $2, $3)
+            RETURNING id, card_id, opens_at, closes_at, opened_at, uncrossed_at, price, volume, halt",
+            card_id, opens_at, closes_at)
+            .fetch_one(&mut *self.tx()?).await?)
+    }
+    async fn insert_halt_auction(&mut self, card_id: i32, closes_at: DateTime<Utc>) -> Result<i64> {
+        let r = sqlx::query!("INSERT INTO call_auctions (card_id, opens_at, closes_at, opened_at, halt) VALUES ($1, CURRENT_TIMESTAMP, $2, CURRENT_TIMESTAMP, true) RETURNING id",
+            card_id, closes_at)
+            .fetch_one(&mut *self.tx()?).await?;
+        Ok(r.id)
+    }
+    async fn set_trading_halt(&mut self, card_id: Option<i32>, halted: bool, operator: &str, reason: &str) -> Result<()> {
+        if halted {
+            sqlx::query!("INSERT INTO trading_halts (card_id, operator, reason) VALUES ($1, $2, $3) ON CONFLICT ((COALESCE(card_id, -1))) DO NOTHING",
+                card_id, operator, reason)
+                .execute(&mut *self.tx()?).await?;
+        } else {
+            sqlx::query!("DELETE FROM trading_halts WHERE card_id IS NOT DISTINCT FROM $1", card_id)
+                .execute(&mut *self.tx()?).await?;
+        }
+        Ok(())
+    }
+    async fn set_trader_suspension(&mut self, trader_id: i64, suspended: bool, operator: &str, reason: &str) -> Result<()> {
+        if suspended {
+            sqlx::query!("INSERT INTO trader_suspensions (trader_id, operator, reason) VALUES ($1, $2, $3) ON CONFLICT (trader_id) DO NOTHING",
+                trader_id, operator, reason)
+                .execute(&mut *self.tx()?).await?;
+        } else {
+            sqlx::query!("DELETE FROM trader_suspensions WHERE trader_id = $1", trader_id)
+                .execute(&mut *self.tx()?).await?;
+        }
+        Ok(())
+    }
+    async fn save_role(&mut self, trader_id: i64, role: Role) -> Result<bool> {
+        let r = sqlx::query!("UPDATE traders SET role = $2 WHERE id = $1", trader_id, role as i16)
+            .execute(&mut *self.tx()?).await?;
+        Ok(r.rows_affected() > 0)
+    }
+    async fn issue_api_key(&mut self, trader_id: i64) -> Result<Option<String>> {
+        // 244 random bits of two version 4 UUIDs, only the hash is stored
+        let r = sqlx::query!(r#"
+            WITH k AS (SELECT replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '') AS api_key)
+            UPDATE traders SET api_key_hash = sha256(convert_to(k.api_key, 'UTF8')) FROM k
+            WHERE id = $1 RETURNING k.api_key AS "api_key!""#, trader_id)
+            .fetch_optional(&mut *self.tx()?).await?;
+        Ok(r.map(|r| r.api_key))
+    }
+    async fn insert_audit_entry(&mut self, entry: NewAuditEntry) -> Result<()> {
+        sqlx::query!("INSERT INTO admin_audit_log (operator, action, card_id, trader_id, details) VALUES ($1, $2, $3, $4, $5)",
+            entry.operator, entry.action.as_str(), entry.card_id, entry.trader_id, entry.details)
+            .execute(&mut *self.tx()?).await?;
+        Ok(())
+    }
+    async fn commit(&mut self) -> Result<()> {
+        let tx = self.tx.take().ok_or_else(|| anyhow!("Transaction is already committed"))?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Connection, postgres::PgPoolOptions};
+
+    // Needs a migrated database in DATABASE_URL, run with `cargo test -- --ignored`
+    #[actix_web::main]
+    #[test]
+    #[ignore]
+    async fn test_order_events_are_append_only() {
+        let pool = PgPoolOptions::new().connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        // Everything is rolled back, the log itself can't be cleaned up
+        let mut tx = pool.begin().await.unwrap();
+        let trader_id: i64 = sqlx::query_scalar("INSERT INTO traders DEFAULT VALUES RETURNING id").fetch_one(&mut tx).await.unwrap();
+        let order_id: i64 = sqlx::query_scalar("INSERT INTO orders (card_id, price, side, status, trader_id) VALUES (1, 400, 0, 0, $1) RETURNING id")
+            .bind(trader_id).fetch_one(&mut tx).await.unwrap();
+        sqlx::query("INSERT INTO order_events (order_id, kind, price, quantity, actor) VALUES ($1, 5, 400, 1, 'system')")
+            .bind(order_id).execute(&mut tx).await.unwrap();
+        for change in ["UPDATE order_events SET actor = 'trader:1' WHERE order_id = $1", "DELETE FROM order_events WHERE order_id = $1", "TRUNCATE order_events"] {
+            let mut savepoint = Connection::begin(&mut *tx).await.unwrap();
+            let query = sqlx::query(change);
+            let query = if change.contains("$1") { query.bind(order_id) } else { query };
+            let e = query.execute(&mut savepoint).await.unwrap_err();
+            assert!(e.to_string().contains("order_events is append-only"), "{}: {}", change, e);
+            savepoint.rollback().await.unwrap();
+        }
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM order_events WHERE order_id = $1").bind(order_id).fetch_one(&mut tx).await.unwrap();
+        assert_eq!(1, count);
+        tx.rollback().await.unwrap();
+    }
 }
@@ -0,0 +1,490 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use actix_web::rt::time::{sleep, timeout};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{info, error};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::ports::{OutboxStore, OutboxSink, OutboxMessage};
+
+// A relay holds the messages it claimed this long, after that another relay publishes them again
+const CLAIM_LEASE: Duration = Duration::from_secs(300);
+// Webhook requests and NATS round trips fail after this
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct RelayConfig {
+    pub batch_size: i64,
+    // How long the relay waits once the outbox is drained
+    pub poll_interval: Duration,
+    // A failed message is retried after the base, doubled per failed attempt up to the maximum
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+    // A message is parked after this many failed attempts
+    pub max_attempts: i32,
+}
+
+// Publishes the outbox to every sink at least once and in order per card, a message is published once all sinks
+// took it, so the sinks before a failing one get it again on the retry
+pub struct Relay<S: OutboxStore> {
+    store: S,
+    sinks: Vec<Box<dyn OutboxSink>>,
+    config: RelayConfig,
+}
+
+impl<S: OutboxStore> Relay<S> {
+    pub fn new(store: S, sinks: Vec<Box<dyn OutboxSink>>, config: RelayConfig) -> Self {
+        Relay { store, sinks, config }
+    }
+
+    // Runs forever, relays of several instances take turns
+    pub async fn run(self) {
+        let names: Vec<&str> = self.sinks.iter().map(|sink| sink.name()).collect();
+        info!("Relaying the outbox to {}", names.join(", "));
+        loop {
+            match self.relay().await {
+                // More messages are waiting after a full batch
+                Ok(published) if published as i64 >= self.config.batch_size => continue,
+                Ok(_) => {},
+                Err(e) => error!("Failed to relay the outbox: {:#}", e),
+            }
+            sleep(self.config.poll_interval).await;
+        }
+    }
+
+    // Publishes a batch and returns how many messages went out, the rest of the batch of a failed message's card
+    // waits for its retry while the other cards go on
+    pub async fn relay(&self) -> Result<usize> {
+        let messages = self.store.claim_messages(self.config.batch_size, CLAIM_LEASE).await.context("Failed to claim outbox messages")?;
+        let mut published = Vec::with_capacity(messages.len());
+        let mut held = vec![];
+        let mut failed_cards = HashSet::new();
+        for message in &messages {
+            if failed_cards.contains(&message.card_id) {
+                held.push(message.id);
+                continue;
+            }
+            if let Err(e) = self.publish(message).await {
+                failed_cards.insert(message.card_id);
+                let error = format!("{:#}", e);
+                if message.attempts + 1 >= self.config.max_attempts {
+                    self.store.park(message.id, &error).await.context("Failed to park outbox message")?;
+                    error!("Parked outbox message {} after {} attempts: {}", message.id, message.attempts + 1, error);
+                } else {
+                    let retry_at = Utc::now() + chrono::Duration::from_std(self.backoff(message.attempts + 1))?;
+                    self.store.mark_failed(message.id, retry_at, &error).await.context("Failed to mark outbox message failed")?;
+                    error!("Failed to publish outbox message {}, retrying at {}: {}", message.id, retry_at, error);
+                }
+                continue;
+            }
+            published.push(message.id);
+        }
+        if !published.is_empty() {
+            self.store.mark_published(&published).await.context("Failed to mark outbox messages published")?;
+        }
+        if !held.is_empty() {
+            self.store.release(&held).await.context("Failed to release outbox messages")?;
+        }
+        Ok(published.len())
+    }
+
+    async fn publish(&self, message: &OutboxMessage) -> Result<()> {
+        for sink in &self.sinks {
+            sink.publish(message).await.with_context(|| format!("Failed to publish to {}", sink.name()))?;
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, attempts: i32) -> Duration {
+        let doublings = attempts.clamp(1, 31) as u32 - 1;
+        self.config.retry_base.saturating_mul(1 << doublings).min(self.config.retry_max)
+    }
+}
+
+// The sinks configured by OUTBOX_FILE, OUTBOX_WEBHOOK_URL and OUTBOX_NATS_URL
+pub async fn sinks(config: &Config) -> Result<Vec<Box<dyn OutboxSink>>> {
+    let mut sinks: Vec<Box<dyn OutboxSink>> = vec![];
+    if !config.outbox_file.is_empty() {
+        sinks.push(Box::new(FileSink::open(&config.outbox_file).await?));
+    }
+    if !config.outbox_webhook_url.is_empty() {
+        sinks.push(Box::new(WebhookSink::new(&config.outbox_webhook_url)?));
+    }
+    if !config.outbox_nats_url.is_empty() {
+        sinks.push(Box::new(NatsSink::new(&config.outbox_nats_url, &config.outbox_nats_subject_prefix)));
+    }
+    Ok(sinks)
+}
+
+// Appends a JSON line per message, synced to disk before the message counts as published
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await
+            .with_context(|| format!("Failed to open outbox file {}", path))?;
+        Ok(FileSink { file: Mutex::new(file) })
+    }
+}
+
+#[async_trait]
+impl OutboxSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    async fn publish(&self, message: &OutboxMessage) -> Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+// POSTs each message as JSON with the id as Idempotency-Key, only a 2xx status publishes it
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(SINK_TIMEOUT).build().context("Failed to build the webhook client")?;
+        Ok(WebhookSink { url: url.to_string(), client })
+    }
+}
+
+#[async_trait]
+impl OutboxSink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn publish(&self, message: &OutboxMessage) -> Result<()> {
+        self.client.post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", message.id.to_string())
+            .body(serde_json::to_vec(message)?)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// Publishes each message on <prefix>.<topic> with the NATS client protocol, the PING after it returns once the server
+// processed it. Core NATS only hands it to the subscribers connected then, a JetStream stream on the subjects keeps it
+pub struct NatsSink {
+    address: String,
+    subject_prefix: String,
+    // Connected on the first message and again after a failure
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl NatsSink {
+    pub fn new(url: &str, subject_prefix: &str) -> Self {
+        NatsSink {
+            address: url.trim_start_matches("nats://").trim_end_matches('/').to_string(),
+            subject_prefix: subject_prefix.to_string(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let mut connection = BufReader::new(TcpStream::connect(&self.address).await.with_context(|| format!("Failed to connect to NATS at {}", self.address))?);
+        let info = read_line(&mut connection).await?;
+        if !info.starts_with("INFO") {
+            bail!("Unexpected greeting from NATS: {}", info);
+        }
+        connection.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"pokemon_trading\",\"lang\":\"rust\"}\r\n").await?;
+        Ok(connection)
+    }
+
+    async fn send(&self, connection: &mut Option<BufReader<TcpStream>>, message: &OutboxMessage) -> Result<()> {
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        let connection = connection.as_mut().ok_or_else(|| anyhow!("Not connected to NATS"))?;
+        let payload = serde_json::to_vec(message)?;
+        connection.write_all(format!("PUB {}.{} {}\r\n", self.subject_prefix, message.topic, payload.len()).as_bytes()).await?;
+        connection.write_all(&payload).await?;
+        connection.write_all(b"\r\nPING\r\n").await?;
+        connection.flush().await?;
+        loop {
+            let line = read_line(connection).await?;
+            if line == "PONG" {
+                return Ok(());
+            } else if line == "PING" {
+                connection.write_all(b"PONG\r\n").await?;
+            } else if line.starts_with("-ERR") {
+                bail!("NATS refused the message: {}", line);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OutboxSink for NatsSink {
+    fn name(&self) -> &str {
+        "nats"
+    }
+
+    async fn publish(&self, message: &OutboxMessage) -> Result<()> {
+        let mut connection = self.connection.lock().await;
+        let result = match timeout(SINK_TIMEOUT, self.send(&mut connection, message)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("NATS didn't confirm the message in {:?}", SINK_TIMEOUT)),
+        };
+        if result.is_err() {
+            // The connection may be in the middle of a message
+            *connection = None;
+        }
+        result
+    }
+}
+
+async fn read_line(connection: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if connection.read_line(&mut line).await? == 0 {
+        bail!("NATS closed the connection");
+    }
+    Ok(line.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex as StdMutex};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use crate::ports::{MockOutboxStore, MockOutboxSink};
+    use super::*;
+
+    fn message(id: i64, attempts: i32) -> OutboxMessage {
+        OutboxMessage {
+            id,
+            topic: "trades".to_string(),
+            payload: serde_json::json!({"id": id, "price": 100}),
+            created_at: Utc::now(),
+            attempts,
+            card_id: Some(1),
+        }
+    }
+
+    fn relay_config() -> RelayConfig {
+        RelayConfig {
+            batch_size: 10,
+            poll_interval: Duration::from_millis(100),
+            retry_base: Duration::from_secs(1),
+            retry_max: Duration::from_secs(60),
+            max_attempts: 5,
+        }
+    }
+
+    // The sink records the ids it took and fails the ones in `failing`
+    fn sink(published: Arc<StdMutex<Vec<i64>>>, failing: Vec<i64>) -> Box<dyn OutboxSink> {
+        let mut sink = MockOutboxSink::new();
+        sink.expect_name().return_const("mock".to_string());
+        sink.expect_publish().returning(move |message| {
+            if failing.contains(&message.id) {
+                bail!("Unavailable");
+            }
+            published.lock().unwrap().push(message.id);
+            Ok(())
+        });
+        Box::new(sink)
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let relay = Relay::new(MockOutboxStore::new(), vec![], relay_config());
+        assert_eq!(Duration::from_secs(1), relay.backoff(1));
+        assert_eq!(Duration::from_secs(2), relay.backoff(2));
+        assert_eq!(Duration::from_secs(32), relay.backoff(6));
+        assert_eq!(Duration::from_secs(60), relay.backoff(7));
+        assert_eq!(Duration::from_secs(60), relay.backoff(1000));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_relay_publishes_in_order_to_every_sink() {
+        let mut store = MockOutboxStore::new();
+        store.expect_claim_messages().withf(|limit, _| *limit == 10).return_once(|_, _| Ok(vec![message(1, 0), message(2, 0), message(3, 0)]));
+        store.expect_mark_published().withf(|ids| ids == [1, 2, 3]).times(1).returning(|_| Ok(()));
+        store.expect_mark_failed().times(0);
+        store.expect_release().times(0);
+        let (first, second) = (Arc::new(StdMutex::new(vec![])), Arc::new(StdMutex::new(vec![])));
+        let relay = Relay::new(store, vec![sink(first.clone(), vec![]), sink(second.clone(), vec![])], relay_config());
+        assert_eq!(3, relay.relay().await.unwrap());
+        assert_eq!(vec![1, 2, 3], *first.lock().unwrap());
+        assert_eq!(vec![1, 2, 3], *second.lock().unwrap());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_relay_retries_failed_message_before_the_rest_of_its_card() {
+        let mut store = MockOutboxStore::new();
+        let other_card = OutboxMessage { card_id: Some(2), ..message(4, 0) };
+        store.expect_claim_messages().return_once(|_, _| Ok(vec![message(1, 0), message(2, 2), message(3, 0), other_card]));
+        store.expect_mark_published().withf(|ids| ids == [1, 4]).times(1).returning(|_| Ok(()));
+        // Third attempt of message 2, 4 seconds from now
+        let now = Utc::now();
+        store.expect_mark_failed()
+            .withf(move |id, retry_at, error| *id == 2 && *retry_at >= now + chrono::Duration::seconds(4)
+                && *retry_at < now + chrono::Duration::seconds(5) && error.contains("Unavailable"))
+            .times(1).returning(|_, _, _| Ok(()));
+        store.expect_release().withf(|ids| ids == [3]).times(1).returning(|_| Ok(()));
+        let published = Arc::new(StdMutex::new(vec![]));
+        let relay = Relay::new(store, vec![sink(published.clone(), vec![2])], relay_config());
+        assert_eq!(2, relay.relay().await.unwrap());
+        assert_eq!(vec![1, 4], *published.lock().unwrap());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_relay_parks_message_after_its_last_attempt() {
+        let mut store = MockOutboxStore::new();
+        store.expect_claim_messages().return_once(|_, _| Ok(vec![message(1, 4), message(2, 0)]));
+        store.expect_park().withf(|id, error| *id == 1 && error.contains("Unavailable")).times(1).returning(|_, _| Ok(()));
+        store.expect_mark_failed().times(0);
+        store.expect_mark_published().times(0);
+        // The next claim hands out the rest of the card
+        store.expect_release().withf(|ids| ids == [2]).times(1).returning(|_| Ok(()));
+        let relay = Relay::new(store, vec![sink(Arc::new(StdMutex::new(vec![])), vec![1])], relay_config());
+        assert_eq!(0, relay.relay().await.unwrap());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_messages_after_a_failed_one_wait_for_its_retry() {
+        let mut store = MockOutboxStore::new();
+        let claims = StdMutex::new(vec![vec![message(1, 0), message(2, 0), message(3, 0)], vec![], vec![message(2, 1), message(3, 0)]]);
+        // Until message 2 is due the store holds it and everything of its card after it
+        store.expect_claim_messages().times(3).returning(move |_, _| Ok(claims.lock().unwrap().remove(0)));
+        let marked = Arc::new(StdMutex::new(vec![]));
+        let log = marked.clone();
+        store.expect_mark_published().returning(move |ids| { log.lock().unwrap().push(ids.to_vec()); Ok(()) });
+        store.expect_mark_failed().withf(|id, _, _| *id == 2).times(1).returning(|_, _, _| Ok(()));
+        store.expect_release().withf(|ids| ids == [3]).times(1).returning(|_| Ok(()));
+        // Message 2 fails on its first attempt only
+        let published = Arc::new(StdMutex::new(vec![]));
+        let (log, attempts) = (published.clone(), StdMutex::new(0));
+        let mut sink = MockOutboxSink::new();
+        sink.expect_name().return_const("mock".to_string());
+        sink.expect_publish().returning(move |message| {
+            if message.id == 2 {
+                let mut attempts = attempts.lock().unwrap();
+                *attempts += 1;
+                if *attempts == 1 {
+                    bail!("Unavailable");
+                }
+            }
+            log.lock().unwrap().push(message.id);
+            Ok(())
+        });
+        let relay = Relay::new(store, vec![Box::new(sink)], relay_config());
+
+        assert_eq!(1, relay.relay().await.unwrap());
+        assert_eq!(vec![1], *published.lock().unwrap());
+        assert_eq!(0, relay.relay().await.unwrap());
+        assert_eq!(vec![1], *published.lock().unwrap());
+        assert_eq!(2, relay.relay().await.unwrap());
+        assert_eq!(vec![1, 2, 3], *published.lock().unwrap());
+        assert_eq!(vec![vec![1], vec![2, 3]], *marked.lock().unwrap());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_file_sink_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = FileSink::open(path.to_str().unwrap()).await.unwrap();
+        sink.publish(&message(1, 0)).await.unwrap();
+        sink.publish(&message(2, 0)).await.unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(2, lines.len());
+        assert_eq!(serde_json::json!(2), lines[1]["id"]);
+        assert_eq!(serde_json::json!("trades"), lines[1]["topic"]);
+        assert_eq!(serde_json::json!({"id": 2, "price": 100}), lines[1]["payload"]);
+        assert!(lines[1].get("attempts").is_none());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_webhook_sink_posts_messages_and_fails_on_error_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        // Answers the first request with 500 and the second one with 204
+        let server = actix_web::rt::spawn(async move {
+            let mut requests = vec![];
+            for status in ["500 Internal Server Error", "204 No Content"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = [0; 4096];
+                while !String::from_utf8_lossy(&request).contains("\"payload\"") || !request.ends_with(b"}") {
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                stream.write_all(format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status).as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+        let sink = WebhookSink::new(&url).unwrap();
+        assert!(sink.publish(&message(1, 0)).await.is_err());
+        sink.publish(&message(2, 0)).await.unwrap();
+        let requests = server.await.unwrap();
+        assert!(requests[1].starts_with("POST /events HTTP/1.1\r\n"));
+        assert!(requests[1].to_lowercase().contains("idempotency-key: 2\r\n"));
+        assert!(requests[1].to_lowercase().contains("content-type: application/json\r\n"));
+        let body: serde_json::Value = serde_json::from_str(requests[1].split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(serde_json::json!(2), body["id"]);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_nats_sink_publishes_to_local_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        // Speaks enough of the server side of the protocol to take two messages on one connection
+        let server = actix_web::rt::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n").await.unwrap();
+            let mut lines = vec![];
+            let mut line = String::new();
+            while lines.iter().filter(|line: &&String| line.as_str() == "PING").count() < 2 {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                let line = line.trim_end().to_string();
+                if line == "PING" {
+                    stream.write_all(b"PONG\r\n").await.unwrap();
+                }
+                lines.push(line);
+            }
+            lines
+        });
+        let sink = NatsSink::new(&url, "pokemon");
+        sink.publish(&message(1, 0)).await.unwrap();
+        sink.publish(&OutboxMessage { topic: "orders".to_string(), ..message(2, 0) }).await.unwrap();
+        let lines = server.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        assert!(lines[1].starts_with("PUB pokemon.trades "));
+        assert_eq!(lines[2].len().to_string(), lines[1].split(' ').nth(2).unwrap());
+        let published: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+        assert_eq!(serde_json::json!(1), published["id"]);
+        assert_eq!("PING", lines[3]);
+        assert!(lines[4].starts_with("PUB pokemon.orders "));
+    }
+}
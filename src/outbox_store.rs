@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use sqlx::{PgPool};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use crate::ports::{OutboxStore, OutboxMessage};
+
+// Key of the transaction advisory lock, relays of all instances claim one after another
+const OUTBOX_LOCK_KEY: i64 = 0x4f5554424f58;
+
+#[derive(Clone)]
+pub struct PostgresOutboxStoreImpl {
+    pub pg_pool: Arc<PgPool>
+}
+
+#[async_trait]
+impl OutboxStore for PostgresOutboxStoreImpl {
+    async fn claim_messages(&self, limit: i64, lease: Duration) -> Result<Vec<OutboxMessage>> {
+        let mut tx = self.pg_pool.begin().await?;
+        sqlx::query!(r#"SELECT true AS "locked!" FROM pg_advisory_xact_lock($1)"#, OUTBOX_LOCK_KEY)
+            .fetch_one(&mut tx).await?;
+        // Messages of a card from its first one which isn't due on stay unclaimed, so a retry never overtakes
+        // a message of its card, while the other cards go on
+        let mut messages = sqlx::query_as!(OutboxMessage, r#"
+            UPDATE outbox SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM outbox message
+                WHERE published_at IS NULL AND parked_at IS NULL AND NOT EXISTS (
+                    SELECT 1 FROM outbox waiting
+                    WHERE waiting.card_id IS NOT DISTINCT FROM message.card_id AND waiting.id <= message.id
+                        AND waiting.published_at IS NULL AND waiting.parked_at IS NULL AND waiting.next_attempt_at > CURRENT_TIMESTAMP)
+                ORDER BY id LIMIT $1)
+            RETURNING id, topic, payload, created_at, attempts, card_id"#,
+            limit, lease.as_secs_f64())
+            .fetch_all(&mut tx).await?;
+        tx.commit().await?;
+        messages.sort_by_key(|message| message.id);
+        Ok(messages)
+    }
+    async fn mark_published(&self, ids: &[i64]) -> Result<()> {
+        sqlx::query!("UPDATE outbox SET published_at = CURRENT_TIMESTAMP WHERE id = ANY($1)", ids)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+    async fn mark_failed(&self, id: i64, retry_at: DateTime<Utc>, error: &str) -> Result<()> {
+        sqlx::query!("UPDATE outbox SET next_attempt_at = $2, attempts = attempts + 1, last_error = $3 WHERE id = $1", id, retry_at, error)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+    async fn park(&self, id: i64, error: &str) -> Result<()> {
+        sqlx::query!("UPDATE outbox SET parked_at = CURRENT_TIMESTAMP, attempts = attempts + 1, last_error = $2 WHERE id = $1", id, error)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+    async fn release(&self, ids: &[i64]) -> Result<()> {
+        sqlx::query!("UPDATE outbox SET next_attempt_at = CURRENT_TIMESTAMP WHERE id = ANY($1)", ids)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::postgres::PgPoolOptions;
+    use super::*;
+
+    // Needs a migrated scratch database in DATABASE_URL, run with `cargo test -- --ignored`,
+    // an older message of cards 1 to 3 which isn't due in that database fails it
+    #[actix_web::main]
+    #[test]
+    #[ignore]
+    async fn test_claim_holds_messages_of_the_card_after_one_waiting_for_retry() {
+        let pool = Arc::new(PgPoolOptions::new().connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap());
+        let store = PostgresOutboxStoreImpl{pg_pool: pool.clone()};
+        let mut ids = vec![];
+        // Three messages of card 1 and one of card 2 after them
+        for card_id in [1, 1, 1, 2] {
+            let id: i64 = sqlx::query_scalar("INSERT INTO outbox (topic, payload, card_id) VALUES ('test', '{}', $1) RETURNING id").bind(card_id).fetch_one(&*pool).await.unwrap();
+            ids.push(id);
+        }
+        let claim = || async {
+            let claimed: Vec<i64> = store.claim_messages(100_000, Duration::from_secs(30)).await.unwrap().into_iter().map(|message| message.id).collect();
+            // Messages of others are given back right away
+            let others: Vec<i64> = claimed.iter().copied().filter(|id| !ids.contains(id)).collect();
+            store.release(&others).await.unwrap();
+            claimed.into_iter().filter(|id| ids.contains(id)).collect::<Vec<_>>()
+        };
+
+        store.mark_failed(ids[1], Utc::now() + chrono::Duration::hours(1), "Unavailable").await.unwrap();
+        assert_eq!(vec![ids[0], ids[3]], claim().await);
+        store.mark_published(&[ids[0], ids[3]]).await.unwrap();
+        assert!(claim().await.is_empty());
+        store.mark_failed(ids[1], Utc::now() - chrono::Duration::seconds(1), "Unavailable").await.unwrap();
+        assert_eq!(vec![ids[1], ids[2]], claim().await);
+
+        // A parked message is skipped and doesn't hold back its card
+        store.park(ids[1], "Unavailable").await.unwrap();
+        store.release(&[ids[2]]).await.unwrap();
+        assert_eq!(vec![ids[2]], claim().await);
+        store.mark_published(&ids[1..3]).await.unwrap();
+    }
+}
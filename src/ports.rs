@@ -1,11 +1,91 @@
+use std::collections::HashMap;
 use async_trait::async_trait;
-use anyhow::{Result};
-use serde::{Serialize};
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
+
+use crate::error::Error;
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait TraderStore {
     async fn is_exist(&self, id: i64) -> Option<bool>;
+    async fn query_rate_limits(&self, trader_id: i64) -> Result<Option<TraderRateLimits>>;
+    // Traders without overrides are left out
+    async fn query_risk_limits(&self) -> Result<HashMap<i64, TraderRiskLimits>>;
+    async fn save_risk_limits(&self, trader_id: i64, limits: &TraderRiskLimits) -> Result<()>;
+    // None for an unknown trader
+    async fn query_role(&self, trader_id: i64) -> Result<Option<Role>>;
+    // Trader and role of the API key, None for an unknown key
+    async fn authenticate(&self, api_key: &str) -> Result<Option<(i64, Role)>>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[repr(i16)]
+pub enum Role {
+    // Trades on its own account
+    Trader = 0,
+    // Quotes on its own account, same permissions as a trader for now
+    MarketMaker = 1,
+    // Reads any trader's orders and trades and the admin state, changes nothing
+    Support = 2,
+    Admin = 3,
+}
+impl Role {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "trader" => Some(Role::Trader),
+            "market_maker" => Some(Role::MarketMaker),
+            "support" => Some(Role::Support),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+impl From<i16> for Role {
+    // Unknown values get the least privileges
+    fn from(value: i16) -> Self {
+        match value {
+            1 => Role::MarketMaker,
+            2 => Role::Support,
+            3 => Role::Admin,
+            _ => Role::Trader,
+        }
+    }
+}
+
+// Overrides of the configured limits, None keeps the default
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct TraderRateLimits {
+    pub requests_per_second: Option<i32>,
+    pub request_burst: Option<i32>,
+    pub orders_per_second: Option<i32>,
+    pub order_burst: Option<i32>,
+}
+
+// Overrides of the configured risk limits, None keeps the default and 0 disables the limit
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow, Serialize, Deserialize, ToSchema)]
+pub struct TraderRiskLimits {
+    /// Price times quantity of an order in cents
+    pub max_order_value: Option<i64>,
+    /// Pending orders of every card
+    pub max_open_orders: Option<i32>,
+    /// Price times open quantity of the pending orders of a card in cents
+    pub max_card_notional: Option<i64>,
+    /// Net quantity of a card bought or sold, counting the pending orders of the same side
+    pub max_position: Option<i32>,
+    /// Quantity of every card traded since midnight UTC, counting the order as filled
+    pub max_daily_volume: Option<i32>,
+}
+
+// Net quantity a trader bought of a card and what it traded of the card today
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraderPosition {
+    pub trader_id: i64,
+    pub card_id: i32,
+    pub position: i64,
+    pub daily_volume: i64,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -24,24 +104,189 @@ impl Action {
     }
 }
 
-#[derive(sqlx::FromRow, Serialize)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, ToSchema)]
 pub struct Order {
     pub id: i64,
     pub card_id: i32,
     pub price: i32,
+    /// 0: buy, 1: sell
     pub side: i16,
+    /// 0: pending, 1: filled, 2: cancelled
     pub status: i16,
     pub trader_id: i64,
-    pub created_at: chrono::DateTime<chrono::Utc>
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub client_order_id: Option<String>,
+    pub quantity: i32,
+    pub filled_quantity: i32,
+    /// 0: cancel newest, 1: cancel oldest, 2: cancel both, 3: decrement, 4: disabled
+    pub self_trade_prevention: i16,
+    /// 0: limit, 1: stop market, 2: stop limit
+    pub order_type: i16,
+    /// Stop orders wait until a trade at or beyond this price, at or above for buy and at or below for sell
+    pub stop_price: Option<i32>,
+    /// When the stop price was reached
+    pub triggered_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Iceberg orders show at most this much of the open quantity in the book, the rest is hidden
+    pub display_quantity: Option<i32>,
+    /// 0: disabled, 1: reject, 2: reprice
+    pub post_only: i16,
+    /// Rests without showing in the depth, behind the displayed orders of the same price
+    pub hidden: bool,
+    /// 0: good till cancelled, 1: day, cancelled at the end of the trading session
+    pub time_in_force: i16,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(i16)]
+pub enum OrderType {
+    #[default]
+    Limit = 0,
+    // Matched at any price once triggered, what can't be filled right away is cancelled
+    StopMarket = 1,
+    // Becomes a limit order once triggered
+    StopLimit = 2,
+}
+impl OrderType {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "limit" => Some(OrderType::Limit),
+            "stop_market" => Some(OrderType::StopMarket),
+            "stop_limit" => Some(OrderType::StopLimit),
+            _ => None,
+        }
+    }
+}
+// What happens to an order that would take liquidity when it enters the book
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(i16)]
+pub enum PostOnly {
+    #[default]
+    Disabled = 0,
+    // Cancel the order
+    Reject = 1,
+    // Move the price one tick behind the best opposite price
+    Reprice = 2,
+}
+impl PostOnly {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "reject" => Some(PostOnly::Reject),
+            "reprice" => Some(PostOnly::Reprice),
+            _ => None,
+        }
+    }
+}
+impl From<i16> for PostOnly {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => PostOnly::Reject,
+            2 => PostOnly::Reprice,
+            _ => PostOnly::Disabled,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(i16)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel = 0,
+    // Cancelled when the trading session closes
+    Day = 1,
+}
+impl TimeInForce {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "gtc" => Some(TimeInForce::GoodTillCancel),
+            "day" => Some(TimeInForce::Day),
+            _ => None,
+        }
+    }
+}
+
+impl From<i16> for OrderType {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => OrderType::StopMarket,
+            2 => OrderType::StopLimit,
+            _ => OrderType::Limit,
+        }
+    }
+}
+
+// What happens when an order would trade with a resting order of the same trader
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(i16)]
+pub enum SelfTradePrevention {
+    // Cancel the incoming order
+    #[default]
+    CancelNewest = 0,
+    // Cancel the resting order and keep matching
+    CancelOldest = 1,
+    CancelBoth = 2,
+    // Reduce both orders by the smaller open quantity, cancelling the ones with nothing left
+    Decrement = 3,
+    // Orders placed before self-trade prevention, not accepted from clients
+    Disabled = 4,
+}
+impl SelfTradePrevention {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "cancel_newest" => Some(SelfTradePrevention::CancelNewest),
+            "cancel_oldest" => Some(SelfTradePrevention::CancelOldest),
+            "cancel_both" => Some(SelfTradePrevention::CancelBoth),
+            "decrement" => Some(SelfTradePrevention::Decrement),
+            _ => None,
+        }
+    }
+}
+impl From<i16> for SelfTradePrevention {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => SelfTradePrevention::CancelOldest,
+            2 => SelfTradePrevention::CancelBoth,
+            3 => SelfTradePrevention::Decrement,
+            4 => SelfTradePrevention::Disabled,
+            _ => SelfTradePrevention::CancelNewest,
+        }
+    }
+}
+
+// Order entry parameters, validated by the caller
+#[derive(Debug, Clone)]
+pub struct PlaceOrder {
+    pub card_id: i32,
+    pub side: Action,
+    pub price: i32,
+    pub quantity: i32,
+    pub client_order_id: Option<String>,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub order_type: OrderType,
+    // Stop price of stop orders, the price of a stop market order is its stop price
+    pub stop_price: Option<i32>,
+    pub display_quantity: Option<i32>,
+    pub post_only: PostOnly,
+    pub hidden: bool,
+    pub time_in_force: TimeInForce,
 }
 
 pub struct NewOrder {
   pub card_id: i32,
   pub price: i32,
+  pub quantity: i32,
   pub action: Action,
   pub status: i16,
   pub trader_id: i64,
-  pub created_at: chrono::DateTime<chrono::Utc>
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  // Unique per trader, used to dedupe retried submissions
+  pub client_order_id: Option<String>,
+  pub self_trade_prevention: SelfTradePrevention,
+  pub order_type: OrderType,
+  pub stop_price: Option<i32>,
+  pub display_quantity: Option<i32>,
+  pub post_only: PostOnly,
+  pub hidden: bool,
+  pub time_in_force: TimeInForce,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -49,46 +294,691 @@ pub struct NewOrder {
 pub enum Status {
     Pending = 0,
     Filled = 1,
+    Cancelled = 2,
 }
 
-#[derive(sqlx::FromRow, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow, Serialize, Deserialize)]
 pub struct PendingOrder {
     pub id: i64,
     pub side: i16,
     pub price: i32,
     pub card_id: i32,
-    // TODO: only id & price is must-have
+    // Open quantity
+    pub quantity: i32,
+    pub trader_id: i64,
+    pub self_trade_prevention: i16,
+    // Missing in snapshots taken before stop orders
+    #[serde(default)]
+    pub order_type: i16,
+    // Set while a stop order waits to be triggered
+    #[serde(default)]
+    pub stop_price: Option<i32>,
+    #[serde(default)]
+    pub display_quantity: Option<i32>,
+    // Open quantity of an iceberg order's current slice, only known by the book
+    #[serde(default)]
+    pub visible_quantity: Option<i32>,
+    #[serde(default)]
+    pub post_only: i16,
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i16)]
+pub enum OrderEventKind {
+    Amended = 0,
+    // Open quantity removed instead of trading with an order of the same trader
+    SelfTradePrevented = 1,
+    // A trade reached the stop price, price and quantity of the order entering the book
+    Triggered = 2,
+    // A post-only order would have taken liquidity and was cancelled
+    PostOnlyRejected = 3,
+    // A post-only order would have taken liquidity and rests at the price instead
+    Repriced = 4,
+    Created = 5,
+    // A trade left quantity of the order open
+    PartiallyFilled = 6,
+    // A trade filled the rest of the order
+    Filled = 7,
+    // By the trader, an operator or the engine, e.g. self-trade prevention or a post-only rejection
+    Cancelled = 8,
+    // A market order without liquidity left or a day order at the close
+    Expired = 9,
+}
+impl OrderEventKind {
+    // Part of the API, never rename an existing one
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderEventKind::Amended => "amended",
+            OrderEventKind::SelfTradePrevented => "self_trade_prevented",
+            OrderEventKind::Triggered => "triggered",
+            OrderEventKind::PostOnlyRejected => "post_only_rejected",
+            OrderEventKind::Repriced => "repriced",
+            OrderEventKind::Created => "created",
+            OrderEventKind::PartiallyFilled => "partially_filled",
+            OrderEventKind::Filled => "filled",
+            OrderEventKind::Cancelled => "cancelled",
+            OrderEventKind::Expired => "expired",
+        }
+    }
+}
+// An unknown kind is an error rather than a guess, the log is the audit trail of the order
+impl TryFrom<i16> for OrderEventKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i16) -> Result<Self> {
+        Ok(match value {
+            0 => OrderEventKind::Amended,
+            1 => OrderEventKind::SelfTradePrevented,
+            2 => OrderEventKind::Triggered,
+            3 => OrderEventKind::PostOnlyRejected,
+            4 => OrderEventKind::Repriced,
+            5 => OrderEventKind::Created,
+            6 => OrderEventKind::PartiallyFilled,
+            7 => OrderEventKind::Filled,
+            8 => OrderEventKind::Cancelled,
+            9 => OrderEventKind::Expired,
+            _ => bail!("Unknown order event kind: {}", value),
+        })
+    }
+}
+
+// Who caused an order event, stored as trader:<id>, operator:<name> or system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Actor {
+    Trader(i64),
+    Operator(String),
+    // The matching engine and the trading session
+    System,
+}
+impl std::fmt::Display for Actor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Actor::Trader(trader_id) => write!(f, "trader:{}", trader_id),
+            Actor::Operator(operator) => write!(f, "operator:{}", operator),
+            Actor::System => write!(f, "system"),
+        }
+    }
+}
+
+pub struct NewOrderEvent {
+  pub order_id: i64,
+  pub kind: OrderEventKind,
+  // Amended: price and quantity after the event, SelfTradePrevented: price and open quantity removed,
+  // PartiallyFilled and Filled: price and quantity of the trade, Cancelled and Expired: price and open quantity
+  pub price: i32,
+  pub quantity: i32,
+  pub previous_price: Option<i32>,
+  pub previous_quantity: Option<i32>,
+  // SelfTradePrevented: the order of the same trader it would have traded with, PartiallyFilled and Filled: the other order of the trade
+  pub counterparty_order_id: Option<i64>,
+  pub trade_id: Option<i64>,
+  pub actor: Actor,
+}
+impl NewOrderEvent {
+    // Without previous values, counterparty or trade
+    pub fn new(order_id: i64, kind: OrderEventKind, price: i32, quantity: i32, actor: Actor) -> Self {
+        NewOrderEvent { order_id, kind, price, quantity, previous_price: None, previous_quantity: None, counterparty_order_id: None, trade_id: None, actor }
+    }
+}
+
+// Events of an order are only appended, they tell what happened to it
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct OrderEvent {
+    pub id: i64,
+    pub order_id: i64,
+    /// created, partially_filled, filled, amended, cancelled, expired, triggered, repriced, self_trade_prevented or post_only_rejected
+    pub kind: String,
+    pub price: i32,
+    pub quantity: i32,
+    pub previous_price: Option<i32>,
+    pub previous_quantity: Option<i32>,
+    pub counterparty_order_id: Option<i64>,
+    pub trade_id: Option<i64>,
+    /// trader:<id>, operator:<name> or system
+    pub actor: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i16)]
+pub enum JournalKind {
+    New = 0,
+    Cancel = 1,
+    Amend = 2,
+    // Orders are collected without matching until the auction is uncrossed
+    OpenAuction = 3,
+    Uncross = 4,
+}
+
+// An input of the matching engine, replaying the entries of a card in sequence rebuilds its book
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct JournalEntry {
+    pub sequence: i64,
+    pub card_id: i32,
+    pub kind: i16,
+    // None for auction entries, which don't use the order fields
+    pub order_id: Option<i64>,
+    pub side: i16,
+    // New: limit price, Cancel: resting price, Amend: new price, Uncross: auction price
+    pub price: i32,
+    // New: open quantity, Amend: change of the open quantity, Uncross: executed volume, unused by Cancel
+    pub quantity: i32,
+    // Amend: resting price before the amendment
+    pub previous_price: Option<i32>,
+    pub trader_id: Option<i64>,
+    pub self_trade_prevention: i16,
+    pub order_type: i16,
+    // New: stop price of a stop order, Cancel: set if the order may still wait to be triggered
+    pub stop_price: Option<i32>,
+    pub display_quantity: Option<i32>,
+    pub post_only: i16,
+    pub hidden: bool,
+    // Of the card when the entry was appended, post-only orders are repriced by it
+    pub tick_size: i32,
+}
+
+pub struct NewJournalEntry {
+  pub card_id: i32,
+  pub kind: JournalKind,
+  pub order_id: Option<i64>,
+  pub side: Action,
+  pub price: i32,
+  pub quantity: i32,
+  pub previous_price: Option<i32>,
+  pub trader_id: Option<i64>,
+  pub self_trade_prevention: SelfTradePrevention,
+  pub order_type: OrderType,
+  pub stop_price: Option<i32>,
+  pub display_quantity: Option<i32>,
+  pub post_only: PostOnly,
+  pub hidden: bool,
+  pub tick_size: i32,
+}
+
+// Resting orders of a card's book after the journal entry of the sequence, in time priority per price level,
+// followed by the stop orders waiting to be triggered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookSnapshot {
+    pub card_id: i32,
+    pub sequence: i64,
+    pub orders: Vec<PendingOrder>,
+    // The book is collecting orders for a call auction
+    pub auction: bool,
+    pub last_price: Option<i32>,
+}
+
+// Visible quantity at a price, hidden orders and the hidden part of iceberg orders are not included
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PriceLevel {
+    pub price: i32,
+    pub quantity: i32,
+    /// Number of orders
+    pub orders: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Depth {
+    pub card_id: i32,
+    /// Journal sequence of the book
+    pub sequence: i64,
+    /// Highest price first
+    pub bids: Vec<PriceLevel>,
+    /// Lowest price first
+    pub asks: Vec<PriceLevel>,
+    /// Set while the card is in a call auction, the book may be crossed then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction: Option<AuctionIndication>,
+}
+
+// What uncrossing the call auction would do now
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AuctionIndication {
+    /// Indicative auction price, null if no orders cross
+    pub price: Option<i32>,
+    /// Quantity that would be executed at the price
+    pub volume: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow, Serialize, ToSchema)]
+pub struct CallAuction {
+    pub id: i64,
+    pub card_id: i32,
+    pub opens_at: chrono::DateTime<chrono::Utc>,
+    pub closes_at: chrono::DateTime<chrono::Utc>,
+    pub opened_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub uncrossed_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Auction price, null until uncrossed or if no orders crossed
+    pub price: Option<i32>,
+    /// Quantity executed at the auction price
+    pub volume: Option<i32>,
+    /// Opened by the circuit breaker, which halted the card
+    pub halt: bool,
+}
+
+// Phase of the trading session, the same for every card
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionPhase {
+    // Orders are collected for the opening auction
+    PreOpen,
+    Continuous,
+    // Orders are collected for the closing auction
+    ClosingAuction,
+    // Orders are rejected, cancellations are accepted
+    Closed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[repr(i16)]
+pub enum ClosureKind {
+    Holiday = 0,
+    Maintenance = 1,
+}
+impl ClosureKind {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "holiday" => Some(ClosureKind::Holiday),
+            "maintenance" => Some(ClosureKind::Maintenance),
+            _ => None,
+        }
+    }
+}
+impl From<i16> for ClosureKind {
+    fn from(value: i16) -> Self {
+        match value {
+            1 => ClosureKind::Maintenance,
+            _ => ClosureKind::Holiday,
+        }
+    }
+}
+
+// A window in which the market is closed regardless of the session hours
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct MarketClosure {
+    pub id: i64,
+    pub kind: ClosureKind,
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub ends_at: chrono::DateTime<chrono::Utc>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SessionStatus {
+    pub phase: SessionPhase,
+    /// The closure window the market is in, if any
+    pub closure: Option<MarketClosure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Ticker {
+    pub card_id: i32,
+    pub phase: SessionPhase,
+    /// Last trade or auction price, null before the first trade
+    pub last_price: Option<i32>,
+    /// Best visible prices of the book
+    pub best_bid: Option<i32>,
+    pub best_ask: Option<i32>,
+}
+
+// Published on the market data stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketDataEvent {
+    // The session phase changed
+    Session(SessionStatus),
+    // A book or the session phase changed
+    Ticker(Ticker),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingState {
+    Continuous,
+    Auction,
+    // In the call auction the circuit breaker opened
+    Halted,
+}
+
+// Read from the database, so every instance serves it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct CardStatus {
+    pub card_id: i32,
+    pub phase: SessionPhase,
+    pub state: TradingState,
+    /// Last trade or auction price, null before the first trade
+    pub reference_price: Option<i32>,
+    /// Lowest price accepted, null if there is no band
+    pub band_low: Option<i32>,
+    /// Highest price accepted, null if there is no band
+    pub band_high: Option<i32>,
+    /// When the running auction uncrosses, a halted card re-opens then
+    pub auction_closes_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Trading stopped by operations until it's resumed
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow, Serialize, ToSchema)]
+pub struct TradingHalt {
+    /// Null if the whole market is halted
+    pub card_id: Option<i32>,
+    pub operator: String,
+    pub reason: String,
+    pub halted_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow, Serialize, ToSchema)]
+pub struct TraderSuspension {
+    pub trader_id: i64,
+    pub operator: String,
+    pub reason: String,
+    pub suspended_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    HaltMarket,
+    ResumeMarket,
+    HaltCard,
+    ResumeCard,
+    SuspendTrader,
+    ReinstateTrader,
+    CancelTraderOrders,
+    Snapshot,
+    ScheduleCallAuction,
+    ScheduleClosure,
+    SetRiskLimits,
+    SetRole,
+    IssueApiKey,
+}
+impl AdminAction {
+    // Stored in the audit log, never rename an existing one
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminAction::HaltMarket => "halt_market",
+            AdminAction::ResumeMarket => "resume_market",
+            AdminAction::HaltCard => "halt_card",
+            AdminAction::ResumeCard => "resume_card",
+            AdminAction::SuspendTrader => "suspend_trader",
+            AdminAction::ReinstateTrader => "reinstate_trader",
+            AdminAction::CancelTraderOrders => "cancel_trader_orders",
+            AdminAction::Snapshot => "snapshot",
+            AdminAction::ScheduleCallAuction => "schedule_call_auction",
+            AdminAction::ScheduleClosure => "schedule_closure",
+            AdminAction::SetRiskLimits => "set_risk_limits",
+            AdminAction::SetRole => "set_role",
+            AdminAction::IssueApiKey => "issue_api_key",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub operator: String,
+    /// halt_market, resume_market, halt_card, resume_card, suspend_trader, reinstate_trader, cancel_trader_orders,
+    /// snapshot, schedule_call_auction, schedule_closure, set_risk_limits, set_role or issue_api_key
+    pub action: String,
+    pub card_id: Option<i32>,
+    pub trader_id: Option<i64>,
+    /// Depends on the action, e.g. the reason or the ids of cancelled orders
+    #[schema(value_type = Object)]
+    pub details: serde_json::Value,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewAuditEntry {
+  pub operator: String,
+  pub action: AdminAction,
+  pub card_id: Option<i32>,
+  pub trader_id: Option<i64>,
+  pub details: serde_json::Value,
 }
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait OrderStore {
   async fn query_orders(&self, trader_id: i64, limit: Option<i64>) -> Result<Vec<Order>>;
-  async fn insert_order(&self, order: NewOrder) -> Result<i64>;
-  async fn update_order_status(&self, order_id: i64, status: Status) -> Result<()>;
+  async fn query_order(&self, order_id: i64) -> Result<Option<Order>>;
+  // Oldest first
+  async fn query_order_events(&self, order_id: i64) -> Result<Vec<OrderEvent>>;
+  async fn query_order_by_client_id(&self, trader_id: i64, client_order_id: &str) -> Result<Option<Order>>;
+  // Ordered by id
   async fn query_pending_orders(&self, card_id: i32, side: i16) -> Result<Vec<PendingOrder>>;
+  async fn query_trader_pending_orders(&self, trader_id: i64, card_id: Option<i32>, side: Option<i16>) -> Result<Vec<Order>>;
+  // Entries of the card after the sequence, in order
+  async fn query_journal(&self, card_id: i32, after_sequence: i64) -> Result<Vec<JournalEntry>>;
+  async fn query_snapshot(&self, card_id: i32) -> Result<Option<BookSnapshot>>;
+  // Replaces the card's snapshot unless the stored one is newer
+  async fn save_snapshot(&self, snapshot: &BookSnapshot) -> Result<()>;
+  async fn insert_call_auction(&self, card_id: i32, opens_at: chrono::DateTime<chrono::Utc>, closes_at: chrono::DateTime<chrono::Utc>) -> Result<CallAuction>;
+  // Latest opening first
+  async fn query_call_auctions(&self, card_id: i32) -> Result<Vec<CallAuction>>;
+  // Auctions to open or uncross at the time, in order of their opening
+  async fn query_due_call_auctions(&self, now: chrono::DateTime<chrono::Utc>) -> Result<Vec<CallAuction>>;
+  // The auction collecting orders of the card, if any
+  async fn query_open_call_auction(&self, card_id: i32) -> Result<Option<CallAuction>>;
+  // Price of the card's latest trade
+  async fn query_last_price(&self, card_id: i32) -> Result<Option<i32>>;
+  // Pending orders with the day time in force
+  async fn query_day_orders(&self) -> Result<Vec<Order>>;
+  // Closures which end after the time, in order of their start
+  async fn query_market_closures(&self, after: chrono::DateTime<chrono::Utc>) -> Result<Vec<MarketClosure>>;
+  async fn query_trading_halts(&self) -> Result<Vec<TradingHalt>>;
+  // Pending orders of every trader, what the risk checks start from
+  async fn query_open_orders(&self) -> Result<Vec<Order>>;
+  // Daily volume is counted from the start of the day
+  async fn query_positions(&self, day_start: chrono::DateTime<chrono::Utc>) -> Result<Vec<TraderPosition>>;
+  async fn query_trader_suspensions(&self) -> Result<Vec<TraderSuspension>>;
+  // For actions outside of the matching engine's transactions
+  async fn insert_audit_entry(&self, entry: NewAuditEntry) -> Result<()>;
+  // Latest first
+  async fn query_audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>>;
+  async fn begin(&self) -> Result<Box<dyn OrderTransaction>>;
+}
+
+// All writes of order entry go through one transaction, nothing is written if it's dropped without commit
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait OrderTransaction: Send {
+  // Fails if another leader was elected after the epoch, so a deposed leader can't write
+  async fn fence(&mut self, epoch: i64) -> Result<()>;
+  // Returns None if the trader already used the client_order_id
+  async fn insert_order(&mut self, order: NewOrder) -> Result<Option<i64>>;
+  async fn update_order_status(&mut self, order_id: i64, status: Status) -> Result<()>;
+  // Adds to filled_quantity, the order becomes filled once nothing is left, returns the quantity left
+  async fn fill_order(&mut self, order_id: i64, quantity: i32) -> Result<i32>;
+  async fn update_order(&mut self, order_id: i64, price: i32, quantity: i32) -> Result<()>;
+  // Reduces the quantity of a pending order, used by self-trade prevention
+  async fn decrement_order(&mut self, order_id: i64, quantity: i32) -> Result<()>;
+  async fn trigger_order(&mut self, order_id: i64) -> Result<()>;
+  async fn reprice_order(&mut self, order_id: i64, price: i32) -> Result<()>;
+  async fn insert_order_event(&mut self, event: NewOrderEvent) -> Result<()>;
+  // Returns the id of the trade
+  async fn insert_trade(&mut self, trade: NewTrade) -> Result<i64>;
+  // Returns the sequence of the entry
+  async fn append_journal(&mut self, entry: NewJournalEntry) -> Result<i64>;
+  async fn open_call_auction(&mut self, auction_id: i64) -> Result<()>;
+  async fn uncross_call_auction(&mut self, auction_id: i64, price: Option<i32>, volume: i32) -> Result<()>;
+  async fn insert_market_closure(&mut self, kind: ClosureKind, starts_at: chrono::DateTime<chrono::Utc>, ends_at: chrono::DateTime<chrono::Utc>, reason: &str) -> Result<MarketClosure>;
+  // Inserts an auction which the leader opens once it's due
+  async fn insert_call_auction(&mut self, card_id: i32, opens_at: chrono::DateTime<chrono::Utc>, closes_at: chrono::DateTime<chrono::Utc>) -> Result<CallAuction>;
+  // Inserts an auction of the card which is open already
+  async fn insert_halt_auction(&mut self, card_id: i32, closes_at: chrono::DateTime<chrono::Utc>) -> Result<i64>;
+  // Without card_id for the whole market, halting a halted one keeps the first halt
+  async fn set_trading_halt(&mut self, card_id: Option<i32>, halted: bool, operator: &str, reason: &str) -> Result<()>;
+  async fn set_trader_suspension(&mut self, trader_id: i64, suspended: bool, operator: &str, reason: &str) -> Result<()>;
+  // False for an unknown trader
+  async fn save_role(&mut self, trader_id: i64, role: Role) -> Result<bool>;
+  // Replaces the API key of the trader with a new random one, None for an unknown trader
+  async fn issue_api_key(&mut self, trader_id: i64) -> Result<Option<String>>;
+  async fn insert_audit_entry(&mut self, entry: NewAuditEntry) -> Result<()>;
+  async fn commit(&mut self) -> Result<()>;
 }
 
 
-#[derive(sqlx::FromRow, Serialize)]
+#[derive(sqlx::FromRow, Serialize, ToSchema)]
 pub struct Trade {
     pub id: i64,
     pub card_id: i32,
     pub price: i32,
     pub buyorder_id: i64,
     pub sellorder_id: i64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub buyer_fee: i32,
+    pub seller_fee: i32,
+    pub quantity: i32,
+}
+
+pub struct NewTrade {
+  pub card_id: i32,
+  pub price: i32,
+  pub quantity: i32,
+  pub buyorder_id: i64,
+  pub sellorder_id: i64,
+  pub buyer_fee: i32,
+  pub seller_fee: i32,
+  pub journal_sequence: i64,
+}
+
+// A trade of the matching engine, tagged with the journal entry which produced it
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct JournalTrade {
+    pub journal_sequence: i64,
+    pub buyorder_id: i64,
+    pub sellorder_id: i64,
+    pub price: i32,
+    pub quantity: i32,
+}
+
+// A trade seen from one trader's side, the counterparty is replaced by an opaque pseudonym
+#[derive(sqlx::FromRow, Serialize, ToSchema)]
+pub struct TraderTrade {
+    pub trade_id: i64,
+    pub order_id: i64,
+    pub card_id: i32,
+    /// 0: buy, 1: sell
+    pub side: i16,
+    pub price: i32,
+    pub quantity: i32,
+    pub fee: i32,
+    pub counterparty: String,
     pub created_at: chrono::DateTime<chrono::Utc>
 }
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait TradeStore {
-  async fn insert_trade(&self, card_id: i32, price: i32, buyorder_id: i64, sellorder_id: i64) -> Result<()>;
   async fn query_trades(&self, card_id: i32, limit: Option<i64>) -> Result<Vec<Trade>>;
+  async fn query_trader_trades(&self, trader_id: i64, limit: Option<i64>) -> Result<Vec<TraderTrade>>;
+  // Trades of the card produced by journal entries, in order of the journal
+  async fn query_journal_trades(&self, card_id: i32) -> Result<Vec<JournalTrade>>;
+}
+
+// Topics of the outbox, the payload is a Trade or an OrderEvent with the trader_id and card_id of the order
+pub const OUTBOX_TOPIC_TRADES: &str = "trades";
+pub const OUTBOX_TOPIC_ORDERS: &str = "orders";
+
+// What the relay publishes, consumers tell redeliveries apart by the id. Ids are taken at insert and the
+// matching threads of the cards commit concurrently, so only the messages of one card are committed
+// in order of id, those of different cards may show up out of order
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub topic: String,
+    pub payload: serde_json::Value,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    // Failed attempts so far
+    #[serde(skip)]
+    pub attempts: i32,
+    // Messages are kept in order per card, the payload has it too
+    #[serde(skip)]
+    pub card_id: Option<i32>,
+}
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait OutboxStore {
+  // Holds the oldest unpublished messages for the lease, in order of id, none past an older message of its card
+  // which is waiting for its retry or held by another relay; parked messages are skipped
+  async fn claim_messages(&self, limit: i64, lease: std::time::Duration) -> Result<Vec<OutboxMessage>>;
+  async fn mark_published(&self, ids: &[i64]) -> Result<()>;
+  async fn mark_failed(&self, id: i64, retry_at: chrono::DateTime<chrono::Utc>, error: &str) -> Result<()>;
+  // Gives up on a message after its last attempt, so the later messages of its card go on
+  async fn park(&self, id: i64, error: &str) -> Result<()>;
+  // Gives held messages back without counting an attempt
+  async fn release(&self, ids: &[i64]) -> Result<()>;
+}
+
+// Where the relay publishes the outbox to, a message is published once the sink returns Ok
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait OutboxSink: Send + Sync {
+  fn name(&self) -> &str;
+  async fn publish(&self, message: &OutboxMessage) -> Result<()>;
 }
 
 
 #[async_trait]
 pub trait OrderService {
-    async fn add_order(&self, trader_id: i64, side: Action, price: i32, card_id: i32) -> Result<()>;
-}
\ No newline at end of file
+    // Resubmitting a client_order_id returns the order placed first instead of placing a new one
+    async fn add_order(&self, trader_id: i64, order: PlaceOrder) -> Result<Order, Error>;
+    // Places the orders of each card in one transaction, cards are placed concurrently and
+    // independently, so a failed card doesn't undo the others; the result of each order
+    // is in the same position as in the request
+    async fn add_orders(&self, trader_id: i64, orders: Vec<PlaceOrder>) -> Result<Vec<Result<Order, Error>>, Error>;
+    async fn cancel_order(&self, order: &Order) -> Result<(), Error>;
+    // Cancels all pending orders of the trader matching the filters, returns the cancelled order ids
+    async fn cancel_orders(&self, trader_id: i64, card_id: Option<i32>, side: Option<Action>) -> Result<Vec<i64>, Error>;
+    // Quantity is the new total quantity including the filled part
+    async fn amend_order(&self, order: &Order, price: Option<i32>, quantity: Option<i32>) -> Result<Order, Error>;
+    // Snapshots the book of every card, returns the snapshots without their orders
+    async fn snapshot(&self) -> Result<Vec<BookSnapshot>, Error>;
+    // The best price levels of each side of the card's book, from the matching engine
+    async fn depth(&self, card_id: i32, levels: usize) -> Result<Depth, Error>;
+    // Fails if the window overlaps another auction of the card which is not uncrossed yet
+    async fn schedule_call_auction(&self, operator: &str, card_id: i32, opens_at: chrono::DateTime<chrono::Utc>, closes_at: chrono::DateTime<chrono::Utc>) -> Result<CallAuction, Error>;
+    // Opens and uncrosses the auctions which are due at now, run periodically by the leader
+    async fn run_call_auctions(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(), Error>;
+    async fn card_status(&self, card_id: i32) -> Result<CardStatus, Error>;
+    // Moves the market to the phase of the calendar at now and runs the due call auctions, run periodically by the leader
+    async fn run_sessions(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(), Error>;
+    async fn schedule_closure(&self, operator: &str, kind: ClosureKind, starts_at: chrono::DateTime<chrono::Utc>, ends_at: chrono::DateTime<chrono::Utc>, reason: &str) -> Result<MarketClosure, Error>;
+    async fn session_status(&self) -> Result<SessionStatus, Error>;
+    async fn ticker(&self, card_id: i32) -> Result<Ticker, Error>;
+    // Without card_id the whole market is halted or resumed, cancellations are accepted while halted
+    async fn set_trading_halt(&self, operator: &str, card_id: Option<i32>, halted: bool, reason: &str) -> Result<(), Error>;
+    // Orders of a suspended trader are rejected, its open orders stay until cancelled
+    async fn set_trader_suspension(&self, operator: &str, trader_id: i64, suspended: bool, reason: &str) -> Result<(), Error>;
+    // Cancels all pending orders of the trader regardless of its rate limit, returns the cancelled order ids
+    async fn cancel_trader_orders(&self, operator: &str, trader_id: i64, reason: &str) -> Result<Vec<i64>, Error>;
+    // Overrides of the trader's risk limits, None if it has the defaults
+    async fn risk_limits(&self, trader_id: i64) -> Result<Option<TraderRiskLimits>, Error>;
+    // Replaces the overrides, every instance uses them within a second
+    async fn set_risk_limits(&self, operator: &str, trader_id: i64, limits: TraderRiskLimits) -> Result<(), Error>;
+    // Reloads the overrides of the risk limits, so order entry checks them without a query
+    async fn refresh_risk_limits(&self) -> Result<(), Error>;
+    // Records an admin action which is done outside of the matching engine, on any instance
+    async fn audit(&self, entry: NewAuditEntry) -> Result<(), Error>;
+    // The next request of the trader is authorized with the new role
+    async fn set_role(&self, operator: &str, trader_id: i64, role: Role) -> Result<(), Error>;
+    // Returns the new API key, the previous one stops working
+    async fn issue_api_key(&self, operator: &str, trader_id: i64) -> Result<String, Error>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_order_event_kind_of_stored_value() {
+        for kind in [OrderEventKind::Amended, OrderEventKind::SelfTradePrevented, OrderEventKind::Triggered, OrderEventKind::PostOnlyRejected, OrderEventKind::Repriced,
+            OrderEventKind::Created, OrderEventKind::PartiallyFilled, OrderEventKind::Filled, OrderEventKind::Cancelled, OrderEventKind::Expired] {
+            assert_eq!(kind, OrderEventKind::try_from(kind as i16).unwrap());
+        }
+        assert!(OrderEventKind::try_from(10).is_err());
+        assert!(OrderEventKind::try_from(-1).is_err());
+    }
+}
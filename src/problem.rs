@@ -0,0 +1,184 @@
+use actix_web::{HttpResponse, ResponseError, http::{header, StatusCode}};
+use log::error;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::error::Error;
+
+// RFC 7807 problem details, `code` is the stable error code of the domain error
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    /// Stable machine-readable error code
+    pub code: &'static str,
+}
+
+impl From<&Error> for Problem {
+    fn from(e: &Error) -> Self {
+        let status = e.status_code();
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown"),
+            status: status.as_u16(),
+            detail: e.detail(),
+            code: e.code(),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest(_)
+            | Error::InvalidSide
+            | Error::InvalidPrice
+            | Error::InvalidQuantity
+            | Error::InvalidCardId
+            | Error::InvalidClientOrderId
+            | Error::InvalidSelfTradePrevention
+            | Error::InvalidOrderType
+            | Error::InvalidStopPrice
+            | Error::InvalidDisplayQuantity
+            | Error::InvalidPostOnly
+            | Error::PriceOutsideBand(..)
+            | Error::OrderValueLimitExceeded(_)
+            | Error::OpenOrdersLimitExceeded(_)
+            | Error::CardNotionalLimitExceeded(_)
+            | Error::PositionLimitExceeded(_)
+            | Error::DailyVolumeLimitExceeded(_)
+            | Error::InvalidAuctionWindow
+            | Error::InvalidClosureWindow
+            | Error::InvalidTimeInForce
+            | Error::OperatorRequired
+            | Error::InvalidBatchSize
+            | Error::EmptyAmendment
+            | Error::QuantityNotAboveFilled => StatusCode::BAD_REQUEST,
+            Error::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Error::TraderSuspended | Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::TraderNotFound | Error::OrderNotFound | Error::CardNotFound => StatusCode::NOT_FOUND,
+            Error::OrderNotPending | Error::AuctionOverlaps | Error::MarketClosed | Error::TradingHalted => StatusCode::CONFLICT,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::NotLeader => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let Error::Internal(e) = self {
+            error!("{:#}", e);
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::RateLimited(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, *retry_after));
+        }
+        response
+            .content_type("application/problem+json")
+            .json(Problem::from(self))
+    }
+}
+
+// Used by the extractor configs, so malformed bodies and queries get a problem too
+pub fn invalid_request(e: impl std::fmt::Display) -> actix_web::Error {
+    Error::InvalidRequest(e.to_string()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::body::to_bytes;
+    use anyhow::anyhow;
+    use super::*;
+
+    #[test]
+    fn test_status_and_code_of_errors() {
+        // The codes are part of the API, a failure here means clients break
+        let errors = vec![
+            (Error::InvalidRequest("Invalid JSON".to_string()), 400, "invalid_request"),
+            (Error::InvalidSide, 400, "invalid_side"),
+            (Error::InvalidPrice, 400, "invalid_price"),
+            (Error::InvalidQuantity, 400, "invalid_quantity"),
+            (Error::InvalidCardId, 400, "invalid_card_id"),
+            (Error::InvalidClientOrderId, 400, "invalid_client_order_id"),
+            (Error::InvalidSelfTradePrevention, 400, "invalid_self_trade_prevention"),
+            (Error::InvalidOrderType, 400, "invalid_order_type"),
+            (Error::InvalidStopPrice, 400, "invalid_stop_price"),
+            (Error::InvalidDisplayQuantity, 400, "invalid_display_quantity"),
+            (Error::InvalidPostOnly, 400, "invalid_post_only"),
+            (Error::PriceOutsideBand(90, 110), 400, "price_outside_band"),
+            (Error::OrderValueLimitExceeded(1000), 400, "order_value_limit_exceeded"),
+            (Error::OpenOrdersLimitExceeded(10), 400, "open_orders_limit_exceeded"),
+            (Error::CardNotionalLimitExceeded(1000), 400, "card_notional_limit_exceeded"),
+            (Error::PositionLimitExceeded(10), 400, "position_limit_exceeded"),
+            (Error::DailyVolumeLimitExceeded(10), 400, "daily_volume_limit_exceeded"),
+            (Error::InvalidAuctionWindow, 400, "invalid_auction_window"),
+            (Error::InvalidClosureWindow, 400, "invalid_closure_window"),
+            (Error::InvalidTimeInForce, 400, "invalid_time_in_force"),
+            (Error::OperatorRequired, 400, "operator_required"),
+            (Error::InvalidBatchSize, 400, "invalid_batch_size"),
+            (Error::EmptyAmendment, 400, "empty_amendment"),
+            (Error::QuantityNotAboveFilled, 400, "quantity_not_above_filled"),
+            (Error::Unauthenticated, 401, "unauthenticated"),
+            (Error::Forbidden, 403, "forbidden"),
+            (Error::TraderSuspended, 403, "trader_suspended"),
+            (Error::TraderNotFound, 404, "trader_not_found"),
+            (Error::OrderNotFound, 404, "order_not_found"),
+            (Error::CardNotFound, 404, "card_not_found"),
+            (Error::OrderNotPending, 409, "order_not_pending"),
+            (Error::AuctionOverlaps, 409, "auction_overlaps"),
+            (Error::MarketClosed, 409, "market_closed"),
+            (Error::TradingHalted, 409, "trading_halted"),
+            (Error::RateLimited(1), 429, "rate_limited"),
+            (Error::NotLeader, 503, "not_leader"),
+            (Error::from(anyhow!("Connection refused")), 500, "internal_error"),
+        ];
+        for (e, status, code) in errors {
+            assert_eq!(status, e.status_code().as_u16(), "{}", code);
+            assert_eq!(code, e.code());
+        }
+    }
+
+    async fn problem_of(e: Error) -> (StatusCode, header::HeaderMap, serde_json::Value) {
+        let response = e.error_response();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_problem_response() {
+        let (status, headers, body) = problem_of(Error::OrderNotFound).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!("application/problem+json", headers.get(header::CONTENT_TYPE).unwrap());
+        assert!(headers.get(header::RETRY_AFTER).is_none());
+        assert_eq!(serde_json::json!({
+            "type": "about:blank",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "Order not found",
+            "code": "order_not_found",
+        }), body);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_rate_limited_response_has_retry_after() {
+        let (status, headers, body) = problem_of(Error::RateLimited(3)).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, status);
+        assert_eq!("3", headers.get(header::RETRY_AFTER).unwrap());
+        assert_eq!("application/problem+json", headers.get(header::CONTENT_TYPE).unwrap());
+        assert_eq!(serde_json::json!("rate_limited"), body["code"]);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_internal_error_detail_is_hidden() {
+        let (status, _, body) = problem_of(Error::from(anyhow!("Connection refused"))).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert_eq!(serde_json::json!("Internal server error"), body["detail"]);
+        assert_eq!(serde_json::json!("internal_error"), body["code"]);
+    }
+}
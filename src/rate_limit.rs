@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::{web, body::MessageBody, dev::{ServiceRequest, ServiceResponse}};
+use actix_web_lab::middleware::Next;
+use log::error;
+
+use crate::error::Error;
+use crate::ports::{TraderStore, TraderRateLimits};
+use crate::v1::V1Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub trader: Limit,
+    pub ip: Limit,
+    // Order entry messages, counted separately from the requests
+    pub order: Limit,
+    // Only behind a proxy which sets the header, clients could fake it otherwise
+    pub trust_forwarded_for: bool,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    // A cost above the burst, e.g. a large batch, is admitted on a full bucket and paid back as debt
+    fn take(&mut self, limit: Limit, cost: u32, now: Instant) -> Result<(), Duration> {
+        let rate = limit.per_second as f64;
+        let burst = limit.burst.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated_at = now;
+        let required = (cost as f64).min(burst);
+        if self.tokens >= required {
+            self.tokens -= cost as f64;
+            return Ok(());
+        }
+        if rate <= 0.0 {
+            return Err(BLOCKED_RETRY_AFTER);
+        }
+        Err(Duration::from_secs_f64((required - self.tokens) / rate))
+    }
+}
+
+// Retry hint when the limit is zero, e.g. a trader blocked by an override
+const BLOCKED_RETRY_AFTER: Duration = Duration::from_secs(60);
+// Buckets idle this long are full again and can be dropped
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+const MAX_BUCKETS: usize = 100_000;
+
+pub struct RateLimiter<K> {
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new() -> Self {
+        Self { buckets: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn check(&self, key: K, limit: Limit, cost: u32) -> Result<(), Duration> {
+        self.check_at(key, limit, cost, Instant::now())
+    }
+
+    fn check_at(&self, key: K, limit: Limit, cost: u32, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated_at) < IDLE_BUCKET_TTL);
+        }
+        buckets.entry(key)
+            .or_insert(TokenBucket { tokens: limit.burst as f64, updated_at: now })
+            .take(limit, cost, now)
+    }
+}
+
+// How long overrides from the database are used before they are read again
+const OVERRIDE_TTL: Duration = Duration::from_secs(60);
+
+pub struct RateLimits {
+    config: RateLimitConfig,
+    trader_store: Arc<dyn TraderStore + Send + Sync>,
+    overrides: Mutex<HashMap<i64, (Instant, Option<TraderRateLimits>)>>,
+    traders: RateLimiter<i64>,
+    ips: RateLimiter<IpAddr>,
+    orders: RateLimiter<i64>,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitConfig, trader_store: Arc<dyn TraderStore + Send + Sync>) -> Self {
+        Self {
+            config,
+            trader_store,
+            overrides: Mutex::new(HashMap::new()),
+            traders: RateLimiter::new(),
+            ips: RateLimiter::new(),
+            orders: RateLimiter::new(),
+        }
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Error> {
+        self.ips.check(ip, self.config.ip, 1).map_err(rate_limited)
+    }
+
+    pub async fn check_trader(&self, trader_id: i64) -> Result<(), Error> {
+        let overrides = self.overrides(trader_id).await.unwrap_or_default();
+        let limit = with_override(self.config.trader, overrides.requests_per_second, overrides.request_burst);
+        self.traders.check(trader_id, limit, 1).map_err(rate_limited)
+    }
+
+    // Every order of a batch is a message
+    pub async fn check_orders(&self, trader_id: i64, count: usize) -> Result<(), Error> {
+        let overrides = self.overrides(trader_id).await.unwrap_or_default();
+        let limit = with_override(self.config.order, overrides.orders_per_second, overrides.order_burst);
+        self.orders.check(trader_id, limit, count.try_into().unwrap_or(u32::MAX)).map_err(rate_limited)
+    }
+
+    async fn overrides(&self, trader_id: i64) -> Option<TraderRateLimits> {
+        if let Some((loaded_at, overrides)) = self.overrides.lock().unwrap().get(&trader_id) {
+            if loaded_at.elapsed() < OVERRIDE_TTL {
+                return overrides.clone();
+            }
+        }
+        // Defaults are used while the database is unavailable
+        let overrides = match self.trader_store.query_rate_limits(trader_id).await {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                error!("Failed to query rate limits of trader {}: {}", trader_id, e);
+                return None;
+            },
+        };
+        let mut cache = self.overrides.lock().unwrap();
+        if cache.len() >= MAX_BUCKETS {
+            cache.clear();
+        }
+        cache.insert(trader_id, (Instant::now(), overrides.clone()));
+        overrides
+    }
+}
+
+fn with_override(default: Limit, per_second: Option<i32>, burst: Option<i32>) -> Limit {
+    Limit {
+        per_second: per_second.map_or(default.per_second, |v| v.max(0) as u32),
+        burst: burst.map_or(default.burst, |v| v.max(0) as u32),
+    }
+}
+
+fn rate_limited(retry_after: Duration) -> Error {
+    // Retry-After has a resolution of seconds, round up so the retry is not rejected again
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Error::RateLimited(secs.max(1))
+}
+
+// Limits every request by client IP, requests of a trader are limited once the caller is authenticated
+pub async fn limit_requests(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let path = req.path().to_string();
+    if path == "/api/health" {
+        return next.call(req).await;
+    }
+    if let Some(rate_limits) = req.app_data::<web::Data<RateLimits>>().cloned() {
+        let ip = if rate_limits.config.trust_forwarded_for {
+            req.connection_info().realip_remote_addr().and_then(|addr| addr.parse().ok())
+        } else {
+            req.peer_addr().map(|addr| addr.ip())
+        };
+        if let Err(e) = ip.map_or(Ok(()), |ip| rate_limits.check_ip(ip)) {
+            // Same error format as the API version of the route
+            let is_v1 = path.starts_with("/api/") && !path.starts_with("/api/v2/");
+            return Err(if is_v1 { V1Error::from(e).into() } else { e.into() });
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LIMIT: Limit = Limit { per_second: 2, burst: 4 };
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for _ in 0..4 {
+            assert!(limiter.check_at(1, LIMIT, 1, now).is_ok());
+        }
+        assert_eq!(limiter.check_at(1, LIMIT, 1, now), Err(Duration::from_millis(500)));
+        // other keys have their own bucket
+        assert!(limiter.check_at(2, LIMIT, 1, now).is_ok());
+        assert!(limiter.check_at(1, LIMIT, 1, now + Duration::from_millis(500)).is_ok());
+        assert!(limiter.check_at(1, LIMIT, 1, now + Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_cost_above_burst_is_paid_back() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.check_at(1, LIMIT, 10, now).is_ok());
+        // 6 tokens in debt, one is available again after 3.5 seconds
+        assert_eq!(limiter.check_at(1, LIMIT, 1, now), Err(Duration::from_millis(3500)));
+        assert!(limiter.check_at(1, LIMIT, 1, now + Duration::from_millis(3500)).is_ok());
+    }
+
+    #[test]
+    fn test_zero_limit_blocks() {
+        let limiter = RateLimiter::new();
+        assert_eq!(limiter.check(1, Limit { per_second: 0, burst: 0 }, 1), Err(BLOCKED_RETRY_AFTER));
+    }
+}
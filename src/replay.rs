@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+
+use crate::card;
+use crate::order_manager::OrderBook;
+use crate::ports::{Action, OrderStore, TradeStore, JournalTrade};
+
+// Replays the journal of every card and compares the rebuilt books and fills with what is stored
+// in orders and trades, returns a description of every mismatch
+pub async fn verify(order_store: &impl OrderStore, trade_store: &impl TradeStore) -> Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+    for card_id in 0..card::NUM_CARDS as i32 {
+        let entries = order_store.query_journal(card_id, 0).await.with_context(|| format!("Failed to load journal of card {}", card_id))?;
+        let mut order_book = OrderBook::for_card(card_id);
+        let mut fills = Vec::new();
+        for entry in &entries {
+            fills.extend(order_book.apply(entry)?.filled_orders.into_iter().map(|filled| JournalTrade {
+                journal_sequence: entry.sequence,
+                buyorder_id: filled.buy_order,
+                sellorder_id: filled.sell_order,
+                price: filled.price,
+                quantity: filled.quantity,
+            }));
+        }
+
+        let trades = trade_store.query_journal_trades(card_id).await.with_context(|| format!("Failed to query trades of card {}", card_id))?;
+        if let Some((fill, trade)) = fills.iter().zip(&trades).find(|(fill, trade)| fill != trade) {
+            mismatches.push(format!("card {}: replayed {:?}, stored {:?}", card_id, fill, trade));
+        } else if fills.len() != trades.len() {
+            mismatches.push(format!("card {}: replayed {} trades, stored {}", card_id, fills.len(), trades.len()));
+        }
+
+        // id -> (side, price, open quantity)
+        let replayed: BTreeMap<i64, (i16, i32, i32)> = order_book.resting_orders().chain(order_book.stop_orders())
+            .map(|order| (order.id, (order.side.clone() as i16, order.price, order.quantity)))
+            .collect();
+        let mut stored = BTreeMap::new();
+        for side in [Action::Buy, Action::Sell] {
+            let orders = order_store.query_pending_orders(card_id, side as i16).await.with_context(|| format!("Failed to query orders of card {}", card_id))?;
+            stored.extend(orders.into_iter().map(|order| (order.id, (order.side, order.price, order.quantity))));
+        }
+        for order_id in replayed.keys().chain(stored.keys().filter(|id| !replayed.contains_key(id))) {
+            let (replayed, stored) = (replayed.get(order_id), stored.get(order_id));
+            if replayed != stored {
+                mismatches.push(format!("card {}: order {} replayed {:?}, stored {:?}", card_id, order_id, replayed, stored));
+            }
+        }
+    }
+    Ok(mismatches)
+}
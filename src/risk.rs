@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::error::Error;
+use crate::ports::{Action, Order, PlaceOrder, Status, OrderTransaction, NewOrder, NewOrderEvent, NewTrade, NewJournalEntry, NewAuditEntry, TraderPosition, TraderRiskLimits, Role, CallAuction, ClosureKind, MarketClosure};
+
+// Risk limits of a trader, 0 disables a limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RiskLimits {
+    pub max_order_value: i64,
+    pub max_open_orders: i64,
+    pub max_card_notional: i64,
+    pub max_position: i64,
+    pub max_daily_volume: i64,
+}
+
+impl RiskLimits {
+    pub fn with_overrides(self, overrides: Option<&TraderRiskLimits>) -> Self {
+        let Some(overrides) = overrides else {
+            return self;
+        };
+        RiskLimits {
+            max_order_value: overrides.max_order_value.unwrap_or(self.max_order_value),
+            max_open_orders: overrides.max_open_orders.map_or(self.max_open_orders, i64::from),
+            max_card_notional: overrides.max_card_notional.unwrap_or(self.max_card_notional),
+            max_position: overrides.max_position.map_or(self.max_position, i64::from),
+            max_daily_volume: overrides.max_daily_volume.map_or(self.max_daily_volume, i64::from),
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        *self == RiskLimits::default()
+    }
+
+    // The order is counted as if it rested in full and could fill in full
+    pub fn check(&self, exposure: &Exposure, side: &Action, price: i32, quantity: i32) -> Result<(), Error> {
+        let value = i64::from(price) * i64::from(quantity);
+        let quantity = i64::from(quantity);
+        if self.max_order_value > 0 && value > self.max_order_value {
+            return Err(Error::OrderValueLimitExceeded(self.max_order_value));
+        }
+        if self.max_open_orders > 0 && exposure.open_orders + 1 > self.max_open_orders {
+            return Err(Error::OpenOrdersLimitExceeded(self.max_open_orders));
+        }
+        if self.max_card_notional > 0 && exposure.notional + value > self.max_card_notional {
+            return Err(Error::CardNotionalLimitExceeded(self.max_card_notional));
+        }
+        let position = match side {
+            Action::Buy => exposure.position + exposure.open_buy + quantity,
+            Action::Sell => exposure.open_sell + quantity - exposure.position,
+        };
+        if self.max_position > 0 && position > self.max_position {
+            return Err(Error::PositionLimitExceeded(self.max_position));
+        }
+        if self.max_daily_volume > 0 && exposure.daily_volume + quantity > self.max_daily_volume {
+            return Err(Error::DailyVolumeLimitExceeded(self.max_daily_volume));
+        }
+        Ok(())
+    }
+
+    // Each order counts the ones before it in the request
+    pub fn check_orders<'a>(&self, orders: impl IntoIterator<Item = &'a PlaceOrder>, exposure: impl Fn(i32) -> Exposure) -> Result<(), Error> {
+        let mut card_exposures: HashMap<i32, Exposure> = HashMap::new();
+        let (mut open_orders, mut volume) = (0, 0);
+        for order in orders {
+            let card_exposure = card_exposures.entry(order.card_id).or_insert_with(|| exposure(order.card_id));
+            // Open orders and daily volume are of every card
+            let current = Exposure { open_orders: card_exposure.open_orders + open_orders, daily_volume: card_exposure.daily_volume + volume, ..*card_exposure };
+            self.check(&current, &order.side, order.price, order.quantity)?;
+            let quantity = i64::from(order.quantity);
+            card_exposure.notional += i64::from(order.price) * quantity;
+            match order.side {
+                Action::Buy => card_exposure.open_buy += quantity,
+                Action::Sell => card_exposure.open_sell += quantity,
+            }
+            open_orders += 1;
+            volume += quantity;
+        }
+        Ok(())
+    }
+
+    // The amended order replaces the pending one, quantity is the new total including the filled part
+    pub fn check_amendment(&self, exposure: &Exposure, order: &Order, price: i32, quantity: i32) -> Result<(), Error> {
+        let open_quantity = i64::from(order.quantity - order.filled_quantity);
+        let mut exposure = Exposure {
+            open_orders: exposure.open_orders - 1,
+            notional: exposure.notional - i64::from(order.price) * open_quantity,
+            ..*exposure
+        };
+        let side = if order.side == Action::Buy as i16 { Action::Buy } else { Action::Sell };
+        match side {
+            Action::Buy => exposure.open_buy -= open_quantity,
+            Action::Sell => exposure.open_sell -= open_quantity,
+        }
+        self.check(&exposure, &side, price, quantity - order.filled_quantity)
+    }
+}
+
+// What a trader has outstanding on a card
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Exposure {
+    // Pending orders of every card
+    pub open_orders: i64,
+    // Price times open quantity of the pending orders
+    pub notional: i64,
+    pub open_buy: i64,
+    pub open_sell: i64,
+    // Net quantity bought
+    pub position: i64,
+    // Of every card, traded today
+    pub daily_volume: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpenOrder {
+    trader_id: i64,
+    card_id: i32,
+    side: i16,
+    price: i32,
+    quantity: i32,
+    filled_quantity: i32,
+}
+
+impl OpenOrder {
+    fn open_quantity(&self) -> i64 {
+        i64::from(self.quantity - self.filled_quantity)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CardExposure {
+    notional: i64,
+    open_buy: i64,
+    open_sell: i64,
+    position: i64,
+}
+
+#[derive(Debug, Default)]
+struct TraderExposure {
+    open_orders: i64,
+    cards: HashMap<i32, CardExposure>,
+    // Day the volume was traded on, it starts over on the next one
+    volume_date: Option<NaiveDate>,
+    daily_volume: i64,
+}
+
+// Exposures of every trader, following the order writes committed by the matchers
+#[derive(Debug, Default)]
+pub struct Exposures {
+    orders: HashMap<i64, OpenOrder>,
+    traders: HashMap<i64, TraderExposure>,
+}
+
+impl Exposures {
+    pub fn load(open_orders: &[Order], positions: &[TraderPosition], today: NaiveDate) -> Self {
+        let mut exposures = Exposures::default();
+        for order in open_orders {
+            exposures.add(order.id, OpenOrder {
+                trader_id: order.trader_id,
+                card_id: order.card_id,
+                side: order.side,
+                price: order.price,
+                quantity: order.quantity,
+                filled_quantity: order.filled_quantity,
+            });
+        }
+        for position in positions {
+            let trader = exposures.traders.entry(position.trader_id).or_default();
+            trader.cards.entry(position.card_id).or_default().position += position.position;
+            trader.volume_date = Some(today);
+            trader.daily_volume += position.daily_volume;
+        }
+        exposures
+    }
+
+    pub fn exposure(&self, trader_id: i64, card_id: i32, today: NaiveDate) -> Exposure {
+        let Some(trader) = self.traders.get(&trader_id) else {
+            return Exposure::default();
+        };
+        let card = trader.cards.get(&card_id);
+        Exposure {
+            open_orders: trader.open_orders,
+            notional: card.map_or(0, |card| card.notional),
+            open_buy: card.map_or(0, |card| card.open_buy),
+            open_sell: card.map_or(0, |card| card.open_sell),
+            position: card.map_or(0, |card| card.position),
+            daily_volume: if trader.volume_date == Some(today) { trader.daily_volume } else { 0 },
+        }
+    }
+
+    fn add(&mut self, order_id: i64, order: OpenOrder) {
+        self.adjust(&order, 1);
+        self.orders.insert(order_id, order);
+    }
+
+    fn remove(&mut self, order_id: i64) -> Option<OpenOrder> {
+        let order = self.orders.remove(&order_id)?;
+        self.adjust(&order, -1);
+        Some(order)
+    }
+
+    fn adjust(&mut self, order: &OpenOrder, sign: i64) {
+        let trader = self.traders.entry(order.trader_id).or_default();
+        trader.open_orders += sign;
+        let card = trader.cards.entry(order.card_id).or_default();
+        card.notional += sign * i64::from(order.price) * order.open_quantity();
+        if order.side == Action::Buy as i16 {
+            card.open_buy += sign * order.open_quantity();
+        } else {
+            card.open_sell += sign * order.open_quantity();
+        }
+    }
+
+    fn apply(&mut self, write: &OrderWrite, today: NaiveDate) {
+        match *write {
+            OrderWrite::Insert(order_id, order) => self.add(order_id, order),
+            OrderWrite::Status(order_id, status) => {
+                if status != Status::Pending {
+                    self.remove(order_id);
+                }
+            },
+            OrderWrite::Fill(order_id, quantity) => {
+                let Some(mut order) = self.remove(order_id) else {
+                    return;
+                };
+                order.filled_quantity += quantity;
+                let trader = self.traders.entry(order.trader_id).or_default();
+                if trader.volume_date != Some(today) {
+                    trader.volume_date = Some(today);
+                    trader.daily_volume = 0;
+                }
+                trader.daily_volume += i64::from(quantity);
+                let signed_quantity = if order.side == Action::Buy as i16 { i64::from(quantity) } else { -i64::from(quantity) };
+                trader.cards.entry(order.card_id).or_default().position += signed_quantity;
+                if order.filled_quantity < order.quantity {
+                    self.add(order_id, order);
+                }
+            },
+            OrderWrite::Update(order_id, price, quantity) => self.modify(order_id, |order| { order.price = price; order.quantity = quantity; }),
+            OrderWrite::Decrement(order_id, quantity) => self.modify(order_id, |order| order.quantity -= quantity),
+            OrderWrite::Reprice(order_id, price) => self.modify(order_id, |order| order.price = price),
+        }
+    }
+
+    fn modify(&mut self, order_id: i64, change: impl FnOnce(&mut OpenOrder)) {
+        if let Some(mut order) = self.remove(order_id) {
+            change(&mut order);
+            self.add(order_id, order);
+        }
+    }
+}
+
+// Writes which change what a trader has outstanding, like the database does
+#[derive(Debug, Clone, Copy)]
+enum OrderWrite {
+    Insert(i64, OpenOrder),
+    Status(i64, Status),
+    Fill(i64, i32),
+    Update(i64, i32, i32),
+    Decrement(i64, i32),
+    Reprice(i64, i32),
+}
+
+// Mirrors the order writes of a transaction into the exposures once it's committed,
+// so they match the database without querying it
+pub struct TrackedTransaction {
+    tx: Box<dyn OrderTransaction>,
+    exposures: Arc<Mutex<Exposures>>,
+    writes: Vec<OrderWrite>,
+}
+
+impl TrackedTransaction {
+    pub fn new(tx: Box<dyn OrderTransaction>, exposures: Arc<Mutex<Exposures>>) -> Self {
+        TrackedTransaction { tx, exposures, writes: vec![] }
+    }
+}
+
+#[async_trait]
+impl OrderTransaction for TrackedTransaction {
+    async fn fence(&mut self, epoch: i64) -> Result<()> {
+        self.tx.fence(epoch).await
+    }
+    async fn insert_order(&mut self, order: NewOrder) -> Result<Option<i64>> {
+        let open_order = OpenOrder {
+            trader_id: order.trader_id,
+            card_id: order.card_id,
+            side: order.action.clone() as i16,
+            price: order.price,
+            quantity: order.quantity,
+            filled_quantity: 0,
+        };
+        let is_pending = order.status == Status::Pending as i16;
+        let order_id = self.tx.insert_order(order).await?;
+        if let (Some(order_id), true) = (order_id, is_pending) {
+            self.writes.push(OrderWrite::Insert(order_id, open_order));
+        }
+        Ok(order_id)
+    }
+    async fn update_order_status(&mut self, order_id: i64, status: Status) -> Result<()> {
+        self.tx.update_order_status(order_id, status).await?;
+        self.writes.push(OrderWrite::Status(order_id, status));
+        Ok(())
+    }
+    async fn fill_order(&mut self, order_id: i64, quantity: i32) -> Result<i32> {
+        let left = self.tx.fill_order(order_id, quantity).await?;
+        self.writes.push(OrderWrite::Fill(order_id, quantity));
+        Ok(left)
+    }
+    async fn update_order(&mut self, order_id: i64, price: i32, quantity: i32) -> Result<()> {
+        self.tx.update_order(order_id, price, quantity).await?;
+        self.writes.push(OrderWrite::Update(order_id, price, quantity));
+        Ok(())
+    }
+    async fn decrement_order(&mut self, order_id: i64, quantity: i32) -> Result<()> {
+        self.tx.decrement_order(order_id, quantity).await?;
+        self.writes.push(OrderWrite::Decrement(order_id, quantity));
+        Ok(())
+    }
+    async fn trigger_order(&mut self, order_id: i64) -> Result<()> {
+        self.tx.trigger_order(order_id).await
+    }
+    async fn reprice_order(&mut self, order_id: i64, price: i32) -> Result<()> {
+        self.tx.reprice_order(order_id, price).await?;
+        self.writes.push(OrderWrite::Reprice(order_id, price));
+        Ok(())
+    }
+    async fn insert_order_event(&mut self, event: NewOrderEvent) -> Result<()> {
+        self.tx.insert_order_event(event).await
+    }
+    async fn insert_trade(&mut self, trade: NewTrade) -> Result<i64> {
+        self.tx.insert_trade(trade).await
+    }
+    async fn append_journal(&mut self, entry: NewJournalEntry) -> Result<i64> {
+        self.tx.append_journal(entry).await
+    }
+    async fn open_call_auction(&mut self, auction_id: i64) -> Result<()> {
+        self.tx.open_call_auction(auction_id).await
+    }
+    async fn uncross_call_auction(&mut self, auction_id: i64, price: Option<i32>, volume: i32) -> Result<()> {
+        self.tx.uncross_call_auction(auction_id, price, volume).await
+    }
+    async fn insert_market_closure(&mut self, kind: ClosureKind, starts_at: DateTime<Utc>, ends_at: DateTime<Utc>, reason: &str) -> Result<MarketClosure> {
+        self.tx.insert_market_closure(kind, starts_at, ends_at, reason).await
+    }
+    async fn insert_call_auction(&mut self, card_id: i32, opens_at: DateTime<Utc>, closes_at: DateTime<Utc>) -> Result<CallAuction> {
+        self.tx.insert_call_auction(card_id, opens_at, closes_at).await
+    }
+    async fn insert_halt_auction(&mut self, card_id: i32, closes_at: DateTime<Utc>) -> Result<i64> {
+        self.tx.insert_halt_auction(card_id, closes_at).await
+    }
+    async fn set_trading_halt(&mut self, card_id: Option<i32>, halted: bool, operator: &str, reason: &str) -> Result<()> {
+        self.tx.set_trading_halt(card_id, halted, operator, reason).await
+    }
+    async fn set_trader_suspension(&mut self, trader_id: i64, suspended: bool, operator: &str, reason: &str) -> Result<()> {
+        self.tx.set_trader_suspension(trader_id, suspended, operator, reason).await
+    }
+    async fn save_role(&mut self, trader_id: i64, role: Role) -> Result<bool> {
+        self.tx.save_role(trader_id, role).await
+    }
+    async fn issue_api_key(&mut self, trader_id: i64) -> Result<Option<String>> {
+        self.tx.issue_api_key(trader_id).await
+    }
+    async fn insert_audit_entry(&mut self, entry: NewAuditEntry) -> Result<()> {
+        self.tx.insert_audit_entry(entry).await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.tx.commit().await?;
+        let today = Utc::now().naive_utc().date();
+        let mut exposures = self.exposures.lock().unwrap();
+        for write in self.writes.drain(..) {
+            exposures.apply(&write, today);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open_order(trader_id: i64, side: Action, price: i32, quantity: i32) -> OpenOrder {
+        OpenOrder { trader_id, card_id: 1, side: side as i16, price, quantity, filled_quantity: 0 }
+    }
+
+    #[test]
+    fn test_exposures_follow_order_writes() {
+        let today = NaiveDate::from_ymd(2026, 10, 19);
+        let mut exposures = Exposures::default();
+        exposures.apply(&OrderWrite::Insert(1, open_order(1, Action::Buy, 100, 5)), today);
+        exposures.apply(&OrderWrite::Insert(2, open_order(2, Action::Sell, 100, 2)), today);
+        exposures.apply(&OrderWrite::Fill(2, 2), today);
+        exposures.apply(&OrderWrite::Fill(1, 2), today);
+        assert_eq!(Exposure { open_orders: 1, notional: 300, open_buy: 3, open_sell: 0, position: 2, daily_volume: 2 }, exposures.exposure(1, 1, today));
+        assert_eq!(Exposure { position: -2, daily_volume: 2, ..Exposure::default() }, exposures.exposure(2, 1, today));
+        exposures.apply(&OrderWrite::Update(1, 110, 4), today);
+        assert_eq!(220, exposures.exposure(1, 1, today).notional);
+        exposures.apply(&OrderWrite::Status(1, Status::Cancelled), today);
+        assert_eq!(Exposure { position: 2, daily_volume: 2, ..Exposure::default() }, exposures.exposure(1, 1, today));
+        // the volume starts over the next day, the position stays
+        assert_eq!(Exposure { position: 2, ..Exposure::default() }, exposures.exposure(1, 1, today.succ()));
+    }
+
+    #[test]
+    fn test_risk_limits() {
+        let limits = RiskLimits { max_order_value: 1000, max_open_orders: 2, max_card_notional: 1500, max_position: 10, max_daily_volume: 20 };
+        let exposure = Exposure { open_orders: 1, notional: 600, open_buy: 6, open_sell: 0, position: 2, daily_volume: 15 };
+        assert!(limits.check(&exposure, &Action::Buy, 100, 2).is_ok());
+        assert!(matches!(limits.check(&exposure, &Action::Buy, 100, 11), Err(Error::OrderValueLimitExceeded(1000))));
+        assert!(matches!(limits.check(&Exposure { open_orders: 2, ..exposure }, &Action::Buy, 100, 1), Err(Error::OpenOrdersLimitExceeded(2))));
+        assert!(matches!(limits.check(&exposure, &Action::Sell, 1000, 1), Err(Error::CardNotionalLimitExceeded(1500))));
+        assert!(matches!(limits.check(&exposure, &Action::Buy, 100, 3), Err(Error::PositionLimitExceeded(10))));
+        // selling reduces the long position first
+        assert!(limits.check(&exposure, &Action::Sell, 100, 5).is_ok());
+        assert!(matches!(limits.check(&Exposure { daily_volume: 19, ..exposure }, &Action::Sell, 100, 2), Err(Error::DailyVolumeLimitExceeded(20))));
+        assert!(RiskLimits::default().check(&exposure, &Action::Buy, 1000, 1000).is_ok());
+
+        let overridden = limits.with_overrides(Some(&TraderRiskLimits { max_open_orders: Some(0), ..TraderRiskLimits::default() }));
+        assert_eq!(RiskLimits { max_open_orders: 0, ..limits }, overridden);
+    }
+}
@@ -0,0 +1,106 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+use crate::ports::{SessionPhase, MarketClosure};
+
+// Daily trading hours in UTC, the phases follow each other in this order within a day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionHours {
+    pub pre_open: NaiveTime,
+    pub open: NaiveTime,
+    pub closing_auction: NaiveTime,
+    pub close: NaiveTime,
+}
+
+impl SessionHours {
+    // None unless the times are in order
+    pub fn new(pre_open: NaiveTime, open: NaiveTime, closing_auction: NaiveTime, close: NaiveTime) -> Option<Self> {
+        (pre_open <= open && open < closing_auction && closing_auction <= close).then_some(SessionHours { pre_open, open, closing_auction, close })
+    }
+
+    pub fn phase(&self, at: DateTime<Utc>) -> SessionPhase {
+        let time = at.time();
+        if time < self.pre_open || time >= self.close {
+            SessionPhase::Closed
+        } else if time < self.open {
+            SessionPhase::PreOpen
+        } else if time < self.closing_auction {
+            SessionPhase::Continuous
+        } else {
+            SessionPhase::ClosingAuction
+        }
+    }
+
+    // Window of the call auction collecting orders in the phase of the day, None for the others
+    pub fn auction_window(&self, date: NaiveDate, phase: SessionPhase) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let at = |time| DateTime::<Utc>::from_utc(date.and_time(time), Utc);
+        match phase {
+            SessionPhase::PreOpen => Some((at(self.pre_open), at(self.open))),
+            SessionPhase::ClosingAuction => Some((at(self.closing_auction), at(self.close))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TradingCalendar {
+    // The market trades around the clock without session hours
+    pub hours: Option<SessionHours>,
+    // Cancel orders with the day time in force when the market closes
+    pub cancel_day_orders: bool,
+}
+
+impl TradingCalendar {
+    // Closures override the session hours
+    pub fn phase(&self, at: DateTime<Utc>, closures: &[MarketClosure]) -> SessionPhase {
+        if closure_at(at, closures).is_some() {
+            return SessionPhase::Closed;
+        }
+        self.hours.map_or(SessionPhase::Continuous, |hours| hours.phase(at))
+    }
+}
+
+pub fn closure_at(at: DateTime<Utc>, closures: &[MarketClosure]) -> Option<&MarketClosure> {
+    closures.iter().find(|closure| closure.starts_at <= at && at < closure.ends_at)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use crate::ports::ClosureKind;
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms(hour, minute, 0)
+    }
+
+    fn hours() -> SessionHours {
+        SessionHours::new(time(8, 0), time(9, 0), time(16, 30), time(16, 35)).unwrap()
+    }
+
+    #[test]
+    fn test_session_phases() {
+        let at = |hour, minute| Utc.ymd(2026, 10, 19).and_hms(hour, minute, 0);
+        let hours = hours();
+        assert_eq!(SessionPhase::Closed, hours.phase(at(7, 59)));
+        assert_eq!(SessionPhase::PreOpen, hours.phase(at(8, 0)));
+        assert_eq!(SessionPhase::Continuous, hours.phase(at(9, 0)));
+        assert_eq!(SessionPhase::ClosingAuction, hours.phase(at(16, 30)));
+        assert_eq!(SessionPhase::Closed, hours.phase(at(16, 35)));
+        assert_eq!(Some((at(16, 30), at(16, 35))), hours.auction_window(at(12, 0).naive_utc().date(), SessionPhase::ClosingAuction));
+        assert_eq!(None, hours.auction_window(at(12, 0).naive_utc().date(), SessionPhase::Continuous));
+        assert_eq!(None, SessionHours::new(time(9, 0), time(8, 0), time(16, 30), time(16, 35)));
+    }
+
+    #[test]
+    fn test_closures_override_hours() {
+        let at = |hour, minute| Utc.ymd(2026, 10, 19).and_hms(hour, minute, 0);
+        let closures = vec![MarketClosure { id: 1, kind: ClosureKind::Maintenance, starts_at: at(12, 0), ends_at: at(13, 0), reason: String::new() }];
+        let around_the_clock = TradingCalendar::default();
+        assert_eq!(SessionPhase::Continuous, around_the_clock.phase(at(2, 0), &closures));
+        assert_eq!(SessionPhase::Closed, around_the_clock.phase(at(12, 0), &closures));
+        assert_eq!(SessionPhase::Continuous, around_the_clock.phase(at(13, 0), &closures));
+        let calendar = TradingCalendar { hours: Some(hours()), cancel_day_orders: true };
+        assert_eq!(SessionPhase::Closed, calendar.phase(at(12, 30), &closures));
+        assert_eq!(SessionPhase::Closed, calendar.phase(at(2, 0), &closures));
+    }
+}
@@ -2,23 +2,38 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use sqlx::{PgPool};
 use anyhow::Result;
-use crate::ports::{TradeStore, Trade};
+use crate::ports::{TradeStore, Trade, TraderTrade, JournalTrade};
 
 #[derive(Clone)]
 pub struct PostgresTradeStoreImpl {
-    pub pg_pool: Arc<PgPool>
+    pub pg_pool: Arc<PgPool>,
+    pub counterparty_salt: String,
 }
 
 #[async_trait]
 impl TradeStore for PostgresTradeStoreImpl {
-    async fn insert_trade(&self, card_id: i32, price: i32, buyorder_id: i64, sellorder_id: i64) -> Result<()> {
-        sqlx::query!("INSERT INTO trades (card_id, price, buyorder_id, sellorder_id) VALUES ($1, $2, $3, $4)",
-        card_id, price, buyorder_id, sellorder_id)
-        .execute(&*self.pg_pool).await?;
-        Ok(())
-    }
     async fn query_trades(&self, card_id: i32, limit: Option<i64>) -> Result<Vec<Trade>> {
-        Ok(sqlx::query_as!(Trade, "SELECT * FROM trades WHERE card_id = $1 ORDER BY created_at DESC LIMIT $2", card_id, limit.unwrap_or(50))
+        Ok(sqlx::query_as!(Trade, "SELECT id, card_id, price, buyorder_id, sellorder_id, created_at, buyer_fee, seller_fee, quantity FROM trades WHERE card_id = $1 ORDER BY created_at DESC LIMIT $2", card_id, limit.unwrap_or(50))
+        .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_trader_trades(&self, trader_id: i64, limit: Option<i64>) -> Result<Vec<TraderTrade>> {
+        // The pseudonym is stable for one (trader, counterparty) pair, but can't be correlated between traders
+        Ok(sqlx::query_as!(TraderTrade, r#"
+            SELECT t.id AS trade_id, o.id AS order_id, t.card_id, o.side, t.price, t.quantity,
+                (CASE WHEN o.id = t.buyorder_id THEN t.buyer_fee ELSE t.seller_fee END) AS "fee!",
+                substr(md5($2 || ':' || o.trader_id || ':' || c.trader_id), 1, 16) AS "counterparty!",
+                t.created_at
+            FROM trades t
+            JOIN orders o ON o.id = t.buyorder_id OR o.id = t.sellorder_id
+            JOIN orders c ON c.id = (CASE WHEN o.id = t.buyorder_id THEN t.sellorder_id ELSE t.buyorder_id END)
+            WHERE o.trader_id = $1
+            ORDER BY t.created_at DESC, t.id DESC LIMIT $3"#,
+            trader_id, self.counterparty_salt, limit.unwrap_or(50))
+        .fetch_all(&*self.pg_pool).await?)
+    }
+    async fn query_journal_trades(&self, card_id: i32) -> Result<Vec<JournalTrade>> {
+        Ok(sqlx::query_as!(JournalTrade, r#"SELECT journal_sequence AS "journal_sequence!", buyorder_id, sellorder_id, price, quantity
+            FROM trades WHERE card_id = $1 AND journal_sequence IS NOT NULL ORDER BY journal_sequence, id"#, card_id)
         .fetch_all(&*self.pg_pool).await?)
     }
 }
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use sqlx::{PgPool};
 use log::{error};
 use async_trait::async_trait;
-use crate::ports::TraderStore;
+use anyhow::Result;
+use crate::ports::{TraderStore, TraderRateLimits, TraderRiskLimits, Role};
 
 #[derive(Clone)]
 pub struct PostgresTraderStoreImpl {
@@ -22,4 +24,36 @@ impl TraderStore for PostgresTraderStoreImpl {
             },
         }
     }
+    async fn query_rate_limits(&self, trader_id: i64) -> Result<Option<TraderRateLimits>> {
+        Ok(sqlx::query_as!(TraderRateLimits, "SELECT requests_per_second, request_burst, orders_per_second, order_burst FROM trader_rate_limits WHERE trader_id = $1", trader_id)
+            .fetch_optional(&*self.pg_pool).await?)
+    }
+    async fn query_risk_limits(&self) -> Result<HashMap<i64, TraderRiskLimits>> {
+        let rows = sqlx::query!("SELECT trader_id, max_order_value, max_open_orders, max_card_notional, max_position, max_daily_volume FROM trader_risk_limits")
+            .fetch_all(&*self.pg_pool).await?;
+        Ok(rows.into_iter().map(|r| (r.trader_id, TraderRiskLimits {
+            max_order_value: r.max_order_value,
+            max_open_orders: r.max_open_orders,
+            max_card_notional: r.max_card_notional,
+            max_position: r.max_position,
+            max_daily_volume: r.max_daily_volume,
+        })).collect())
+    }
+    async fn save_risk_limits(&self, trader_id: i64, limits: &TraderRiskLimits) -> Result<()> {
+        sqlx::query!("INSERT INTO trader_risk_limits (trader_id, max_order_value, max_open_orders, max_card_notional, max_position, max_daily_volume) VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (trader_id) DO UPDATE SET max_order_value = $2, max_open_orders = $3, max_card_notional = $4, max_position = $5, max_daily_volume = $6",
+            trader_id, limits.max_order_value, limits.max_open_orders, limits.max_card_notional, limits.max_position, limits.max_daily_volume)
+            .execute(&*self.pg_pool).await?;
+        Ok(())
+    }
+    async fn query_role(&self, trader_id: i64) -> Result<Option<Role>> {
+        let r = sqlx::query!("SELECT role FROM traders WHERE id = $1", trader_id)
+            .fetch_optional(&*self.pg_pool).await?;
+        Ok(r.map(|r| r.role.into()))
+    }
+    async fn authenticate(&self, api_key: &str) -> Result<Option<(i64, Role)>> {
+        let r = sqlx::query!("SELECT id, role FROM traders WHERE api_key_hash = sha256(convert_to($1, 'UTF8'))", api_key)
+            .fetch_optional(&*self.pg_pool).await?;
+        Ok(r.map(|r| (r.id, r.role.into())))
+    }
 }
@@ -0,0 +1,247 @@
+use actix_web::{web, get, post, delete, HttpResponse, ResponseError, http::{header, StatusCode}};
+use log::{info, error};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Caller, Permission};
+use crate::error::Error;
+use crate::ports::{self, OrderStore, TradeStore, OrderService};
+use crate::order_store::PostgresOrderStoreImpl;
+use crate::trade_store::PostgresTradeStoreImpl;
+use crate::trader_store::PostgresTraderStoreImpl;
+use crate::{card, OrderServiceImpl};
+
+// Adapters keeping the shapes of the first API on top of the current service: single unit orders,
+// an empty body on order entry and plain text errors. The order routes need an API key since 0.2.0,
+// a breaking change of v1 listed in the README
+
+#[derive(Debug)]
+pub struct V1Error(Error);
+
+impl<E: Into<Error>> From<E> for V1Error {
+    fn from(e: E) -> Self {
+        V1Error(e.into())
+    }
+}
+
+impl std::fmt::Display for V1Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for V1Error {
+    fn status_code(&self) -> StatusCode {
+        match self.0 {
+            // v1 never had a 404 for unknown traders
+            Error::TraderNotFound => StatusCode::BAD_REQUEST,
+            ref e => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let Error::Internal(e) = &self.0 {
+            error!("{:#}", e);
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::RateLimited(retry_after) = self.0 {
+            response.insert_header((header::RETRY_AFTER, retry_after));
+        }
+        response.body(self.0.detail())
+    }
+}
+
+#[derive(Serialize)]
+struct Order {
+    id: i64,
+    card_id: i32,
+    price: i32,
+    side: i16,
+    status: i16,
+    trader_id: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+impl From<ports::Order> for Order {
+    fn from(order: ports::Order) -> Self {
+        Order {
+            id: order.id,
+            card_id: order.card_id,
+            price: order.price,
+            side: order.side,
+            status: order.status,
+            trader_id: order.trader_id,
+            created_at: order.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Trade {
+    id: i64,
+    card_id: i32,
+    price: i32,
+    buyorder_id: i64,
+    sellorder_id: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+impl From<ports::Trade> for Trade {
+    fn from(trade: ports::Trade) -> Self {
+        Trade {
+            id: trade.id,
+            card_id: trade.card_id,
+            price: trade.price,
+            buyorder_id: trade.buyorder_id,
+            sellorder_id: trade.sellorder_id,
+            created_at: trade.created_at,
+        }
+    }
+}
+
+// The caller is taken as a result, so an unauthenticated request gets a plain text error too
+#[get("/traders/{id}/orders")]
+async fn get_orders(order_store: web::Data<PostgresOrderStoreImpl>, trader_store: web::Data<PostgresTraderStoreImpl>, caller: Result<Caller, Error>, path: web::Path<i64>) -> Result<HttpResponse, V1Error> {
+    let trader_id = path.into_inner();
+    caller?.authorize(Permission::ReadTrader(trader_id))?;
+    crate::check_trader(&trader_store, trader_id).await?;
+    let orders = order_store.query_orders(trader_id, Some(50)).await?;
+    Ok(HttpResponse::Ok().json(orders.into_iter().map(Order::from).collect::<Vec<_>>()))
+}
+
+#[derive(Deserialize)]
+struct OrderRequest {
+    side: String,
+    price: i32,
+    card_id: i32,
+}
+
+#[post("/traders/{id}/orders")]
+async fn add_order(order_service: web::Data<OrderServiceImpl>, caller: Result<Caller, Error>, path: web::Path<i64>, req_body: web::Json<OrderRequest>) -> Result<HttpResponse, V1Error> {
+    let trader_id = path.into_inner();
+    caller?.authorize(Permission::Trade(trader_id))?;
+    let req_body = req_body.into_inner();
+    let order = crate::OrderRequest {
+        side: req_body.side,
+        price: Some(req_body.price),
+        card_id: req_body.card_id,
+        quantity: None,
+        client_order_id: None,
+        self_trade_prevention: None,
+        order_type: None,
+        stop_price: None,
+        display_quantity: None,
+        post_only: None,
+        hidden: None,
+        time_in_force: None,
+    }.validate()?;
+    info!("Received v1 order request: {:?} card={} price={}", &order.side, &order.card_id, order.price);
+
+    order_service.add_order(trader_id, order).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/traders/{id}/orders/{order_id}")]
+async fn delete_order(order_service: web::Data<OrderServiceImpl>, order_store: web::Data<PostgresOrderStoreImpl>, caller: Result<Caller, Error>, path: web::Path<(i64, i64)>) -> Result<HttpResponse, V1Error> {
+    let (trader_id, order_id) = path.into_inner();
+    caller?.authorize(Permission::Trade(trader_id))?;
+    let order = crate::query_trader_order(&order_store, trader_id, order_id).await?;
+    order_service.cancel_order(&order).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/cards/{id}/trades")]
+async fn get_trades(trade_store: web::Data<PostgresTradeStoreImpl>, path: web::Path<i32>) -> Result<HttpResponse, V1Error> {
+    let card_id = path.into_inner();
+    if !card::is_valid(card_id) {
+        return Err(Error::CardNotFound.into());
+    }
+    let trades = trade_store.query_trades(card_id, Some(50)).await?;
+    Ok(HttpResponse::Ok().json(trades.into_iter().map(Trade::from).collect::<Vec<_>>()))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // Default extractor errors, which are plain text
+    cfg.app_data(web::JsonConfig::default())
+        .app_data(web::PathConfig::default())
+        .service(get_orders)
+        .service(add_order)
+        .service(delete_order)
+        .service(get_trades);
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::body::MessageBody;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn test_order_keeps_v1_shape() {
+        let order = ports::Order {
+            id: 7,
+            card_id: 1,
+            price: 400,
+            side: 1,
+            status: 0,
+            trader_id: 2,
+            created_at: Utc.ymd(2026, 1, 2).and_hms(3, 4, 5),
+            client_order_id: Some("a".to_string()),
+            quantity: 3,
+            filled_quantity: 1,
+            self_trade_prevention: 0,
+            order_type: 0,
+            stop_price: None,
+            triggered_at: None,
+            display_quantity: Some(1),
+            post_only: 0,
+            hidden: false,
+            time_in_force: 0,
+        };
+        assert_eq!(json!({
+            "id": 7,
+            "card_id": 1,
+            "price": 400,
+            "side": 1,
+            "status": 0,
+            "trader_id": 2,
+            "created_at": "2026-01-02T03:04:05Z",
+        }), serde_json::to_value(Order::from(order)).unwrap());
+    }
+
+    #[test]
+    fn test_trade_keeps_v1_shape() {
+        let trade = ports::Trade {
+            id: 9,
+            card_id: 1,
+            price: 400,
+            buyorder_id: 7,
+            sellorder_id: 8,
+            created_at: Utc.ymd(2026, 1, 2).and_hms(3, 4, 5),
+            buyer_fee: 4,
+            seller_fee: 4,
+            quantity: 2,
+        };
+        assert_eq!(json!({
+            "id": 9,
+            "card_id": 1,
+            "price": 400,
+            "buyorder_id": 7,
+            "sellorder_id": 8,
+            "created_at": "2026-01-02T03:04:05Z",
+        }), serde_json::to_value(Trade::from(trade)).unwrap());
+    }
+
+    #[test]
+    fn test_errors_are_plain_text() {
+        let response = V1Error(Error::CardNotFound).error_response();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert!(response.headers().get(header::CONTENT_TYPE).is_none_or(|content_type| content_type != "application/problem+json"));
+        assert_eq!("Card not found", response.into_body().try_into_bytes().unwrap());
+
+        let response = V1Error(Error::TraderNotFound).error_response();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let response = V1Error(Error::RateLimited(3)).error_response();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("3", response.headers().get(header::RETRY_AFTER).unwrap());
+    }
+}